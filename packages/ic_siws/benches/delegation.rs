@@ -0,0 +1,44 @@
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use ic_siws::{
+    delegation::generate_seed,
+    settings::{reload_settings, SettingsBuilder},
+    solana::SolPubkey,
+};
+use std::str::FromStr;
+
+fn init_settings() {
+    let settings = SettingsBuilder::new("example.com", "http://example.com", "some_salt")
+        .build()
+        .unwrap();
+    reload_settings(settings).unwrap();
+}
+
+fn bench_generate_seed(c: &mut Criterion) {
+    let address = SolPubkey::from_str("Awes4Tr6TX8JDzEhCZY2QVNimT6iD1zWHzf1vNyGvpLM").unwrap();
+
+    // The first call after the settings have been (re)initialized.
+    c.bench_function("generate_seed cold", |b| {
+        b.iter_batched(
+            init_settings,
+            |_| generate_seed(black_box(&address)),
+            BatchSize::SmallInput,
+        )
+    });
+
+    init_settings();
+    generate_seed(&address);
+    c.bench_function("generate_seed warm", |b| {
+        b.iter(|| generate_seed(black_box(&address)))
+    });
+
+    c.bench_function("generate_seed batch of 1000", |b| {
+        b.iter(|| {
+            for _ in 0..1000 {
+                black_box(generate_seed(black_box(&address)));
+            }
+        })
+    });
+}
+
+criterion_group!(benches, bench_generate_seed);
+criterion_main!(benches);
@@ -0,0 +1,108 @@
+use crate::{solana::SolPubkey, ACCESS_CONTROL};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashSet, fmt};
+
+#[derive(Debug)]
+pub enum AccessControlError {
+    DecodeError(String),
+}
+
+impl fmt::Display for AccessControlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AccessControlError::DecodeError(e) => write!(f, "Invalid access control bytes: {}", e),
+        }
+    }
+}
+
+impl From<AccessControlError> for String {
+    fn from(error: AccessControlError) -> Self {
+        error.to_string()
+    }
+}
+
+/// Addresses that have been banned from signing in at runtime, e.g. sanctioned addresses or compromised keys.
+/// Unlike the `additional_blocked_programs` setting, the blocklist can be changed without reloading the
+/// settings, see [`block_address`] and [`unblock_address`].
+#[derive(Default, Debug, Serialize, Deserialize)]
+pub struct AccessControl {
+    blocked_addresses: HashSet<SolPubkey>,
+}
+
+impl AccessControl {
+    pub fn new() -> AccessControl {
+        AccessControl::default()
+    }
+
+    /// Adds `pubkey` to the blocklist. Returns `false` if the address was already blocked.
+    pub fn block(&mut self, pubkey: &SolPubkey) -> bool {
+        self.blocked_addresses.insert(*pubkey)
+    }
+
+    /// Removes `pubkey` from the blocklist. Returns `false` if the address was not blocked.
+    pub fn unblock(&mut self, pubkey: &SolPubkey) -> bool {
+        self.blocked_addresses.remove(pubkey)
+    }
+
+    pub fn is_blocked(&self, pubkey: &SolPubkey) -> bool {
+        self.blocked_addresses.contains(pubkey)
+    }
+
+    /// Returns the blocked addresses in no particular order.
+    pub fn blocked_addresses(&self) -> impl Iterator<Item = &SolPubkey> {
+        self.blocked_addresses.iter()
+    }
+
+    /// Serializes the blocklist to CBOR, to be saved to stable memory before a canister upgrade. See
+    /// [`crate::upgrade::pre_upgrade_access_control`].
+    pub fn to_bytes(&self) -> Vec<u8> {
+        serde_cbor::to_vec(self).expect("Access control should be CBOR serializable")
+    }
+
+    /// Restores a blocklist serialized with [`AccessControl::to_bytes`].
+    pub fn from_bytes(bytes: &[u8]) -> Result<AccessControl, AccessControlError> {
+        serde_cbor::from_slice(bytes).map_err(|e| AccessControlError::DecodeError(e.to_string()))
+    }
+}
+
+/// Bans `pubkey` from signing in. Both `prepare_login` and `login` fail for blocked addresses. Existing
+/// delegations are not revoked, see [`crate::delegation::revoke_all_delegations_for_address`].
+pub fn block_address(pubkey: &SolPubkey) {
+    ACCESS_CONTROL.with_borrow_mut(|access_control| access_control.block(pubkey));
+}
+
+/// Lifts the ban of an address blocked with [`block_address`].
+pub fn unblock_address(pubkey: &SolPubkey) {
+    ACCESS_CONTROL.with_borrow_mut(|access_control| access_control.unblock(pubkey));
+}
+
+/// Returns `true` if `pubkey` has been blocked with [`block_address`].
+pub fn is_blocked(pubkey: &SolPubkey) -> bool {
+    ACCESS_CONTROL.with_borrow(|access_control| access_control.is_blocked(pubkey))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_block_and_unblock() {
+        let pubkey = SolPubkey::from_str("Awes4Tr6TX8JDzEhCZY2QVNimT6iD1zWHzf1vNyGvpLM").unwrap();
+        assert!(!is_blocked(&pubkey));
+        block_address(&pubkey);
+        assert!(is_blocked(&pubkey));
+        unblock_address(&pubkey);
+        assert!(!is_blocked(&pubkey));
+    }
+
+    #[test]
+    fn test_bytes_roundtrip() {
+        let pubkey = SolPubkey::from_str("Awes4Tr6TX8JDzEhCZY2QVNimT6iD1zWHzf1vNyGvpLM").unwrap();
+        let mut access_control = AccessControl::new();
+        access_control.block(&pubkey);
+        let restored = AccessControl::from_bytes(&access_control.to_bytes()).unwrap();
+        assert!(restored.is_blocked(&pubkey));
+        assert!(AccessControl::from_bytes(b"not cbor").is_err());
+    }
+}
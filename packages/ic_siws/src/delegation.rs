@@ -1,19 +1,77 @@
 use super::hash::{self, Value};
 use crate::{
-    settings::{RuntimeFeature, Settings},
-    signature_map::SignatureMap,
+    login::{session_token, LoginDetails},
+    settings::{validate_targets, RuntimeFeature, Settings},
+    signature_map::{DelegationEntry, SignatureMap, SignatureMapError},
+    siws::SiwsMessage,
     solana::SolPubkey,
     time::get_current_time,
     with_settings,
 };
 use candid::{CandidType, Principal};
-use ic_certified_map::{Hash, HashTree};
+use ic_certified_map::{fork_hash, labeled_hash, leaf_hash, Hash, HashTree};
 use serde::{Deserialize, Serialize};
 use serde_bytes::ByteBuf;
 use simple_asn1::{from_der, oid, ASN1Block, ASN1EncodeErr};
 use std::{collections::HashMap, fmt};
 
+#[cfg(feature = "timers")]
+use crate::SIGNATURE_MAP_PRUNE_TIMER;
+#[cfg(all(feature = "timers", not(test)))]
+use ic_cdk_timers::{clear_timer, set_timer_interval};
+#[cfg(feature = "timers")]
+use std::time::Duration;
+#[cfg(all(feature = "timers", test))]
+use tests::{clear_timer, set_timer_interval};
+
+/// The DER content bytes of the object identifier `1.3.6.1.4.1.56387.1.2` that identifies canister signature
+/// public keys, see the "Canister signatures" section of the ICP interface specification
+/// (<https://internetcomputer.org/docs/current/references/ic-interface-spec#canister-signatures>). Changing
+/// this value changes the principal of every user.
+pub const ICP_SELF_AUTH_KEY_OID: &[u8] =
+    &[0x2b, 0x06, 0x01, 0x04, 0x01, 0x83, 0xb8, 0x43, 0x01, 0x02];
+
+/// The DER encoding of an Ed25519 `SubjectPublicKeyInfo` up to the 32-byte raw public key.
+const ED25519_DER_PREFIX: [u8; 12] = [
+    0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00,
+];
+
+/// The format of a session key sent by a client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionKeyFormat {
+    /// A 32-byte raw Ed25519 public key.
+    Raw32,
+    /// A DER-encoded `SubjectPublicKeyInfo`, e.g. a 44-byte Ed25519 public key.
+    DerEncoded,
+}
+
+impl SessionKeyFormat {
+    /// Detects the format of a session key by its length. 32-byte keys are raw Ed25519 public keys, all other
+    /// keys are expected to be DER-encoded.
+    pub fn detect(session_key: &[u8]) -> SessionKeyFormat {
+        if session_key.len() == 32 {
+            SessionKeyFormat::Raw32
+        } else {
+            SessionKeyFormat::DerEncoded
+        }
+    }
+}
+
+/// Returns the DER encoding of the session key. Raw Ed25519 public keys are wrapped in a DER
+/// `SubjectPublicKeyInfo`, DER-encoded keys are returned unchanged.
+pub fn normalize_session_key(session_key: ByteBuf) -> ByteBuf {
+    match SessionKeyFormat::detect(&session_key) {
+        SessionKeyFormat::Raw32 => {
+            let mut der = ED25519_DER_PREFIX.to_vec();
+            der.extend_from_slice(&session_key);
+            ByteBuf::from(der)
+        }
+        SessionKeyFormat::DerEncoded => session_key,
+    }
+}
+
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum DelegationError {
     SignatureNotFound,
     WitnessHashMismatch(Hash, Hash),
@@ -21,6 +79,13 @@ pub enum DelegationError {
     InvalidSessionKey(String),
     InvalidExpiration(String),
     SignatureExpired,
+    InvalidCanisterPublicKey(String),
+    InvalidSignature(String),
+    InvalidMemo(String),
+    InvalidTargets(String),
+    InvalidSeedSalt(String),
+    SignatureMapError(SignatureMapError),
+    ExpiryTooFar { max_allowed: u64, requested: u64 },
 }
 
 impl fmt::Display for DelegationError {
@@ -37,6 +102,31 @@ impl fmt::Display for DelegationError {
             DelegationError::InvalidSessionKey(e) => write!(f, "Invalid session key: {}", e),
             DelegationError::InvalidExpiration(e) => write!(f, "Invalid expiration: {}", e),
             DelegationError::SignatureExpired => write!(f, "Signature expired"),
+            DelegationError::InvalidCanisterPublicKey(e) => {
+                write!(f, "Invalid canister public key: {}", e)
+            }
+            DelegationError::InvalidSignature(e) => write!(f, "Invalid signature: {}", e),
+            DelegationError::InvalidMemo(e) => write!(f, "Invalid memo: {}", e),
+            DelegationError::InvalidTargets(e) => write!(f, "Invalid targets: {}", e),
+            DelegationError::InvalidSeedSalt(e) => write!(f, "Invalid seed salt: {}", e),
+            DelegationError::SignatureMapError(e) => write!(f, "{}", e),
+            DelegationError::ExpiryTooFar {
+                max_allowed,
+                requested,
+            } => write!(
+                f,
+                "Expiration {} exceeds the maximum allowed expiration {}",
+                requested, max_allowed
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DelegationError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DelegationError::SignatureMapError(e) => Some(e),
+            _ => None,
         }
     }
 }
@@ -52,6 +142,11 @@ pub struct Delegation {
     pub pubkey: ByteBuf,
     pub expiration: u64,
     pub targets: Option<Vec<Principal>>,
+
+    /// Application metadata attached to the delegation, e.g. a device name or session label. The memo is not
+    /// part of the delegation hash, the ICP interface specification defines no such field, so it is not signed
+    /// and the IC does not verify it. Treat it as an unauthenticated hint.
+    pub memo: Option<ByteBuf>,
 }
 
 #[derive(Clone, Debug, CandidType, Deserialize)]
@@ -60,20 +155,185 @@ pub struct SignedDelegation {
     pub signature: ByteBuf,
 }
 
+impl SignedDelegation {
+    /// Verifies that the signature certifies this delegation for the user identified by `canister_pubkey`,
+    /// the DER-encoded user canister public key returned by [`crate::login::login`].
+    ///
+    /// The verification decodes the canister signature and checks that:
+    /// 1. The signature hash tree contains the path `sig/<sha256(seed)>/<delegation hash>`, where the seed is
+    ///    taken from `canister_pubkey` and the delegation hash is recomputed from `self.delegation`.
+    /// 2. The `certified_data` of the signing canister in the certificate equals the root hash of the
+    ///    signature hash tree.
+    ///
+    /// The BLS signature of the certificate itself is NOT verified, as that requires the IC root public key.
+    /// Callers that do not trust the source of the certificate must verify it separately, for example using
+    /// `ic-agent` or `ic-certification`.
+    pub fn verify_signature(&self, canister_pubkey: &[u8]) -> Result<(), DelegationError> {
+        let (canister_id, seed) = parse_user_canister_pubkey(canister_pubkey)?;
+
+        let signature: CanisterSignature = serde_cbor::from_slice(&self.signature)
+            .map_err(|e| DelegationError::InvalidSignature(e.to_string()))?;
+        let signature_tree = WitnessTree::from_cbor(&signature.tree)?;
+
+        // The signature tree must contain the delegation hash under the seed hash.
+        let seed_hash = hash::hash_bytes(seed);
+        let delegation_hash = create_delegation_hash(&self.delegation);
+        match signature_tree.lookup(&[&b"sig"[..], &seed_hash[..], &delegation_hash[..]]) {
+            Some(WitnessTree::Leaf(_)) => (),
+            _ => return Err(DelegationError::SignatureNotFound),
+        }
+
+        // The certificate must certify the root hash of the signature tree as the canister certified data.
+        let certificate: Certificate = serde_cbor::from_slice(&signature.certificate)
+            .map_err(|e| DelegationError::InvalidSignature(e.to_string()))?;
+        let certificate_tree = WitnessTree::from_cbor(&certificate.tree)?;
+        let certified_data = match certificate_tree.lookup(&[
+            &b"canister"[..],
+            canister_id.as_slice(),
+            &b"certified_data"[..],
+        ]) {
+            Some(WitnessTree::Leaf(data)) => data,
+            _ => {
+                return Err(DelegationError::InvalidSignature(
+                    "Certificate does not contain certified data for the canister".to_string(),
+                ))
+            }
+        };
+
+        let root_hash = signature_tree.reconstruct();
+        if certified_data.as_slice() != &root_hash[..] {
+            return Err(DelegationError::InvalidSignature(
+                "Certified data does not match the signature tree".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
 #[derive(Serialize)]
 struct CertificateSignature<'a> {
     certificate: ByteBuf,
     tree: HashTree<'a>,
 }
 
+#[derive(Deserialize)]
+struct CanisterSignature {
+    certificate: ByteBuf,
+    tree: serde_cbor::Value,
+}
+
+#[derive(Deserialize)]
+struct Certificate {
+    tree: serde_cbor::Value,
+}
+
+/// An owned hash tree decoded from its CBOR representation, see the ICP interface specification.
+enum WitnessTree {
+    Empty,
+    Fork(Box<WitnessTree>, Box<WitnessTree>),
+    Labeled(Vec<u8>, Box<WitnessTree>),
+    Leaf(Vec<u8>),
+    Pruned(Hash),
+}
+
+impl WitnessTree {
+    fn from_cbor(value: &serde_cbor::Value) -> Result<WitnessTree, DelegationError> {
+        use serde_cbor::Value;
+
+        let invalid = || DelegationError::InvalidSignature("Invalid hash tree".to_string());
+        let items = match value {
+            Value::Array(items) if !items.is_empty() => items,
+            _ => return Err(invalid()),
+        };
+        match (&items[0], &items[1..]) {
+            (Value::Integer(0), []) => Ok(WitnessTree::Empty),
+            (Value::Integer(1), [left, right]) => Ok(WitnessTree::Fork(
+                Box::new(WitnessTree::from_cbor(left)?),
+                Box::new(WitnessTree::from_cbor(right)?),
+            )),
+            (Value::Integer(2), [Value::Bytes(label), subtree]) => Ok(WitnessTree::Labeled(
+                label.clone(),
+                Box::new(WitnessTree::from_cbor(subtree)?),
+            )),
+            (Value::Integer(3), [Value::Bytes(leaf)]) => Ok(WitnessTree::Leaf(leaf.clone())),
+            (Value::Integer(4), [Value::Bytes(hash)]) => Ok(WitnessTree::Pruned(
+                hash.as_slice().try_into().map_err(|_| invalid())?,
+            )),
+            _ => Err(invalid()),
+        }
+    }
+
+    fn reconstruct(&self) -> Hash {
+        match self {
+            WitnessTree::Empty => hash::hash_with_domain(b"ic-hashtree-empty", &[]),
+            WitnessTree::Fork(left, right) => fork_hash(&left.reconstruct(), &right.reconstruct()),
+            WitnessTree::Labeled(label, subtree) => labeled_hash(label, &subtree.reconstruct()),
+            WitnessTree::Leaf(leaf) => leaf_hash(leaf),
+            WitnessTree::Pruned(hash) => *hash,
+        }
+    }
+
+    fn lookup(&self, path: &[&[u8]]) -> Option<&WitnessTree> {
+        match path.split_first() {
+            None => Some(self),
+            Some((label, rest)) => self.find_label(label)?.lookup(rest),
+        }
+    }
+
+    fn find_label(&self, label: &[u8]) -> Option<&WitnessTree> {
+        match self {
+            WitnessTree::Labeled(l, subtree) if l.as_slice() == label => Some(subtree),
+            WitnessTree::Fork(left, right) => {
+                left.find_label(label).or_else(|| right.find_label(label))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Extracts the canister id and seed from a DER-encoded user canister public key, see
+/// [`create_user_canister_pubkey`].
+fn parse_user_canister_pubkey(
+    canister_pubkey: &[u8],
+) -> Result<(Vec<u8>, Vec<u8>), DelegationError> {
+    let invalid = |e: &str| DelegationError::InvalidCanisterPublicKey(e.to_string());
+
+    let blocks = from_der(canister_pubkey).map_err(|e| invalid(&e.to_string()))?;
+    let key = match blocks.as_slice() {
+        [ASN1Block::Sequence(_, items)] => match items.as_slice() {
+            [_, ASN1Block::BitString(_, _, key)] => key,
+            _ => return Err(invalid("Unexpected DER structure")),
+        },
+        _ => return Err(invalid("Unexpected DER structure")),
+    };
+
+    // The key consists of the length prefixed canister id followed by the seed.
+    let canister_id_len = *key.first().ok_or_else(|| invalid("Empty key"))? as usize;
+    if key.len() < 1 + canister_id_len {
+        return Err(invalid("Key too short"));
+    }
+    Ok((
+        key[1..1 + canister_id_len].to_vec(),
+        key[1 + canister_id_len..].to_vec(),
+    ))
+}
+
 /// The seed is used when creating the delegate identity.
 ///
 /// The seed is generated by hashing the salt, the address, and the URI.
 pub fn generate_seed(address: &SolPubkey) -> Hash {
+    let salt = with_settings!(|settings: &Settings| settings.salt.clone());
+    generate_seed_with_salt(address, salt.as_bytes())
+}
+
+/// Works like [`generate_seed`] but uses `salt` instead of the salt from the settings, see
+/// [`LoginOptions::custom_seed_salt`](crate::login::LoginOptions::custom_seed_salt). The salt must not exceed
+/// 255 bytes, as its length is stored in a single byte.
+pub fn generate_seed_with_salt(address: &SolPubkey, salt: &[u8]) -> Hash {
     with_settings!(|settings: &Settings| {
         let mut seed: Vec<u8> = vec![];
 
-        let salt = settings.salt.as_bytes();
         seed.push(salt.len() as u8);
         seed.extend_from_slice(salt);
 
@@ -95,10 +355,23 @@ pub fn generate_seed(address: &SolPubkey) -> Hash {
     })
 }
 
+/// Computes the expiration time of a delegation created from the SIWS message, in nanoseconds since the UNIX
+/// epoch. The delegation is valid for `settings.session_expires_in` nanoseconds from the time the message was
+/// issued. Canisters can use this to show the expected session duration before the user signs the message.
+pub fn compute_delegation_expiry(message: &SiwsMessage, settings: &Settings) -> u64 {
+    message
+        .issued_at
+        .saturating_add(settings.session_expires_in)
+}
+
 /// Creates a delegation with the provided session key and expiration, including a list of canisters for identity delegation.
 ///
+/// The expiration must not be further in the future than the maximum session duration, `session_expires_in` or
+/// `max_session_expires_in` from the settings, otherwise [`DelegationError::ExpiryTooFar`] is returned.
+///
 /// # Parameters
-/// * `session_key`: A key uniquely identifying the session.
+/// * `session_key`: A key uniquely identifying the session. Raw 32-byte Ed25519 public keys are DER-encoded,
+///   see [`normalize_session_key`], so both formats result in the same delegation.
 /// * `expiration`: Expiration time in nanoseconds since the UNIX epoch.
 pub fn create_delegation(
     session_key: ByteBuf,
@@ -111,7 +384,8 @@ pub fn create_delegation(
         ));
     }
 
-    // Validate the session key is DER-encoded
+    // Raw Ed25519 keys are wrapped in DER, then validate the session key is DER-encoded
+    let session_key = normalize_session_key(session_key);
     from_der(&session_key).map_err(|e| {
         DelegationError::InvalidSessionKey(format!("Session key should be DER-encoded: {}", e))
     })?;
@@ -122,16 +396,83 @@ pub fn create_delegation(
         ));
     }
     with_settings!(|settings: &Settings| {
+        let max_allowed = get_current_time().saturating_add(
+            settings
+                .max_session_expires_in
+                .unwrap_or(settings.session_expires_in),
+        );
+        if expiration > max_allowed {
+            return Err(DelegationError::ExpiryTooFar {
+                max_allowed,
+                requested: expiration,
+            });
+        }
+
         Ok(Delegation {
             pubkey: session_key.clone(),
             expiration,
             targets: settings.targets.clone(),
+            memo: None,
         })
     })
 }
 
+/// Works like [`create_delegation`] but attaches a memo with application metadata to the delegation. The memo
+/// must not exceed [`Settings::max_delegation_memo_bytes`]. The memo is not signed, see [`Delegation::memo`].
+pub fn create_delegation_with_memo(
+    session_key: ByteBuf,
+    expiration: u64,
+    memo: Vec<u8>,
+) -> Result<Delegation, DelegationError> {
+    let max_memo_bytes = with_settings!(|settings: &Settings| settings.max_delegation_memo_bytes);
+    if memo.len() > max_memo_bytes {
+        return Err(DelegationError::InvalidMemo(format!(
+            "Memo is {} bytes, the maximum is {}",
+            memo.len(),
+            max_memo_bytes
+        )));
+    }
+
+    let mut delegation = create_delegation(session_key, expiration)?;
+    delegation.memo = Some(ByteBuf::from(memo));
+    Ok(delegation)
+}
+
+/// Works like [`create_delegation`] but restricts the delegation to `targets` instead of the targets from the
+/// settings, so a compromised session key can only be used to call these canisters. The list must not be empty,
+/// contain duplicates or exceed 1000 entries.
+pub fn create_delegation_with_targets(
+    session_key: ByteBuf,
+    expiration: u64,
+    targets: Vec<Principal>,
+) -> Result<Delegation, DelegationError> {
+    let targets = validate_targets(&Some(targets)).map_err(DelegationError::InvalidTargets)?;
+
+    let mut delegation = create_delegation(session_key, expiration)?;
+    delegation.targets = targets;
+    Ok(delegation)
+}
+
 /// Constructs a hash tree as proof of an entry in the signature map.
 ///
+/// # Wire format
+///
+/// The signature map is a two level map from `sha256(seed)` to delegation hashes, where each delegation hash
+/// maps to an empty leaf. The witness for an entry follows the hash tree format of the ICP interface
+/// specification, with every other entry in the map replaced by `pruned` nodes:
+///
+/// ```text
+/// ... fork / pruned ...
+///   labeled(sha256(seed),
+///     ... fork / pruned ...
+///       labeled(delegation_hash, leaf(b"")))
+/// ```
+///
+/// The returned witness reconstructs to the [`SignatureMap::root_hash`]. Canisters certify the signature map
+/// under the label `b"sig"`, so the full path looked up by a verifier is `sig/<sha256(seed)>/<delegation_hash>`,
+/// and the witness must be wrapped in `labeled(b"sig", witness)` before it is returned as part of a canister
+/// signature.
+///
 /// # Parameters
 /// * `signature_map`: The map of signatures.
 /// * `seed`: The unique seed identifying the delegation.
@@ -163,6 +504,192 @@ pub fn witness(
     Ok(witness)
 }
 
+/// Revokes a delegation by removing it from the signature map, e.g. when a user suspects their session key was
+/// compromised. Returns `true` if the delegation was present. The implementing canister is responsible for
+/// updating the certified data after revocation.
+pub fn revoke_delegation(
+    seed_hash: &[u8; 32],
+    delegation_hash: &[u8; 32],
+    signature_map: &mut SignatureMap,
+) -> bool {
+    if !signature_map.contains(*seed_hash, *delegation_hash) {
+        return false;
+    }
+    signature_map.delete(*seed_hash, *delegation_hash);
+    true
+}
+
+/// Revokes all delegations of the user with the Solana address and returns how many were removed. The
+/// implementing canister is responsible for updating the certified data after revocation.
+pub fn revoke_all_delegations_for_address(
+    pubkey: &SolPubkey,
+    signature_map: &mut SignatureMap,
+) -> usize {
+    let seed_hash = hash::hash_bytes(generate_seed(pubkey));
+    signature_map.delete_seed(seed_hash)
+}
+
+/// Starts a timer that prunes all expired signatures from the signature map every `interval_ns` nanoseconds.
+/// Without it, signatures are only pruned on login, at most `max_sigs_to_prune` at a time. Any previously
+/// started timer is stopped.
+///
+/// The library doesn't own the signature map. On each tick, `with_signature_map` is called with a function
+/// that prunes a map. It should call the function with the canister's signature map and then update the
+/// certified data, since pruning changes the root hash. Requires the `timers` feature.
+///
+/// # Examples
+///
+/// ```ignore
+/// start_auto_prune_timer(
+///     |prune| {
+///         STATE.with(|s| {
+///             prune(&mut s.signature_map.borrow_mut());
+///             update_root_hash(&s.asset_hashes.borrow(), &s.signature_map.borrow());
+///         })
+///     },
+///     60_000_000_000,
+/// );
+/// ```
+#[cfg(feature = "timers")]
+pub fn start_auto_prune_timer<F>(with_signature_map: F, interval_ns: u64)
+where
+    F: Fn(&mut dyn FnMut(&mut SignatureMap)) + 'static,
+{
+    stop_auto_prune_timer();
+    let timer_id = set_timer_interval(Duration::from_nanos(interval_ns), move || {
+        with_signature_map(&mut |signature_map: &mut SignatureMap| {
+            signature_map.prune_expired(get_current_time(), usize::MAX);
+        });
+    });
+    SIGNATURE_MAP_PRUNE_TIMER.set(Some(timer_id));
+}
+
+/// Stops the timer started by [`start_auto_prune_timer`]. Returns `false` if no timer was running. Requires the
+/// `timers` feature.
+#[cfg(feature = "timers")]
+pub fn stop_auto_prune_timer() -> bool {
+    match SIGNATURE_MAP_PRUNE_TIMER.take() {
+        Some(timer_id) => {
+            clear_timer(timer_id);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Returns the delegations of the user with the Solana address, see [`SignatureMap::delegations_for_seed`].
+pub fn query_delegations(pubkey: &SolPubkey, signature_map: &SignatureMap) -> Vec<DelegationEntry> {
+    let seed_hash = hash::hash_bytes(generate_seed(pubkey));
+    signature_map.delegations_for_seed(seed_hash)
+}
+
+/// Renews an existing, still valid delegation of the user with the Solana address without a new SIWS signature.
+/// The delegation for `session_key` that expires at `old_expiration` is replaced in the signature map by a
+/// delegation that expires at `new_expiration`. Delegations of the user for other session keys, e.g. on other
+/// devices, are left untouched. The client fetches the new delegation with `siws_get_delegation` as after a
+/// regular login.
+///
+/// Returns [`DelegationError::SignatureNotFound`] if the signature map holds no signature for the existing
+/// delegation, [`DelegationError::SignatureExpired`] if that signature or the delegation itself has expired, and
+/// [`DelegationError::ExpiryTooFar`] if `new_expiration` exceeds `old_expiration` plus `max_session_expires_in`.
+/// Signatures are pruned from the map about a minute after they were added, so a delegation can only be renewed
+/// shortly after it was issued or renewed. Delegations created with custom targets can't be renewed.
+///
+/// # Security
+///
+/// The existing delegation proves that the user logged in, but not that the caller is that user. The
+/// implementing canister should check that `ic_cdk::caller()` is the principal of the address.
+pub fn renew_delegation(
+    pubkey: &SolPubkey,
+    session_key: ByteBuf,
+    old_expiration: u64,
+    new_expiration: u64,
+    signature_map: &mut SignatureMap,
+    canister_id: &Principal,
+) -> Result<LoginDetails, DelegationError> {
+    let current_time = get_current_time();
+    if new_expiration <= current_time {
+        return Err(DelegationError::InvalidExpiration(
+            "Expiration is in the past".to_string(),
+        ));
+    }
+    if old_expiration <= current_time {
+        return Err(DelegationError::SignatureExpired);
+    }
+    let max_session_expires_in = with_settings!(|settings: &Settings| settings
+        .max_session_expires_in
+        .unwrap_or(settings.session_expires_in));
+    let max_allowed = old_expiration.saturating_add(max_session_expires_in);
+    if new_expiration > max_allowed {
+        return Err(DelegationError::ExpiryTooFar {
+            max_allowed,
+            requested: new_expiration,
+        });
+    }
+
+    let seed = generate_seed(pubkey);
+    let seed_hash = hash::hash_bytes(seed);
+
+    // Look up the existing delegation, its hash depends on the session key and the old expiration.
+    let old_delegation_hash =
+        create_delegation_hash(&create_delegation(session_key.clone(), old_expiration)?);
+    if !signature_map.contains(seed_hash, old_delegation_hash) {
+        return Err(DelegationError::SignatureNotFound);
+    }
+    if signature_map.is_expired(current_time, seed_hash, old_delegation_hash) {
+        return Err(DelegationError::SignatureExpired);
+    }
+
+    let session_token = with_settings!(|settings: &Settings| settings.issue_session_tokens)
+        .then(|| session_token(&seed, new_expiration, &session_key));
+
+    // Create the delegation first so that the signature map is left untouched if it is invalid.
+    let delegation = create_delegation(session_key, new_expiration)?;
+    let user_canister_pubkey = create_user_canister_pubkey(canister_id, seed.to_vec())
+        .map_err(|e| DelegationError::SerializationError(e.to_string()))?;
+
+    signature_map.delete(seed_hash, old_delegation_hash);
+    signature_map
+        .try_put(seed_hash, create_delegation_hash(&delegation))
+        .map_err(DelegationError::SignatureMapError)?;
+
+    Ok(LoginDetails {
+        expiration: new_expiration,
+        user_canister_pubkey: ByteBuf::from(user_canister_pubkey),
+        session_token,
+    })
+}
+
+/// Verifies a CBOR encoded witness created by [`witness`], without trusting the canister that produced it.
+/// Returns `true` if the witness reconstructs to `root_hash` and contains the path
+/// `<seed_hash>/<delegation_hash>`.
+///
+/// # Parameters
+/// * `root_hash`: The root hash of the signature map, for example as certified by the canister.
+/// * `seed_hash`: The hash of the seed, `sha256(seed)`.
+/// * `delegation_hash`: The hash of the delegation.
+/// * `witness`: The CBOR encoded witness.
+pub fn verify_witness(
+    root_hash: &[u8; 32],
+    seed_hash: &[u8; 32],
+    delegation_hash: &[u8; 32],
+    witness: &[u8],
+) -> bool {
+    let tree = match serde_cbor::from_slice::<serde_cbor::Value>(witness)
+        .ok()
+        .and_then(|value| WitnessTree::from_cbor(&value).ok())
+    {
+        Some(tree) => tree,
+        None => return false,
+    };
+
+    tree.reconstruct() == *root_hash
+        && matches!(
+            tree.lookup(&[&seed_hash[..], &delegation_hash[..]]),
+            Some(WitnessTree::Leaf(_))
+        )
+}
+
 /// Creates a certified signature using a certificate and a state hash tree.
 ///
 /// # Parameters
@@ -183,6 +710,14 @@ pub fn create_certified_signature(
     cbor_serialize(&certificate_signature)
 }
 
+/// Creates the hash of a delegation that is signed with a canister signature. The hash is the
+/// representation-independent hash with the `ic-request-auth-delegation` domain separator defined in the ICP
+/// interface specification, which the IC recomputes when it verifies the delegation.
+///
+/// The canister id is deliberately not part of the hash. A different hash would not verify, and the signature
+/// is already scoped to the canister: the user public key embeds the canister id (see
+/// [`create_user_canister_pubkey`]) and the signature is only valid with a certificate for the certified data
+/// of that canister.
 pub fn create_delegation_hash(delegation: &Delegation) -> Hash {
     let mut delegation_map = HashMap::new();
 
@@ -221,6 +756,7 @@ pub(crate) fn create_user_canister_pubkey(
     key.extend(canister_id);
     key.extend(seed);
 
+    // Must match ICP_SELF_AUTH_KEY_OID.
     let algorithm = oid!(1, 3, 6, 1, 4, 1, 56387, 1, 2);
     let algorithm = ASN1Block::Sequence(0, vec![ASN1Block::ObjectIdentifier(0, algorithm)]);
     let subject_public_key = ASN1Block::BitString(0, key.len() * 8, key.to_vec());
@@ -228,6 +764,50 @@ pub(crate) fn create_user_canister_pubkey(
     simple_asn1::to_der(&subject_public_key_info)
 }
 
+/// Returns the raw public key for a seed, for canisters that implement their own session protocol instead of
+/// ICP identity delegation. The raw public key is the SHA-256 hash of the seed, `raw_pubkey = sha256(seed)`,
+/// which is also the key of the user's delegations in the [`SignatureMap`]. It is not wrapped in a DER
+/// `SubjectPublicKeyInfo` and does not depend on the canister id, unlike [`create_user_canister_pubkey`].
+///
+/// # Parameters
+/// * `seed`: The seed returned by [`generate_seed`].
+pub fn create_user_canister_pubkey_raw(seed: &[u8; 32]) -> [u8; 32] {
+    hash::hash_bytes(seed)
+}
+
+/// Creates a DER-encoded public key for a user canister from a given seed and subaccount index, allowing a
+/// single Solana address to hold multiple identities. Subaccount `0` produces the same public key as
+/// [`create_user_canister_pubkey`].
+///
+/// Delegations for a subaccount must be added to the signature map using the seed returned by
+/// [`subaccount_seed`].
+///
+/// # Parameters
+/// * `canister_id`: The principal of the canister for which the public key is created.
+/// * `seed`: The seed returned by [`generate_seed`].
+/// * `subaccount`: The subaccount index.
+///
+/// # Returns
+/// Bytes of the DER-encoded public key.
+pub fn create_user_canister_pubkey_with_subaccount(
+    canister_id: &Principal,
+    seed: &[u8; 32],
+    subaccount: u32,
+) -> Result<Vec<u8>, ASN1EncodeErr> {
+    create_user_canister_pubkey(canister_id, subaccount_seed(seed, subaccount).to_vec())
+}
+
+/// Derives the seed for a subaccount by hashing the seed together with the 4-byte little-endian subaccount
+/// index. Subaccount `0` returns the seed unchanged.
+pub fn subaccount_seed(seed: &[u8; 32], subaccount: u32) -> Hash {
+    if subaccount == 0 {
+        return *seed;
+    }
+    let mut bytes = seed.to_vec();
+    bytes.extend_from_slice(&subaccount.to_le_bytes());
+    hash::hash_bytes(bytes)
+}
+
 /// Serializes data into CBOR format.
 ///
 /// # Parameters
@@ -259,6 +839,53 @@ mod tests {
 
     use super::*;
 
+    #[cfg(feature = "timers")]
+    use ic_cdk_timers::TimerId;
+    #[cfg(feature = "timers")]
+    use std::cell::Cell;
+
+    #[cfg(feature = "timers")]
+    thread_local! {
+        static TIMERS_SET: Cell<usize> = const { Cell::new(0) };
+        static TIMERS_CLEARED: Cell<usize> = const { Cell::new(0) };
+    }
+
+    // Stand-ins for the ic_cdk_timers functions, which need the canister runtime.
+    #[cfg(feature = "timers")]
+    pub(super) fn set_timer_interval(
+        _interval: Duration,
+        _func: impl FnMut() + 'static,
+    ) -> TimerId {
+        TIMERS_SET.set(TIMERS_SET.get() + 1);
+        TimerId::default()
+    }
+
+    #[cfg(feature = "timers")]
+    pub(super) fn clear_timer(_timer_id: TimerId) {
+        TIMERS_CLEARED.set(TIMERS_CLEARED.get() + 1);
+    }
+
+    #[cfg(feature = "timers")]
+    #[test]
+    fn test_auto_prune_timer() {
+        let with_signature_map = |_prune: &mut dyn FnMut(&mut SignatureMap)| {};
+        assert!(!stop_auto_prune_timer());
+
+        start_auto_prune_timer(with_signature_map, 60_000_000_000);
+        assert!(SIGNATURE_MAP_PRUNE_TIMER.with_borrow(Option::is_some));
+        assert_eq!(TIMERS_CLEARED.get(), 0);
+
+        // Starting the timer again replaces the running timer.
+        start_auto_prune_timer(with_signature_map, 60_000_000_000);
+        assert_eq!(TIMERS_SET.get(), 2);
+        assert_eq!(TIMERS_CLEARED.get(), 1);
+
+        assert!(stop_auto_prune_timer());
+        assert_eq!(TIMERS_CLEARED.get(), 2);
+        assert!(SIGNATURE_MAP_PRUNE_TIMER.with_borrow(Option::is_none));
+        assert!(!stop_auto_prune_timer());
+    }
+
     pub const SESSION_KEY: &[u8] = &[
         48, 42, 48, 5, 6, 3, 43, 101, 112, 3, 33, 0, 220, 227, 2, 129, 72, 36, 43, 220, 96, 102,
         225, 92, 98, 163, 114, 182, 117, 181, 51, 15, 219, 197, 104, 55, 123, 245, 74, 181, 35,
@@ -269,7 +896,7 @@ mod tests {
         let builder = SettingsBuilder::new("example.com", "http://example.com", "some_salt")
             .targets(vec![Principal::from_text("aaaaa-aa").unwrap()]);
         let settings = builder.build().unwrap();
-        SETTINGS.set(Some(settings));
+        SETTINGS.set(Some(std::rc::Rc::new(settings)));
         let wallet = Keypair::new();
         solana::SolPubkey::from(wallet.pubkey().to_bytes())
     }
@@ -281,6 +908,55 @@ mod tests {
         assert!(!seed.is_empty(), "Seed should not be empty");
     }
 
+    #[derive(Deserialize)]
+    struct SeedVector {
+        salt: String,
+        pubkey_base58: String,
+        uri: Option<String>,
+        expected_seed_hex: String,
+    }
+
+    #[test]
+    fn test_generate_seed_vectors() {
+        let vectors: Vec<SeedVector> =
+            serde_json::from_str(include_str!("../delegation_seed_vectors.json")).unwrap();
+        assert_eq!(vectors.len(), 10);
+
+        for vector in vectors {
+            let mut builder = SettingsBuilder::new(
+                "example.com",
+                vector.uri.as_deref().unwrap_or("http://example.com"),
+                &vector.salt,
+            );
+            if vector.uri.is_some() {
+                builder = builder.runtime_features(vec![RuntimeFeature::IncludeUriInSeed]);
+            }
+            SETTINGS.set(Some(std::rc::Rc::new(builder.build().unwrap())));
+
+            let address: solana::SolPubkey = vector.pubkey_base58.parse().unwrap();
+            let seed = generate_seed(&address);
+            assert_eq!(
+                hex::encode(seed),
+                vector.expected_seed_hex,
+                "Seed mismatch for salt {} and pubkey {}",
+                vector.salt,
+                vector.pubkey_base58
+            );
+        }
+    }
+
+    #[test]
+    fn test_compute_delegation_expiry() {
+        let address = init();
+        let message = SiwsMessage::new(&address, "nonce");
+        with_settings!(|settings: &Settings| {
+            assert_eq!(
+                compute_delegation_expiry(&message, settings),
+                message.issued_at + settings.session_expires_in
+            );
+        });
+    }
+
     #[test]
     fn test_create_delegation() {
         init();
@@ -296,6 +972,54 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_create_delegation_hash_reference() {
+        // The delegation hash is not a hash of a CBOR encoding of the delegation. Like agent-rs, it uses
+        // the representation-independent hash defined in the ICP interface specification. The expected
+        // values have been computed independently to pin the encoding of every field.
+        let delegation = Delegation {
+            pubkey: ByteBuf::from(SESSION_KEY),
+            expiration: 123456789,
+            targets: Some(vec![Principal::from_text("aaaaa-aa").unwrap()]),
+            memo: None,
+        };
+        assert_eq!(
+            create_delegation_hash(&delegation),
+            hex_literal::hex!("286b6c51bfc62f47ecf2e9ebd910fc872c50e57566716f6ff4c7e1195ef8c082")
+        );
+
+        let delegation = Delegation {
+            targets: None,
+            ..delegation
+        };
+        assert_eq!(
+            create_delegation_hash(&delegation),
+            hex_literal::hex!("2637718235275f7524f53f6bb967f67cf8f744f82c2a1163ee39c200c57e6972")
+        );
+    }
+
+    #[test]
+    fn test_create_delegation_with_memo() {
+        init();
+        let session_key = ByteBuf::from(SESSION_KEY);
+        let delegation =
+            create_delegation_with_memo(session_key.clone(), 123456789, b"laptop".to_vec())
+                .unwrap();
+        assert_eq!(delegation.memo, Some(ByteBuf::from(b"laptop".to_vec())));
+
+        // The memo is not part of the delegation hash, which must match the hash the IC computes.
+        let without_memo = create_delegation(session_key.clone(), 123456789).unwrap();
+        assert_eq!(
+            create_delegation_hash(&delegation),
+            create_delegation_hash(&without_memo)
+        );
+
+        assert!(matches!(
+            create_delegation_with_memo(session_key, 123456789, vec![0u8; 257]),
+            Err(DelegationError::InvalidMemo(_))
+        ));
+    }
+
     #[test]
     fn test_create_delegation_invalid_session_key() {
         init();
@@ -310,6 +1034,20 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_create_delegation_expiry_too_far() {
+        init();
+        let session_key = ByteBuf::from(SESSION_KEY);
+        let session_expires_in = with_settings!(|settings: &Settings| settings.session_expires_in);
+        let expiration = get_current_time() + session_expires_in + 60_000_000_000;
+        assert!(matches!(
+            create_delegation(session_key.clone(), expiration),
+            Err(DelegationError::ExpiryTooFar { requested, .. }) if requested == expiration
+        ));
+        let expiration = get_current_time() + session_expires_in - 60_000_000_000;
+        assert!(create_delegation(session_key, expiration).is_ok());
+    }
+
     #[test]
     fn test_create_delegation_invalid_expiration() {
         init();
@@ -340,6 +1078,318 @@ mod tests {
         assert_eq!(witness_hash, root_hash);
     }
 
+    #[test]
+    fn test_revoke_delegations() {
+        let address = init();
+        let seed_hash = hash::hash_bytes(generate_seed(&address));
+        let delegation_hash = hash::hash_bytes(b"delegation");
+        let mut signature_map = SignatureMap::default();
+        signature_map.put(seed_hash, delegation_hash);
+        signature_map.put(seed_hash, hash::hash_bytes(b"other delegation"));
+
+        assert!(revoke_delegation(
+            &seed_hash,
+            &delegation_hash,
+            &mut signature_map
+        ));
+        assert!(!revoke_delegation(
+            &seed_hash,
+            &delegation_hash,
+            &mut signature_map
+        ));
+        assert_eq!(
+            revoke_all_delegations_for_address(&address, &mut signature_map),
+            1
+        );
+        assert!(!signature_map.contains_seed(seed_hash));
+    }
+
+    #[test]
+    fn test_query_delegations() {
+        let address = init();
+        let seed_hash = hash::hash_bytes(generate_seed(&address));
+        let delegation_hash = hash::hash_bytes(b"delegation");
+        let mut signature_map = SignatureMap::default();
+        signature_map.put(seed_hash, delegation_hash);
+
+        let entries = query_delegations(&address, &signature_map);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].delegation_hash, delegation_hash);
+    }
+
+    #[test]
+    fn test_raw_session_key() {
+        init();
+        assert_eq!(
+            SessionKeyFormat::detect(SESSION_KEY),
+            SessionKeyFormat::DerEncoded
+        );
+        let raw_session_key = &SESSION_KEY[ED25519_DER_PREFIX.len()..];
+        assert_eq!(
+            SessionKeyFormat::detect(raw_session_key),
+            SessionKeyFormat::Raw32
+        );
+
+        let der = create_delegation(ByteBuf::from(SESSION_KEY), 123456789).unwrap();
+        let raw = create_delegation(ByteBuf::from(raw_session_key), 123456789).unwrap();
+        assert_eq!(raw.pubkey, der.pubkey);
+        assert_eq!(create_delegation_hash(&raw), create_delegation_hash(&der));
+    }
+
+    #[test]
+    fn test_create_delegation_with_targets() {
+        init();
+        let target = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap();
+        let delegation =
+            create_delegation_with_targets(ByteBuf::from(SESSION_KEY), 123456789, vec![target])
+                .unwrap();
+        assert_eq!(delegation.targets, Some(vec![target]));
+        assert_ne!(
+            create_delegation_hash(&delegation),
+            create_delegation_hash(
+                &create_delegation(ByteBuf::from(SESSION_KEY), 123456789).unwrap()
+            )
+        );
+
+        for targets in [vec![], vec![target, target], vec![target; 1001]] {
+            assert!(matches!(
+                create_delegation_with_targets(ByteBuf::from(SESSION_KEY), 123456789, targets),
+                Err(DelegationError::InvalidTargets(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_renew_delegation() {
+        let address = init();
+        let canister_id = Principal::from_text("aaaaa-aa").unwrap();
+        let seed = generate_seed(&address);
+        let seed_hash = hash::hash_bytes(seed);
+        let old_expiration = get_current_time() + 30_000_000_000;
+        let old_delegation_hash = create_delegation_hash(
+            &create_delegation(ByteBuf::from(SESSION_KEY), old_expiration).unwrap(),
+        );
+        let mut signature_map = SignatureMap::default();
+        signature_map.put(seed_hash, old_delegation_hash);
+
+        let new_expiration = get_current_time() + 60_000_000_000;
+        let login_details = renew_delegation(
+            &address,
+            ByteBuf::from(SESSION_KEY),
+            old_expiration,
+            new_expiration,
+            &mut signature_map,
+            &canister_id,
+        )
+        .unwrap();
+        assert_eq!(login_details.expiration, new_expiration);
+        assert_eq!(
+            login_details.user_canister_pubkey.into_vec(),
+            create_user_canister_pubkey(&canister_id, seed.to_vec()).unwrap()
+        );
+        let delegation = create_delegation(ByteBuf::from(SESSION_KEY), new_expiration).unwrap();
+        assert!(signature_map.contains(seed_hash, create_delegation_hash(&delegation)));
+        assert!(!signature_map.contains(seed_hash, old_delegation_hash));
+
+        // The signature map is unchanged if the expiration is too far in the future.
+        let root_hash = signature_map.root_hash();
+        let result = renew_delegation(
+            &address,
+            ByteBuf::from(SESSION_KEY),
+            new_expiration,
+            u64::MAX,
+            &mut signature_map,
+            &canister_id,
+        );
+        assert!(matches!(result, Err(DelegationError::ExpiryTooFar { .. })));
+        assert_eq!(signature_map.root_hash(), root_hash);
+    }
+
+    #[test]
+    fn test_renew_delegation_rejects_missing_and_expired() {
+        let address = init();
+        let canister_id = Principal::from_text("aaaaa-aa").unwrap();
+        let seed_hash = hash::hash_bytes(generate_seed(&address));
+        let mut signature_map = SignatureMap::default();
+        let old_expiration = get_current_time() + 30_000_000_000;
+        let new_expiration = get_current_time() + 60_000_000_000;
+
+        // No login, no delegation to renew.
+        let result = renew_delegation(
+            &address,
+            ByteBuf::from(SESSION_KEY),
+            old_expiration,
+            new_expiration,
+            &mut signature_map,
+            &canister_id,
+        );
+        assert!(matches!(result, Err(DelegationError::SignatureNotFound)));
+        assert!(signature_map.is_empty());
+
+        // The existing delegation has expired.
+        let expired = get_current_time() - 1;
+        signature_map.put(
+            seed_hash,
+            create_delegation_hash(
+                &create_delegation(ByteBuf::from(SESSION_KEY), expired).unwrap(),
+            ),
+        );
+        let result = renew_delegation(
+            &address,
+            ByteBuf::from(SESSION_KEY),
+            expired,
+            new_expiration,
+            &mut signature_map,
+            &canister_id,
+        );
+        assert!(matches!(result, Err(DelegationError::SignatureExpired)));
+    }
+
+    #[test]
+    fn test_renew_delegation_bounded_by_old_expiration() {
+        let settings = SettingsBuilder::new("example.com", "http://example.com", "some_salt")
+            .session_expires_in(60_000_000_000)
+            .max_session_expires_in(120_000_000_000)
+            .build()
+            .unwrap();
+        SETTINGS.set(Some(std::rc::Rc::new(settings)));
+        let address = solana::SolPubkey::from(Keypair::new().pubkey().to_bytes());
+        let canister_id = Principal::from_text("aaaaa-aa").unwrap();
+        let seed_hash = hash::hash_bytes(generate_seed(&address));
+        let old_expiration = get_current_time() + 10_000_000_000;
+        let mut signature_map = SignatureMap::default();
+        signature_map.put(
+            seed_hash,
+            create_delegation_hash(
+                &create_delegation(ByteBuf::from(SESSION_KEY), old_expiration).unwrap(),
+            ),
+        );
+
+        let result = renew_delegation(
+            &address,
+            ByteBuf::from(SESSION_KEY),
+            old_expiration,
+            old_expiration + 120_000_000_001,
+            &mut signature_map,
+            &canister_id,
+        );
+        assert!(matches!(
+            result,
+            Err(DelegationError::ExpiryTooFar { max_allowed, .. })
+                if max_allowed == old_expiration + 120_000_000_000
+        ));
+    }
+
+    #[test]
+    fn test_renew_delegation_keeps_other_devices() {
+        let address = init();
+        let canister_id = Principal::from_text("aaaaa-aa").unwrap();
+        let seed_hash = hash::hash_bytes(generate_seed(&address));
+        let old_expiration = get_current_time() + 30_000_000_000;
+        let other_session_key = ByteBuf::from(vec![7u8; 32]);
+        let old_delegation_hash = create_delegation_hash(
+            &create_delegation(ByteBuf::from(SESSION_KEY), old_expiration).unwrap(),
+        );
+        let other_delegation_hash =
+            create_delegation_hash(&create_delegation(other_session_key, old_expiration).unwrap());
+        let mut signature_map = SignatureMap::default();
+        signature_map.put(seed_hash, old_delegation_hash);
+        signature_map.put(seed_hash, other_delegation_hash);
+
+        renew_delegation(
+            &address,
+            ByteBuf::from(SESSION_KEY),
+            old_expiration,
+            get_current_time() + 60_000_000_000,
+            &mut signature_map,
+            &canister_id,
+        )
+        .unwrap();
+        assert!(!signature_map.contains(seed_hash, old_delegation_hash));
+        assert!(signature_map.contains(seed_hash, other_delegation_hash));
+        assert_eq!(signature_map.delegations_for_seed(seed_hash).len(), 2);
+    }
+
+    #[test]
+    fn test_verify_witness() {
+        let address = init();
+        let seed = generate_seed(&address);
+        let seed_hash = hash::hash_bytes(seed);
+        let delegation = create_delegation(ByteBuf::from(SESSION_KEY), 123456789).unwrap();
+        let delegation_hash = create_delegation_hash(&delegation);
+        let mut signature_map = SignatureMap::default();
+        signature_map.put(seed_hash, delegation_hash);
+        signature_map.put(hash::hash_bytes(b"other seed"), hash::hash_bytes(b"other"));
+
+        let root_hash = signature_map.root_hash();
+        let cbor =
+            serde_cbor::to_vec(&witness(&signature_map, seed, delegation_hash).unwrap()).unwrap();
+        assert!(verify_witness(
+            &root_hash,
+            &seed_hash,
+            &delegation_hash,
+            &cbor
+        ));
+
+        let other_hash = hash::hash_bytes(b"other");
+        assert!(!verify_witness(
+            &other_hash,
+            &seed_hash,
+            &delegation_hash,
+            &cbor
+        ));
+        assert!(!verify_witness(&root_hash, &seed_hash, &other_hash, &cbor));
+        assert!(!verify_witness(
+            &root_hash,
+            &seed_hash,
+            &delegation_hash,
+            b"not cbor"
+        ));
+    }
+
+    #[test]
+    fn test_witness_wire_format() {
+        let address = init();
+        let seed = generate_seed(&address);
+        let delegation = create_delegation(ByteBuf::from(SESSION_KEY), 123456789).unwrap();
+        let delegation_hash = create_delegation_hash(&delegation);
+        let other_seed_hash = hash::hash_bytes(b"other seed");
+        let other_delegation_hash = hash::hash_bytes(b"other delegation");
+        let mut signature_map = SignatureMap::default();
+        signature_map.put(hash::hash_bytes(seed), delegation_hash);
+        signature_map.put(other_seed_hash, other_delegation_hash);
+
+        // Encode the witness as it is sent on the wire, wrapped in the `sig` label.
+        let tree = ic_certified_map::labeled(
+            b"sig",
+            witness(&signature_map, seed, delegation_hash).unwrap(),
+        );
+        let cbor: serde_cbor::Value =
+            serde_cbor::from_slice(&serde_cbor::to_vec(&tree).unwrap()).unwrap();
+        let tree = WitnessTree::from_cbor(&cbor).unwrap();
+
+        // Verify according to the ICP spec: the reconstructed root hash matches the certified data and the
+        // path resolves to an empty leaf.
+        assert_eq!(
+            tree.reconstruct(),
+            labeled_hash(b"sig", &signature_map.root_hash())
+        );
+        let seed_hash = hash::hash_bytes(seed);
+        match tree.lookup(&[&b"sig"[..], &seed_hash[..], &delegation_hash[..]]) {
+            Some(WitnessTree::Leaf(leaf)) => assert!(leaf.is_empty()),
+            _ => panic!("Expected an empty leaf"),
+        }
+
+        // Other entries are pruned from the witness.
+        assert!(tree
+            .lookup(&[
+                &b"sig"[..],
+                &other_seed_hash[..],
+                &other_delegation_hash[..]
+            ])
+            .is_none());
+    }
+
     #[test]
     fn test_witness_multiple_entries() {
         let address = init();
@@ -425,6 +1475,68 @@ mod tests {
         assert!(!signature.is_empty(), "Signature should not be empty");
     }
 
+    #[derive(Serialize)]
+    struct TestCertificate<'a> {
+        tree: HashTree<'a>,
+        signature: ByteBuf,
+    }
+
+    #[test]
+    fn test_verify_signature() {
+        let address = init();
+        let canister_id = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap();
+        let seed = generate_seed(&address);
+        let delegation = create_delegation(ByteBuf::from(SESSION_KEY), 123456789).unwrap();
+        let delegation_hash = create_delegation_hash(&delegation);
+        let mut signature_map = SignatureMap::default();
+        signature_map.put(hash::hash_bytes(seed), delegation_hash);
+
+        // Certify the signature map root hash as the canister certified data.
+        let signature_witness = signature_map
+            .witness(hash::hash_bytes(seed), delegation_hash)
+            .unwrap();
+        let tree = ic_certified_map::labeled(b"sig", signature_witness);
+        let root_hash = tree.reconstruct();
+        let certificate_tree = ic_certified_map::labeled(
+            b"canister",
+            ic_certified_map::labeled(
+                canister_id.as_slice(),
+                ic_certified_map::labeled(
+                    b"certified_data",
+                    HashTree::Leaf(std::borrow::Cow::Borrowed(&root_hash)),
+                ),
+            ),
+        );
+        let certificate = cbor_serialize(&TestCertificate {
+            tree: certificate_tree,
+            signature: ByteBuf::new(),
+        })
+        .unwrap();
+        let signature = create_certified_signature(certificate, tree).unwrap();
+        let signed_delegation = SignedDelegation {
+            delegation,
+            signature: ByteBuf::from(signature),
+        };
+
+        let canister_pubkey = create_user_canister_pubkey(&canister_id, seed.to_vec()).unwrap();
+        assert!(signed_delegation.verify_signature(&canister_pubkey).is_ok());
+
+        // The delegation is not certified for another seed.
+        let other_pubkey = create_user_canister_pubkey(&canister_id, vec![0u8; 32]).unwrap();
+        assert!(matches!(
+            signed_delegation.verify_signature(&other_pubkey),
+            Err(DelegationError::SignatureNotFound)
+        ));
+
+        // The certified data is not valid for another canister.
+        let other_canister_id = Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap();
+        let other_pubkey = create_user_canister_pubkey(&other_canister_id, seed.to_vec()).unwrap();
+        assert!(matches!(
+            signed_delegation.verify_signature(&other_pubkey),
+            Err(DelegationError::InvalidSignature(_))
+        ));
+    }
+
     #[test]
     fn test_create_user_canister_pubkey() {
         let address = init();
@@ -440,6 +1552,58 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_user_canister_pubkey_is_scoped_to_canister() {
+        // Delegation signatures can't be replayed across canisters, because the public key the IC verifies them
+        // against contains the canister id.
+        let address = init();
+        let seed = generate_seed(&address);
+        let pubkey_a =
+            create_user_canister_pubkey(&Principal::from_text("aaaaa-aa").unwrap(), seed.to_vec())
+                .unwrap();
+        let pubkey_b = create_user_canister_pubkey(
+            &Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap(),
+            seed.to_vec(),
+        )
+        .unwrap();
+        assert_ne!(pubkey_a, pubkey_b);
+    }
+
+    #[test]
+    fn test_create_user_canister_pubkey_with_subaccount() {
+        let address = init();
+        let seed = generate_seed(&address);
+        let canister_id = Principal::from_text("aaaaa-aa").unwrap();
+        let default_pubkey = create_user_canister_pubkey(&canister_id, seed.to_vec()).unwrap();
+        assert_eq!(
+            create_user_canister_pubkey_with_subaccount(&canister_id, &seed, 0).unwrap(),
+            default_pubkey
+        );
+        let pubkey_1 = create_user_canister_pubkey_with_subaccount(&canister_id, &seed, 1).unwrap();
+        let pubkey_2 = create_user_canister_pubkey_with_subaccount(&canister_id, &seed, 2).unwrap();
+        assert_ne!(pubkey_1, default_pubkey);
+        assert_ne!(pubkey_1, pubkey_2);
+    }
+
+    #[test]
+    fn test_create_user_canister_pubkey_raw() {
+        let address = init();
+        let seed = generate_seed(&address);
+        let raw_pubkey = create_user_canister_pubkey_raw(&seed);
+        assert_eq!(raw_pubkey, hash::hash_bytes(seed));
+        assert_ne!(raw_pubkey, seed);
+    }
+
+    #[test]
+    fn test_create_user_canister_pubkey_oid() {
+        let pubkey = create_user_canister_pubkey(&Principal::anonymous(), vec![0u8; 32]).unwrap();
+        let mut encoded_oid = vec![0x06, ICP_SELF_AUTH_KEY_OID.len() as u8];
+        encoded_oid.extend_from_slice(ICP_SELF_AUTH_KEY_OID);
+        assert!(pubkey
+            .windows(encoded_oid.len())
+            .any(|window| window == encoded_oid.as_slice()));
+    }
+
     #[test]
     fn test_cbor_serialize() {
         let cbor = cbor_serialize(&vec![1, 2, 3]).unwrap();
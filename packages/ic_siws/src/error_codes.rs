@@ -0,0 +1,38 @@
+//! Numeric codes for [`LoginError`](crate::login::LoginError) variants, see
+//! [`LoginError::code`](crate::login::LoginError::code). Frontend clients can use the codes to tell login
+//! failures apart without parsing error messages.
+//!
+//! The codes are stable: a code is never reused for a different variant and doesn't change in minor versions.
+//! New variants get new codes.
+//!
+//! | Code | Variant |
+//! |------|---------|
+//! | 1001 | `SignatureError` |
+//! | 1002 | `SiwsMessageError` |
+//! | 1003 | `AddressMismatch` |
+//! | 1004 | `DelegationError` |
+//! | 1005 | `ASN1EncodeErr` |
+//! | 1006 | `MessageIssuedInFuture` |
+//! | 1007 | `MessageNotYetValid` |
+//! | 1008 | `IssueTimeTooOld` |
+//! | 1009 | `UriMismatch` |
+//! | 1010 | `SessionKeyMismatch` |
+//! | 1011 | `AddressBlocklisted` |
+//! | 1012 | `PreCheckFailed` |
+//! | 1013 | `InvalidSessionExpiresIn` |
+//! | 1014 | `SignatureMapError` |
+
+pub const SIGNATURE_ERROR: u32 = 1001;
+pub const SIWS_MESSAGE_ERROR: u32 = 1002;
+pub const ADDRESS_MISMATCH: u32 = 1003;
+pub const DELEGATION_ERROR: u32 = 1004;
+pub const ASN1_ENCODE_ERR: u32 = 1005;
+pub const MESSAGE_ISSUED_IN_FUTURE: u32 = 1006;
+pub const MESSAGE_NOT_YET_VALID: u32 = 1007;
+pub const ISSUE_TIME_TOO_OLD: u32 = 1008;
+pub const URI_MISMATCH: u32 = 1009;
+pub const SESSION_KEY_MISMATCH: u32 = 1010;
+pub const ADDRESS_BLOCKLISTED: u32 = 1011;
+pub const PRE_CHECK_FAILED: u32 = 1012;
+pub const INVALID_SESSION_EXPIRES_IN: u32 = 1013;
+pub const SIGNATURE_MAP_ERROR: u32 = 1014;
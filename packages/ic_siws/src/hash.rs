@@ -1,4 +1,5 @@
-/// Utilities for computing hashes of values.
+//! Utilities for computing hashes of values.
+
 use ic_certified_map::Hash;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
@@ -30,8 +31,17 @@ pub(crate) fn hash_of_map<S: AsRef<str>>(map: HashMap<S, Value>) -> Hash {
     hasher.finalize().into()
 }
 
-/// Computes a hash with a domain separator.
-pub(crate) fn hash_with_domain(sep: &[u8], bytes: &[u8]) -> Hash {
+/// Computes the SHA-256 hash of `bytes` with a domain separator, as defined by the ICP interface
+/// specification. The separator is prefixed with its length as a single byte.
+///
+/// # Example
+/// ```
+/// use ic_siws::hash::{hash_with_domain, sha256};
+///
+/// let hash = hash_with_domain(b"ic-request-auth-delegation", b"data");
+/// assert_eq!(hash, sha256(b"\x1aic-request-auth-delegationdata"));
+/// ```
+pub fn hash_with_domain(sep: &[u8], bytes: &[u8]) -> Hash {
     let mut hasher = Sha256::new();
     hasher.update([sep.len() as u8]);
     hasher.update(sep);
@@ -52,8 +62,28 @@ pub(crate) fn hash_string(value: &str) -> Hash {
     hash_bytes(value.as_bytes())
 }
 
-/// Hashes a byte slice.
-pub(crate) fn hash_bytes(value: impl AsRef<[u8]>) -> Hash {
+/// Computes the SHA-256 hash of a byte slice.
+///
+/// # Example
+/// ```
+/// use ic_siws::hash::sha256;
+///
+/// let hash = sha256(b"abc");
+/// assert_eq!(hash[..4], [0xba, 0x78, 0x16, 0xbf]);
+/// ```
+pub fn sha256(data: &[u8]) -> Hash {
+    hash_bytes(data)
+}
+
+/// Computes the SHA-256 hash of anything that can be referenced as bytes. Alias of [`sha256`].
+///
+/// # Example
+/// ```
+/// use ic_siws::hash::{hash_bytes, sha256};
+///
+/// assert_eq!(hash_bytes("abc"), sha256(b"abc"));
+/// ```
+pub fn hash_bytes(value: impl AsRef<[u8]>) -> Hash {
     let mut hasher = Sha256::new();
     hasher.update(value.as_ref());
     hasher.finalize().into()
@@ -1,4 +1,9 @@
-use crate::{settings::Settings, SETTINGS};
+use crate::{
+    login::schedule_message_pruning,
+    settings::{Settings, SettingsError},
+    SETTINGS,
+};
+use std::rc::Rc;
 
 /// Initializes the SIWS library with the provided settings. Must be called before any other SIWS functions. Use the [SettingsBuilder](crate::settings::SettingsBuilder)  to create a [Settings] object.
 ///
@@ -23,13 +28,42 @@ use crate::{settings::Settings, SETTINGS};
 /// ```
 ///
 pub fn init(settings: Settings) -> Result<(), String> {
-    SETTINGS.set(Some(settings));
+    let auto_prune_interval_ns = settings.auto_prune_interval_ns;
+    SETTINGS.set(Some(Rc::new(settings)));
 
     init_rng();
 
+    if let Some(interval_ns) = auto_prune_interval_ns {
+        schedule_message_pruning(interval_ns);
+    }
+
     Ok(())
 }
 
+/// Restores settings created by [`Settings::to_stable_bytes`] and initializes the SIWS library with them.
+/// Thread-local state is lost when a canister is upgraded, canisters that construct their settings at deploy
+/// time can persist them in stable memory instead of passing them again on upgrade.
+///
+/// # Examples
+///
+/// ```ignore
+/// #[pre_upgrade]
+/// fn pre_upgrade() {
+///     let bytes = with_settings!(|settings: &Settings| settings.to_stable_bytes());
+///     storage::stable_save((bytes,)).unwrap();
+/// }
+///
+/// #[post_upgrade]
+/// fn post_upgrade() {
+///     let (bytes,): (Vec<u8>,) = storage::stable_restore().unwrap();
+///     ic_siws::restore_settings(&bytes).unwrap();
+/// }
+/// ```
+pub fn restore_settings(bytes: &[u8]) -> Result<(), SettingsError> {
+    let settings = Settings::from_stable_bytes(bytes)?;
+    init(settings).map_err(SettingsError::ValidationError)
+}
+
 fn init_rng() {
     use crate::RNG;
     use candid::Principal;
@@ -25,6 +25,7 @@ for Ethereum-based applications.
   - [`siws_login`](#siws_login)
   - [`siws_get_delegation`](#siws_get_delegation)
 - [Crate features](#crate-features)
+- [Login error codes](#login-error-codes)
 - [Updates](#updates)
 - [Contributing](#contributing)
 - [License](#license)
@@ -35,9 +36,7 @@ Sign In With Solana (SIWS) is a Solana wallet standard with broad support across
 
 At the core of the protocol is the SIWS message, which is a signed message that contains the Solana address of the user and some additional metadata. The SIWS message is signed by the user's Solana wallet and then sent to the application's backend. The backend verifies the signature and Solana address and then creates a session for the user.
 
-`ic_siws` implements most parts of the Sign In with Solana standard, with some notable exceptions:
-
-- `not-before`, `request-id`, `resources` - Not implemented. These fields are marked as OPTIONAL in the SIWS standard and are not currently implemented.
+`ic_siws` implements the Sign In with Solana standard, including the OPTIONAL `not-before`, `request-id` and `resources` fields.
 
 
 ## Prebuilt `ic_siws_provider` canister
@@ -114,6 +113,7 @@ type Delegation = record {
   pubkey : PublicKey;
   expiration : Timestamp;
   targets : opt vec principal;
+  memo : opt blob;
 };
 
 type GetPrincipalResponse = variant {
@@ -129,6 +129,7 @@ type LoginResponse = variant {
 type LoginDetails = record {
   expiration : Timestamp;
   user_canister_pubkey : CanisterPublicKey;
+  session_token : opt text;
 };
 
 type SiwsMessage = record {
@@ -141,6 +142,9 @@ type SiwsMessage = record {
   nonce : text;
   issued_at : nat64;
   expiration_time : nat64;
+  not_before : opt nat64;
+  request_id : opt text;
+  resources : vec text;
 };
 
 type PrepareLoginResponse = variant {
@@ -235,6 +239,13 @@ The login flow is illustrated in the following diagram:
                                 └────────┘                                        └────────┘                              └─────────┘
 ```
 
+## Login error codes
+
+Each [`LoginError`](login::LoginError) has a stable numeric code, returned by
+[`LoginError::code`](login::LoginError::code), that clients can use to tell login failures apart without
+parsing error messages. Codes start at 1001, for example 1001 for `SignatureError`, 1003 for `AddressMismatch`
+and 1012 for `PreCheckFailed`. See [`error_codes`] for the full list.
+
 ## Updates
 
 See the [CHANGELOG](https://github.com/kristoferlund/ic-siws/blob/main/packages/ic_siws/CHANGELOG.md) for details on updates.
@@ -255,24 +266,34 @@ Contributions are welcome. Please submit your pull requests or open issues to pr
 This project is licensed under the MIT License. See the LICENSE file for more details.
 
 */
+pub mod access_control;
 pub mod delegation;
-pub(crate) mod hash;
+pub mod error_codes;
+pub mod hash;
 pub(crate) mod init;
 pub mod login;
+pub mod login_cache;
 mod macros;
-pub(crate) mod rand;
+pub mod rand;
+pub mod rate_limit;
+pub mod session_key;
 pub mod settings;
 pub mod signature_map;
 pub mod siws;
 pub mod solana;
-pub(crate) mod time;
+pub mod time;
+pub mod upgrade;
 
-pub use init::init;
+pub use init::{init, restore_settings};
 
+use access_control::AccessControl;
+use login_cache::LoginCache;
+use rate_limit::AddressRateLimiter;
 use settings::Settings;
 use siws::SiwsMessageMap;
-use std::cell::RefCell;
+use std::{cell::RefCell, rc::Rc};
 
+use ic_cdk_timers::TimerId;
 use rand_chacha::ChaCha20Rng;
 
 thread_local! {
@@ -281,11 +302,32 @@ thread_local! {
     static RNG: RefCell<Option<ChaCha20Rng>> = const { RefCell::new(None) };
 
     // The settings control the behavior of the SIWS library. The settings must be initialized
-    // before any other library functions are called.
-    static SETTINGS: RefCell<Option<Settings>> = const { RefCell::new(None) };
+    // before any other library functions are called. Readers get a shared snapshot, see `with_settings!`,
+    // so the settings can be replaced while they are being read.
+    static SETTINGS: RefCell<Option<Rc<Settings>>> = const { RefCell::new(None) };
 
     // SIWS messages are stored in global state during the login process. The key is the
     // Solana address as a byte array and the value is the SIWS message. After a successful
     // login, the SIWS message is removed from state.
     static SIWS_MESSAGES: RefCell<SiwsMessageMap> = RefCell::new(SiwsMessageMap::new());
+
+    // The times of recent prepare_login calls per address, used to rate limit the calls. Pruned together
+    // with the SIWS messages.
+    static PREPARE_LOGIN_RATE_LIMITER: RefCell<AddressRateLimiter> =
+        RefCell::new(AddressRateLimiter::new());
+
+    // The results of recent logins, so that retried login calls succeed. Pruned together with the SIWS
+    // messages.
+    static LOGIN_CACHE: RefCell<LoginCache> = RefCell::new(LoginCache::new());
+
+    // Addresses that have been banned from signing in at runtime, see `access_control::block_address`.
+    static ACCESS_CONTROL: RefCell<AccessControl> = RefCell::new(AccessControl::new());
+
+    // The timer that periodically prunes expired SIWS messages, if automatic pruning is enabled.
+    static PRUNE_TIMER: RefCell<Option<TimerId>> = const { RefCell::new(None) };
+
+    // The timer that periodically prunes the signature map of the canister, see
+    // `delegation::start_auto_prune_timer`.
+    #[cfg(feature = "timers")]
+    static SIGNATURE_MAP_PRUNE_TIMER: RefCell<Option<TimerId>> = const { RefCell::new(None) };
 }
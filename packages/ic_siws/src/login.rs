@@ -1,27 +1,35 @@
 use crate::{
+    access_control,
     delegation::{
-        create_delegation, create_delegation_hash, create_user_canister_pubkey, generate_seed,
-        DelegationError,
+        compute_delegation_expiry, create_delegation, create_delegation_hash,
+        create_delegation_with_targets, create_user_canister_pubkey, generate_seed,
+        generate_seed_with_salt, normalize_session_key, DelegationError,
     },
-    hash,
-    rand::generate_nonce,
+    error_codes, hash,
+    login_cache::login_cache_key,
+    rate_limit::RateLimitError,
     settings::Settings,
-    signature_map::SignatureMap,
-    siws::{SiwsMessage, SiwsMessageError},
+    signature_map::{SignatureMap, SignatureMapError, DELEGATION_SIGNATURE_EXPIRES_AT},
+    siws::{SiwsMessage, SiwsMessageBuilder, SiwsMessageError},
     solana::{verify_sol_signature, SolError, SolPubkey, SolSignature},
     time::get_current_time,
-    with_settings, SIWS_MESSAGES,
+    with_settings, LOGIN_CACHE, PREPARE_LOGIN_RATE_LIMITER, PRUNE_TIMER, SIWS_MESSAGES,
 };
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
 use candid::{CandidType, Principal};
+use ic_certified_map::Hash;
 use serde::Deserialize;
 use serde_bytes::ByteBuf;
 use simple_asn1::ASN1EncodeErr;
-use std::fmt;
-
-const MAX_SIGS_TO_PRUNE: usize = 10;
+use std::{fmt, future::Future, time::Duration};
 
 type Nonce = String;
 
+const SESSION_KEY_RESOURCE_PREFIX: &str = "icp:session:";
+
+// The seed stores the salt length in a single byte.
+const MAX_SEED_SALT_BYTES: usize = u8::MAX as usize;
+
 /// This function is the first step of the user login process. It validates the provided Solana address,
 /// creates a SIWS message and its `nonce`, saves it for future use, and returns it. The `nonce` is
 /// used by the login function to prevent replay attacks. It is also used as part of the SIWS
@@ -37,16 +45,324 @@ type Nonce = String;
 /// let address = SolPubkey::from_str("Awes4Tr6TX8JDzEhCZY2QVNimT6iD1zWHzf1vNyGvpLM").unwrap()
 /// let message = prepare_login(&address).unwrap();
 /// ```
-pub fn prepare_login(address: &SolPubkey) -> SiwsMessage {
-    let nonce = generate_nonce();
-    let message = SiwsMessage::new(address, &nonce);
+pub fn prepare_login(address: &SolPubkey) -> Result<SiwsMessage, PrepareLoginError> {
+    prepare_and_store_message(address, SiwsMessageBuilder::new())
+}
+
+/// Works like [`prepare_login`] but binds the SIWS message to a session key generated by the frontend before
+/// the login flow starts. The SHA-256 hash of the DER encoded session key is embedded in the message resources as
+/// `icp:session:{hex}`, so raw and DER encoded keys can be used interchangeably. The [`login`] function verifies that the session key presented at login matches the
+/// one the user signed, preventing a man-in-the-middle from substituting their own session key.
+pub fn prepare_login_with_session_key(
+    address: &SolPubkey,
+    session_key: ByteBuf,
+) -> Result<SiwsMessage, PrepareLoginError> {
+    prepare_and_store_message(
+        address,
+        SiwsMessageBuilder::new().resources(vec![session_key_resource(&session_key)]),
+    )
+}
+
+/// Works like [`prepare_login`] but adds `resources` to the SIWS message, allowing the user to authorize
+/// access to the listed resources. The resources are added after the `default_resources` from the settings.
+/// Each resource must be a valid URI.
+pub fn prepare_login_with_resources(
+    address: &SolPubkey,
+    resources: Vec<String>,
+) -> Result<SiwsMessage, PrepareLoginError> {
+    prepare_and_store_message(address, SiwsMessageBuilder::new().resources(resources))
+}
+
+/// Works like [`prepare_login`] but attaches a request ID to the SIWS message, allowing the message to be
+/// correlated with an application-level request. The request ID must not exceed 4096 characters. The
+/// request ID does not affect how the message is stored or the identity of the user.
+pub fn prepare_login_with_request_id(
+    address: &SolPubkey,
+    request_id: String,
+) -> Result<SiwsMessage, PrepareLoginError> {
+    prepare_and_store_message(address, SiwsMessageBuilder::new().request_id(&request_id))
+}
+
+/// Works like [`prepare_login`] but builds the SIWS message from `builder`, allowing a custom statement,
+/// nonce, not-before time, request ID and resources. The customized fields are validated the same way as
+/// the settings.
+pub fn prepare_login_with_builder(
+    address: &SolPubkey,
+    builder: SiwsMessageBuilder,
+) -> Result<SiwsMessage, PrepareLoginError> {
+    prepare_and_store_message(address, builder)
+}
+
+fn prepare_and_store_message(
+    address: &SolPubkey,
+    builder: SiwsMessageBuilder,
+) -> Result<SiwsMessage, PrepareLoginError> {
+    // Program addresses have no private key and can never sign a message.
+    if address.is_null() {
+        return Err(PrepareLoginError::NullAddress);
+    }
+    if address.is_native_program() {
+        return Err(PrepareLoginError::SystemProgramAddress);
+    }
+    if access_control::is_blocked(address) {
+        return Err(PrepareLoginError::AddressBlocklisted);
+    }
+    let (is_blocked, is_allowed) = with_settings!(|settings: &Settings| {
+        (
+            settings.additional_blocked_programs.contains(address),
+            settings.is_address_allowed(address),
+        )
+    });
+    if is_blocked {
+        return Err(PrepareLoginError::AddressBlocked);
+    }
+    if !is_allowed {
+        return Err(PrepareLoginError::AddressNotAllowed);
+    }
+    if !address.is_on_curve() {
+        return Err(PrepareLoginError::ProgramDerivedAddress);
+    }
+    // Signature verification rejects small order keys, don't store a message that can never be signed.
+    if SolPubkey::try_from_bytes(address.as_bytes()).is_err() {
+        return Err(PrepareLoginError::WeakAddress);
+    }
+
+    let (max_calls, window_ns) = with_settings!(|settings: &Settings| {
+        (
+            settings.max_prepare_login_calls,
+            settings.prepare_login_window_ns,
+        )
+    });
+    let current_time = get_current_time();
+    PREPARE_LOGIN_RATE_LIMITER
+        .with_borrow(|limiter| limiter.check(address, current_time, max_calls, window_ns))
+        .map_err(|e| match e {
+            RateLimitError::RateLimitExceeded { retry_after_ns } => {
+                PrepareLoginError::RateLimitExceeded { retry_after_ns }
+            }
+        })?;
+
+    // The message is sanitized here rather than only when it is stored, so that the caller gets the exact
+    // text the login call verifies the signature against.
+    let message = builder
+        .build(address)
+        .map_err(|e| match e {
+            SiwsMessageError::RequestIdTooLong | SiwsMessageError::InvalidRequestId => {
+                PrepareLoginError::InvalidRequestId(e.to_string())
+            }
+            SiwsMessageError::InvalidResource(e) => PrepareLoginError::InvalidResource(e),
+            e => PrepareLoginError::InvalidMessage(e.to_string()),
+        })?
+        .sanitize();
 
     // Save the SIWS message for use in the login call
+    let (max_per_address, max_pending) = with_settings!(|settings: &Settings| {
+        (
+            settings.max_pending_messages_per_address,
+            settings.max_pending_logins,
+        )
+    });
     SIWS_MESSAGES.with_borrow_mut(|siws_messages| {
-        siws_messages.insert(address, message.clone(), &nonce);
+        siws_messages
+            .insert(
+                address,
+                message.clone(),
+                &message.nonce,
+                max_per_address,
+                max_pending,
+            )
+            .map_err(|e| match e {
+                SiwsMessageError::TooManyPendingMessages { .. } => {
+                    PrepareLoginError::TooManyPendingLogins
+                }
+                e => PrepareLoginError::InternalError(e.to_string()),
+            })
+    })?;
+    // Only calls that stored a message count against the rate limit.
+    PREPARE_LOGIN_RATE_LIMITER
+        .with_borrow_mut(|limiter| limiter.record(address, current_time, window_ns));
+
+    #[cfg(feature = "debug-logging")]
+    log_event(LoginEvent::MessagePrepared {
+        address: address.to_string(),
+        nonce: message.nonce.clone(),
     });
 
-    message
+    Ok(message)
+}
+
+/// Structured events emitted by the login flow when the `debug-logging` feature is enabled.
+#[cfg(feature = "debug-logging")]
+#[derive(serde::Serialize, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum LoginEvent {
+    MessagePrepared {
+        address: String,
+        nonce: String,
+    },
+    LoginSucceeded {
+        address: String,
+        expiration: u64,
+        /// See the `bool` returned by [`login`].
+        recent_login: bool,
+    },
+    LoginFailed {
+        address: String,
+        error: String,
+    },
+}
+
+/// Prints the event as a single line of JSON, making the logs parseable by log aggregation tools. Requires the
+/// `debug-logging` feature.
+#[cfg(feature = "debug-logging")]
+pub fn log_event(event: LoginEvent) {
+    let json = serde_json::to_string(&event).expect("Login events should be JSON serializable");
+    ic_cdk::println!("{}", json);
+}
+
+/// The reasons `prepare_login` can fail. Canisters can return the error as a Candid variant, or convert it to a
+/// string with `to_string()`.
+#[derive(Debug, CandidType)]
+pub enum PrepareLoginError {
+    /// The address is a well-known Solana native program.
+    SystemProgramAddress,
+    /// The address is listed in the `additional_blocked_programs` setting.
+    AddressBlocked,
+    /// The address is not in the `allowed_addresses` setting.
+    AddressNotAllowed,
+    /// The address has been banned with [`block_address`](crate::access_control::block_address).
+    AddressBlocklisted,
+    /// The address is a program derived address (off the ed25519 curve).
+    ProgramDerivedAddress,
+    /// The address consists of zero bytes only.
+    NullAddress,
+    /// The address is an ed25519 point of small order, such as the identity, that no private key signs for.
+    WeakAddress,
+    /// The number of pending SIWS messages has reached `max_pending_logins`.
+    TooManyPendingLogins,
+    /// The address has made `max_prepare_login_calls` calls within `prepare_login_window_ns`.
+    RateLimitExceeded {
+        retry_after_ns: u64,
+    },
+    InvalidRequestId(String),
+    InvalidResource(String),
+    InvalidMessage(String),
+    /// An unexpected error while storing the SIWS message.
+    InternalError(String),
+}
+
+impl fmt::Display for PrepareLoginError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PrepareLoginError::SystemProgramAddress => {
+                write!(f, "Program addresses are not allowed to sign in")
+            }
+            PrepareLoginError::AddressBlocked => write!(f, "Address is blocked from signing in"),
+            PrepareLoginError::AddressNotAllowed => {
+                write!(f, "Address is not allowed to sign in")
+            }
+            PrepareLoginError::AddressBlocklisted => write!(f, "Address has been banned"),
+            PrepareLoginError::ProgramDerivedAddress => {
+                write!(f, "Program derived addresses are not allowed to sign in")
+            }
+            PrepareLoginError::NullAddress => {
+                write!(f, "The null address is not allowed to sign in")
+            }
+            PrepareLoginError::WeakAddress => {
+                write!(f, "Small order public keys are not allowed to sign in")
+            }
+            PrepareLoginError::TooManyPendingLogins => {
+                write!(f, "Too many pending login requests, try again later")
+            }
+            PrepareLoginError::RateLimitExceeded { retry_after_ns } => write!(
+                f,
+                "Too many login requests, try again in {} ns",
+                retry_after_ns
+            ),
+            PrepareLoginError::InvalidRequestId(e) => write!(f, "Invalid request ID: {}", e),
+            PrepareLoginError::InvalidResource(e) => write!(f, "{}", e),
+            PrepareLoginError::InvalidMessage(e) => write!(f, "Invalid message: {}", e),
+            PrepareLoginError::InternalError(e) => write!(f, "Internal error: {}", e),
+        }
+    }
+}
+
+impl From<PrepareLoginError> for String {
+    fn from(error: PrepareLoginError) -> Self {
+        error.to_string()
+    }
+}
+
+/// Schedules a timer that prunes expired SIWS messages every `interval_ns` nanoseconds. Without it, expired
+/// messages are only pruned lazily on the next login. Any previously scheduled pruning is cancelled.
+///
+/// Called automatically by [`crate::init`] when [`Settings::auto_prune_interval_ns`] is set.
+pub fn schedule_message_pruning(interval_ns: u64) {
+    cancel_message_pruning();
+    let timer_id = ic_cdk_timers::set_timer_interval(Duration::from_nanos(interval_ns), || {
+        prune_pending_logins();
+    });
+    PRUNE_TIMER.set(Some(timer_id));
+}
+
+// Prunes expired SIWS messages, the prepare_login calls that have left the rate limit window and expired
+// entries of the login cache.
+fn prune_pending_logins() {
+    SIWS_MESSAGES.with_borrow_mut(|siws_messages| siws_messages.prune_expired());
+    prune_prepare_login_calls();
+    prune_login_cache();
+}
+
+fn prune_prepare_login_calls() {
+    let window_ns = with_settings!(|settings: &Settings| settings.prepare_login_window_ns);
+    PREPARE_LOGIN_RATE_LIMITER
+        .with_borrow_mut(|limiter| limiter.prune(get_current_time(), window_ns));
+}
+
+fn prune_login_cache() {
+    LOGIN_CACHE.with_borrow_mut(|login_cache| login_cache.prune(get_current_time()));
+}
+
+/// Cancels the pruning timer scheduled by [`schedule_message_pruning`], if any.
+pub fn cancel_message_pruning() {
+    if let Some(timer_id) = PRUNE_TIMER.take() {
+        ic_cdk_timers::clear_timer(timer_id);
+    }
+}
+
+/// Returns the number of SIWS messages awaiting login, including expired messages that have not been pruned
+/// yet, e.g. for a monitoring endpoint. See [`SiwsMessageMap::len`](crate::siws::SiwsMessageMap::len).
+pub fn pending_login_count() -> usize {
+    SIWS_MESSAGES.with_borrow(|siws_messages| siws_messages.len())
+}
+
+/// Returns `true` if the Solana address has logged in within about the last minute, i.e. the signature map
+/// still holds a signature for it that has not expired. Requires the library to be initialized.
+///
+/// Signatures are only needed until the client has fetched the delegation and are pruned after about a minute.
+/// A `false` result does not mean that the user has no valid session, so don't use this to gate access.
+pub fn has_recent_login(pubkey: &SolPubkey, signature_map: &SignatureMap) -> bool {
+    recent_login_count(pubkey, signature_map) > 0
+}
+
+/// Returns the number of signatures for the Solana address in the signature map that have not expired, one
+/// per session key the address has logged in with in about the last minute. See [`has_recent_login`].
+pub fn recent_login_count(pubkey: &SolPubkey, signature_map: &SignatureMap) -> usize {
+    let seed_hash = hash::hash_bytes(generate_seed(pubkey));
+    signature_map
+        .active_delegations_iter(get_current_time())
+        .filter(|(entry_seed_hash, _, _)| *entry_seed_hash == seed_hash)
+        .count()
+}
+
+/// Creates the SIWS message resource that binds a message to a session key.
+fn session_key_resource(session_key: &[u8]) -> String {
+    let session_key = normalize_session_key(ByteBuf::from(session_key));
+    format!(
+        "{}{}",
+        SESSION_KEY_RESOURCE_PREFIX,
+        hex::encode(hash::hash_bytes(session_key))
+    )
 }
 
 /// Login details are returned after a successful login. They contain the expiration time of the
@@ -59,14 +375,135 @@ pub struct LoginDetails {
 
     /// The user canister public key. This key is used to derive the user principal.
     pub user_canister_pubkey: ByteBuf,
+
+    /// An opaque, stateless session token for HTTP gateways that do not use delegations. Only issued if
+    /// [`Settings::issue_session_tokens`] is enabled. See [`session_token`].
+    pub session_token: Option<String>,
+}
+
+impl LoginDetails {
+    /// Derives the user principal from the user canister public key. The principal is a self-authenticating
+    /// principal, use this function instead of `Principal::from_slice` which produces a different principal.
+    pub fn derive_principal(&self) -> Principal {
+        Principal::self_authenticating(&self.user_canister_pubkey)
+    }
+
+    /// Returns the number of nanoseconds remaining in the session, or `None` if the session has expired.
+    pub fn session_expires_in_ns(&self, current_time: u64) -> Option<u64> {
+        if self.is_expired(current_time) {
+            None
+        } else {
+            Some(self.expiration - current_time)
+        }
+    }
+
+    /// Returns `true` if the session has expired at `current_time`.
+    pub fn is_expired(&self, current_time: u64) -> bool {
+        self.expiration <= current_time
+    }
+}
+
+/// Creates the session token for a login. The token is the base64url encoded (without padding) SHA-256
+/// hash of the seed, the big-endian expiration time and the DER encoded session key. Raw Ed25519 session keys
+/// are DER encoded first, like in the delegation. A gateway can recompute the token to confirm its validity.
+pub fn session_token(seed: &[u8], expiration: u64, session_key: &[u8]) -> String {
+    let mut bytes = seed.to_vec();
+    bytes.extend_from_slice(&expiration.to_be_bytes());
+    bytes.extend_from_slice(&normalize_session_key(ByteBuf::from(session_key)));
+    URL_SAFE_NO_PAD.encode(hash::hash_bytes(bytes))
 }
 
+#[derive(Debug)]
+#[non_exhaustive]
 pub enum LoginError {
     SignatureError(SolError),
     SiwsMessageError(SiwsMessageError),
     AddressMismatch,
+    MessageIssuedInFuture {
+        issued_at: u64,
+        current_time: u64,
+    },
+    MessageNotYetValid {
+        not_before: u64,
+        current_time: u64,
+    },
+    /// The message was issued more than `max_message_age_ns` nanoseconds ago.
+    IssueTimeTooOld {
+        threshold_ns: u64,
+        age_ns: u64,
+    },
+    UriMismatch {
+        message_uri: String,
+        settings_uri: String,
+    },
+    SessionKeyMismatch,
+    /// The address has been banned with [`block_address`](crate::access_control::block_address) since the
+    /// login was prepared.
+    AddressBlocklisted,
+    PreCheckFailed(String),
+    InvalidSessionExpiresIn {
+        session_expires_in: u64,
+        max_session_expires_in: u64,
+    },
     DelegationError(DelegationError),
+    SignatureMapError(SignatureMapError),
     ASN1EncodeErr(ASN1EncodeErr),
+    /// A login error that is only known by its code, see [`LoginError::code`]. Created when converting a code
+    /// of a variant that carries details back to a `LoginError`, or an unknown code.
+    Other {
+        code: u32,
+    },
+}
+
+impl LoginError {
+    /// Returns the stable numeric code of the error, see [`crate::error_codes`]. Canisters can return the code
+    /// to clients alongside the error message.
+    pub fn code(&self) -> u32 {
+        match self {
+            LoginError::SignatureError(_) => error_codes::SIGNATURE_ERROR,
+            LoginError::SiwsMessageError(_) => error_codes::SIWS_MESSAGE_ERROR,
+            LoginError::AddressMismatch => error_codes::ADDRESS_MISMATCH,
+            LoginError::MessageIssuedInFuture { .. } => error_codes::MESSAGE_ISSUED_IN_FUTURE,
+            LoginError::MessageNotYetValid { .. } => error_codes::MESSAGE_NOT_YET_VALID,
+            LoginError::IssueTimeTooOld { .. } => error_codes::ISSUE_TIME_TOO_OLD,
+            LoginError::UriMismatch { .. } => error_codes::URI_MISMATCH,
+            LoginError::SessionKeyMismatch => error_codes::SESSION_KEY_MISMATCH,
+            LoginError::AddressBlocklisted => error_codes::ADDRESS_BLOCKLISTED,
+            LoginError::PreCheckFailed(_) => error_codes::PRE_CHECK_FAILED,
+            LoginError::InvalidSessionExpiresIn { .. } => error_codes::INVALID_SESSION_EXPIRES_IN,
+            LoginError::DelegationError(_) => error_codes::DELEGATION_ERROR,
+            LoginError::SignatureMapError(_) => error_codes::SIGNATURE_MAP_ERROR,
+            LoginError::ASN1EncodeErr(_) => error_codes::ASN1_ENCODE_ERR,
+            LoginError::Other { code } => *code,
+        }
+    }
+}
+
+/// Converts an error code back to a `LoginError`, e.g. in a Rust client. Codes of variants without details map
+/// to the variant, all other codes map to [`LoginError::Other`]. Through the standard library's blanket
+/// implementation, `LoginError::try_from(code)` works as well and never fails.
+impl From<u32> for LoginError {
+    fn from(code: u32) -> Self {
+        match code {
+            error_codes::ADDRESS_MISMATCH => LoginError::AddressMismatch,
+            error_codes::SESSION_KEY_MISMATCH => LoginError::SessionKeyMismatch,
+            error_codes::ADDRESS_BLOCKLISTED => LoginError::AddressBlocklisted,
+            code => LoginError::Other { code },
+        }
+    }
+}
+
+impl std::error::Error for LoginError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LoginError::SignatureError(e) => Some(e),
+            LoginError::SiwsMessageError(e) => Some(e),
+            LoginError::DelegationError(e) => Some(e),
+            LoginError::SignatureMapError(e) => Some(e),
+            LoginError::ASN1EncodeErr(e) => Some(e),
+            _ => None,
+        }
+    }
 }
 
 impl From<SolError> for LoginError {
@@ -87,6 +524,12 @@ impl From<DelegationError> for LoginError {
     }
 }
 
+impl From<SignatureMapError> for LoginError {
+    fn from(err: SignatureMapError) -> Self {
+        LoginError::SignatureMapError(err)
+    }
+}
+
 impl From<ASN1EncodeErr> for LoginError {
     fn from(err: ASN1EncodeErr) -> Self {
         LoginError::ASN1EncodeErr(err)
@@ -99,8 +542,55 @@ impl fmt::Display for LoginError {
             LoginError::SignatureError(e) => write!(f, "{}", e),
             LoginError::SiwsMessageError(e) => write!(f, "{}", e),
             LoginError::AddressMismatch => write!(f, "Recovered address does not match"),
+            LoginError::MessageIssuedInFuture {
+                issued_at,
+                current_time,
+            } => write!(
+                f,
+                "Message issued at {} is in the future, current time is {}",
+                issued_at, current_time
+            ),
+            LoginError::MessageNotYetValid {
+                not_before,
+                current_time,
+            } => write!(
+                f,
+                "Message is not valid before {}, current time is {}",
+                not_before, current_time
+            ),
+            LoginError::IssueTimeTooOld {
+                threshold_ns,
+                age_ns,
+            } => write!(
+                f,
+                "Message was issued {} ns ago, the maximum is {} ns",
+                age_ns, threshold_ns
+            ),
+            LoginError::UriMismatch {
+                message_uri,
+                settings_uri,
+            } => write!(
+                f,
+                "Message URI {} does not match settings URI {}",
+                message_uri, settings_uri
+            ),
+            LoginError::SessionKeyMismatch => {
+                write!(f, "Session key does not match the signed message")
+            }
+            LoginError::AddressBlocklisted => write!(f, "Address has been banned"),
+            LoginError::PreCheckFailed(e) => write!(f, "Login pre-check failed: {}", e),
+            LoginError::InvalidSessionExpiresIn {
+                session_expires_in,
+                max_session_expires_in,
+            } => write!(
+                f,
+                "Session expires in {} must be greater than 0 and not exceed {}",
+                session_expires_in, max_session_expires_in
+            ),
             LoginError::DelegationError(e) => write!(f, "{}", e),
+            LoginError::SignatureMapError(e) => write!(f, "{}", e),
             LoginError::ASN1EncodeErr(e) => write!(f, "{}", e),
+            LoginError::Other { code } => write!(f, "Login failed with error code {}", code),
         }
     }
 }
@@ -119,7 +609,14 @@ impl fmt::Display for LoginError {
 ///
 /// # Returns
 /// A `Result` that, on success, contains the [LoginDetails] with session expiration and user canister
-/// public key, or an error of type [`LoginError`] on failure.
+/// public key, or an error of type [`LoginError`] on failure. The `bool` is `true` if the signature map
+/// still held a signature for the user, i.e. the user also logged in within the last minute, e.g. from another
+/// device. Earlier delegations stay valid until they expire, signatures are only kept until the client has
+/// fetched the delegation.
+///
+/// A retried call with the same signature, address, session key and nonce returns the login details of the
+/// first successful call while the signature of the delegation is in the signature map, about a minute, unless
+/// `disable_login_idempotency` is set in the settings.
 pub fn login(
     signature: &SolSignature,
     address: &SolPubkey,
@@ -127,53 +624,960 @@ pub fn login(
     signature_map: &mut SignatureMap,
     canister_id: &Principal,
     nonce: &Nonce,
-) -> Result<LoginDetails, LoginError> {
+) -> Result<(LoginDetails, bool), LoginError> {
+    login_with_options(
+        signature,
+        address,
+        session_key,
+        signature_map,
+        canister_id,
+        nonce,
+        LoginOptions::default(),
+    )
+}
+
+/// Works like [`login`] but runs the `pre_check` function before the signature is verified. This allows
+/// canisters to reject login attempts early, for example based on on-chain data, before paying the cost of
+/// Ed25519 signature verification.
+///
+/// # Parameters
+/// * `pre_check`: Called with the Solana address of the user. Returning an error aborts the login with
+///   [`LoginError::PreCheckFailed`].
+///
+/// See [`login`] for a description of the other parameters.
+pub fn login_with_pre_check<F: Fn(&SolPubkey) -> Result<(), String>>(
+    signature: &SolSignature,
+    address: &SolPubkey,
+    session_key: ByteBuf,
+    signature_map: &mut SignatureMap,
+    canister_id: &Principal,
+    nonce: &Nonce,
+    pre_check: F,
+) -> Result<(LoginDetails, bool), LoginError> {
+    let cache_key = login_cache_key_if_enabled(
+        signature,
+        address,
+        &session_key,
+        nonce,
+        &LoginOptions::default(),
+    );
+    if let Some(cached) = cached_login(cache_key.as_ref()) {
+        pre_check(address).map_err(LoginError::PreCheckFailed)?;
+        return Ok(cached);
+    }
+
+    let message = take_verified_message(signature, address, &session_key, nonce, pre_check)
+        .inspect_err(|e| log_login_failed(address, e))?;
+    let result = create_login_details(
+        &message,
+        address,
+        session_key,
+        signature_map,
+        canister_id,
+        LoginOptions::default(),
+    );
+    cache_login(cache_key, &result);
+    result
+}
+
+/// Options for [`login_with_options`].
+#[derive(Debug, Clone, Default)]
+pub struct LoginOptions {
+    /// Overrides `session_expires_in` from the settings for this login, e.g. to give premium users longer
+    /// sessions. Must not exceed `max_session_expires_in` from the settings.
+    pub session_expires_in: Option<u64>,
+
+    /// Restricts the delegation to these canisters instead of the `targets` from the settings, see
+    /// [`create_delegation_with_targets`]. Ignored if empty. `get_delegation` must create the delegation with
+    /// the same targets, or the delegation hash won't be found in the signature map.
+    pub delegation_targets: Vec<Principal>,
+
+    /// Replaces the salt from the settings when generating the seed, giving the user a different principal,
+    /// e.g. per tenant of a multi-tenant canister. `get_delegation` must generate the seed with the same salt,
+    /// see [`generate_seed_with_salt`]. Must be 1 to 255 bytes long.
+    pub custom_seed_salt: Option<Vec<u8>>,
+}
+
+/// Works like [`login`] but applies the [`LoginOptions`], allowing the calling canister to implement its own
+/// session policy per user.
+///
+/// # Parameters
+/// * `options`: The options for this login. Returns [`LoginError::InvalidSessionExpiresIn`] if the session
+///   TTL override is 0 or exceeds `max_session_expires_in` from the settings, and
+///   [`DelegationError::InvalidTargets`] if the delegation targets contain duplicates or more than 1000
+///   entries. A custom seed salt must be 1 to 255 bytes long, see [`DelegationError::InvalidSeedSalt`].
+///
+/// See [`login`] for a description of the other parameters.
+pub fn login_with_options(
+    signature: &SolSignature,
+    address: &SolPubkey,
+    session_key: ByteBuf,
+    signature_map: &mut SignatureMap,
+    canister_id: &Principal,
+    nonce: &Nonce,
+    options: LoginOptions,
+) -> Result<(LoginDetails, bool), LoginError> {
+    if let Some(session_expires_in) = options.session_expires_in {
+        let max_session_expires_in = with_settings!(|settings: &Settings| settings
+            .max_session_expires_in
+            .unwrap_or(settings.session_expires_in));
+        if session_expires_in == 0 || session_expires_in > max_session_expires_in {
+            return Err(LoginError::InvalidSessionExpiresIn {
+                session_expires_in,
+                max_session_expires_in,
+            });
+        }
+    }
+    if let Some(salt) = &options.custom_seed_salt {
+        if salt.is_empty() || salt.len() > MAX_SEED_SALT_BYTES {
+            return Err(DelegationError::InvalidSeedSalt(format!(
+                "Salt is {} bytes, it must be between 1 and {}",
+                salt.len(),
+                MAX_SEED_SALT_BYTES
+            ))
+            .into());
+        }
+    }
+
+    let cache_key = login_cache_key_if_enabled(signature, address, &session_key, nonce, &options);
+    if let Some(cached) = cached_login(cache_key.as_ref()) {
+        return Ok(cached);
+    }
+
+    let message = take_verified_message(signature, address, &session_key, nonce, |_| Ok(()))
+        .inspect_err(|e| log_login_failed(address, e))?;
+    let result = create_login_details(
+        &message,
+        address,
+        session_key,
+        signature_map,
+        canister_id,
+        options,
+    );
+    cache_login(cache_key, &result);
+    result
+}
+
+/// Works like [`login`] but awaits the `pre_check` future after the signature has been verified and before
+/// the delegation is created. This allows canisters to make inter-canister calls, for example to check token
+/// ownership, without spending cycles on login attempts with invalid signatures.
+///
+/// The SIWS message is consumed before the pre-check runs, a failed pre-check requires a new
+/// `prepare_login` call. The caller must make sure that `signature_map` is not borrowed from a `RefCell`
+/// across the await point, as other calls may be processed while the pre-check is pending.
+///
+/// # Parameters
+/// * `pre_check`: Called with the Solana address of the user. Returning an error aborts the login with
+///   [`LoginError::PreCheckFailed`].
+///
+/// See [`login`] for a description of the other parameters.
+pub async fn login_with_async_pre_check<F, Fut>(
+    signature: &SolSignature,
+    address: &SolPubkey,
+    session_key: ByteBuf,
+    signature_map: &mut SignatureMap,
+    canister_id: &Principal,
+    nonce: &Nonce,
+    pre_check: F,
+) -> Result<(LoginDetails, bool), LoginError>
+where
+    F: FnOnce(SolPubkey) -> Fut,
+    Fut: Future<Output = Result<(), String>>,
+{
+    let cache_key = login_cache_key_if_enabled(
+        signature,
+        address,
+        &session_key,
+        nonce,
+        &LoginOptions::default(),
+    );
+    if let Some(cached) = cached_login(cache_key.as_ref()) {
+        pre_check(*address)
+            .await
+            .map_err(LoginError::PreCheckFailed)?;
+        return Ok(cached);
+    }
+
+    let message = take_verified_message(signature, address, &session_key, nonce, |_| Ok(()))
+        .inspect_err(|e| log_login_failed(address, e))?;
+    pre_check(*address)
+        .await
+        .map_err(LoginError::PreCheckFailed)?;
+    let result = create_login_details(
+        &message,
+        address,
+        session_key,
+        signature_map,
+        canister_id,
+        LoginOptions::default(),
+    );
+    cache_login(cache_key, &result);
+    result
+}
+
+// Returns the key of the login in the login cache, or `None` if login idempotency is disabled. Blocked
+// addresses get no key, so that a retried login fails with `AddressBlocklisted` instead of being served from
+// the cache.
+fn login_cache_key_if_enabled(
+    signature: &SolSignature,
+    address: &SolPubkey,
+    session_key: &[u8],
+    nonce: &Nonce,
+    options: &LoginOptions,
+) -> Option<Hash> {
+    let disabled = with_settings!(|settings: &Settings| settings.disable_login_idempotency);
+    (!disabled && !access_control::is_blocked(address))
+        .then(|| login_cache_key(signature, address, session_key, nonce, options))
+}
+
+// Returns the result of an earlier successful call if the login is a retry. The caller runs its pre-check
+// before returning a cached result, as it would for a new login.
+fn cached_login(cache_key: Option<&Hash>) -> Option<(LoginDetails, bool)> {
+    let cache_key = cache_key?;
+    LOGIN_CACHE.with_borrow(|login_cache| login_cache.get(cache_key, get_current_time()))
+}
+
+// Stores the result of a successful login so that retries return it. The result is kept no longer than the
+// signature of the delegation, after that `get_delegation` would fail for the cached login details.
+fn cache_login(cache_key: Option<Hash>, result: &Result<(LoginDetails, bool), LoginError>) {
+    if let (Some(cache_key), Ok((login_details, recent_login))) = (cache_key, result) {
+        let expires_in = with_settings!(|settings: &Settings| settings.sign_in_expires_in)
+            .min(DELEGATION_SIGNATURE_EXPIRES_AT);
+        let expires_at = get_current_time().saturating_add(expires_in);
+        LOGIN_CACHE.with_borrow_mut(|login_cache| {
+            login_cache.insert(cache_key, login_details.clone(), *recent_login, expires_at)
+        });
+    }
+}
+
+/// Fetches the SIWS message for the address and nonce, validates it and verifies the signature. The message is
+/// removed from the state whether or not the verification succeeds.
+fn take_verified_message<F: FnOnce(&SolPubkey) -> Result<(), String>>(
+    signature: &SolSignature,
+    address: &SolPubkey,
+    session_key: &[u8],
+    nonce: &Nonce,
+    pre_check: F,
+) -> Result<SiwsMessage, LoginError> {
+    if access_control::is_blocked(address) {
+        return Err(LoginError::AddressBlocklisted);
+    }
+
     // Remove expired SIWS messages from the state before proceeding. The init settings determines
     // the time to live for SIWS messages.
-    SIWS_MESSAGES.with_borrow_mut(|siws_messages| {
+    let message = SIWS_MESSAGES.with_borrow_mut(|siws_messages| {
         // Prune any expired SIWS messages from the state.
         siws_messages.prune_expired();
+        prune_prepare_login_calls();
+        prune_login_cache();
 
         // Get the previously created SIWS message for current address. If it has expired or does not
         // exist, return an error.
         let message = siws_messages.get(address, nonce)?;
-        let message_string: String = message.clone().into();
 
-        // Verify the supplied signature and public key against the stored SIWS message.
-        let verification_result = verify_sol_signature(&message_string, signature, address);
+        // Ensure the SIWS message was created for the address that is trying to log in.
+        if !message.is_for_address(address) {
+            siws_messages.remove(address, nonce);
+            return Err(LoginError::AddressMismatch);
+        }
+
+        // Reject messages issued in the future. prepare_login always sets issued_at to the current time, this
+        // guards against messages that were tampered with.
+        let current_time = get_current_time();
+        if message.issued_at > current_time {
+            siws_messages.remove(address, nonce);
+            return Err(LoginError::MessageIssuedInFuture {
+                issued_at: message.issued_at,
+                current_time,
+            });
+        }
+
+        // Reject messages issued too long ago. Messages expire sign_in_expires_in after they are issued, this is
+        // a defence in depth check that doesn't depend on the stored expiration time.
+        let threshold_ns = with_settings!(|settings: &Settings| {
+            settings
+                .max_message_age_ns
+                .unwrap_or(settings.sign_in_expires_in)
+        });
+        if !message.is_fresh(threshold_ns) {
+            siws_messages.remove(address, nonce);
+            return Err(LoginError::IssueTimeTooOld {
+                threshold_ns,
+                age_ns: current_time - message.issued_at,
+            });
+        }
+
+        // Reject messages used before their not_before time.
+        if let Some(not_before) = message.not_before {
+            if message.is_before_not_before(current_time) {
+                siws_messages.remove(address, nonce);
+                return Err(LoginError::MessageNotYetValid {
+                    not_before,
+                    current_time,
+                });
+            }
+        }
+
+        // Guard against settings having changed between the prepare_login and login calls.
+        let settings_uri = with_settings!(|settings: &Settings| settings.uri.clone());
+        if message.uri != settings_uri {
+            siws_messages.remove(address, nonce);
+            return Err(LoginError::UriMismatch {
+                message_uri: message.uri,
+                settings_uri,
+            });
+        }
+
+        // If the message was bound to a session key, the presented session key must match.
+        if let Some(resource) = message
+            .resources
+            .iter()
+            .find(|r| r.starts_with(SESSION_KEY_RESOURCE_PREFIX))
+        {
+            if *resource != session_key_resource(session_key) {
+                siws_messages.remove(address, nonce);
+                return Err(LoginError::SessionKeyMismatch);
+            }
+        }
 
         // Ensure the SIWS message is removed from the state both on success and on failure.
         siws_messages.remove(address, nonce);
 
-        // Handle the result of the signature verification.
-        verification_result?;
+        Ok(message)
+    })?;
 
-        // The delegation is valid for the duration of the session as defined in the settings.
-        let expiration = with_settings!(|settings: &Settings| {
-            message
-                .issued_at
-                .saturating_add(settings.session_expires_in)
-        });
+    // Run the pre-check before the comparatively expensive signature verification. The message map is no
+    // longer borrowed, so the hook can call functions such as pending_login_count.
+    pre_check(address).map_err(LoginError::PreCheckFailed)?;
+
+    let mut message_string: String = message.clone().into();
+    if let Some(prefix) = with_settings!(|settings: &Settings| settings.message_prefix.clone()) {
+        message_string.insert_str(0, &prefix);
+    }
+
+    // Verify the supplied signature and public key against the stored SIWS message.
+    verify_sol_signature(&message_string, signature, address)?;
+
+    Ok(message)
+}
+
+// Logs a login that failed before the delegation was created, see `LoginEvent::LoginFailed`.
+fn log_login_failed(_address: &SolPubkey, _error: &LoginError) {
+    #[cfg(feature = "debug-logging")]
+    log_event(LoginEvent::LoginFailed {
+        address: _address.to_string(),
+        error: _error.to_string(),
+    });
+}
+
+/// Creates the delegation for a verified SIWS message, adds it to the signature map and returns the login details.
+/// The `options` override the session TTL, the delegation targets and the seed salt from the settings.
+fn create_login_details(
+    message: &SiwsMessage,
+    address: &SolPubkey,
+    session_key: ByteBuf,
+    signature_map: &mut SignatureMap,
+    canister_id: &Principal,
+    options: LoginOptions,
+) -> Result<(LoginDetails, bool), LoginError> {
+    // The delegation is valid for the duration of the session as defined in the settings, unless overridden.
+    let expiration = match options.session_expires_in {
+        Some(session_expires_in) => message.issued_at.saturating_add(session_expires_in),
+        None => with_settings!(|settings: &Settings| compute_delegation_expiry(message, settings)),
+    };
+
+    // The seed is what uniquely identifies the delegation. It is derived from the salt, the
+    // Solana address and the SIWS message URI.
+    let seed = match options.custom_seed_salt {
+        Some(salt) => generate_seed_with_salt(address, &salt),
+        None => generate_seed(address),
+    };
+
+    // Before adding the signature to the signature map, prune any expired signatures.
+    signature_map.prune_expired(
+        get_current_time(),
+        with_settings!(|settings: &Settings| settings.max_sigs_to_prune),
+    );
 
-        // The seed is what uniquely identifies the delegation. It is derived from the salt, the
-        // Solana address and the SIWS message URI.
-        let seed = generate_seed(address);
+    // Check whether the signature map still holds a signature of a recent login of the user.
+    let seed_hash = hash::hash_bytes(seed);
+    let recent_login = signature_map.contains_seed(seed_hash);
 
-        // Before adding the signature to the signature map, prune any expired signatures.
-        signature_map.prune_expired(get_current_time(), MAX_SIGS_TO_PRUNE);
+    // Issue a stateless session token if enabled in the settings.
+    let session_token = with_settings!(|settings: &Settings| settings.issue_session_tokens)
+        .then(|| session_token(&seed, expiration, &session_key));
 
-        // Create the delegation and add its hash to the signature map. The seed is used as the map key.
-        let delegation = create_delegation(session_key, expiration)?;
-        let delegation_hash = create_delegation_hash(&delegation);
-        signature_map.put(hash::hash_bytes(seed), delegation_hash);
+    // Create the delegation and add its hash to the signature map. The seed is used as the map key.
+    let delegation = if options.delegation_targets.is_empty() {
+        create_delegation(session_key, expiration)?
+    } else {
+        create_delegation_with_targets(session_key, expiration, options.delegation_targets)?
+    };
+    let delegation_hash = create_delegation_hash(&delegation);
+    signature_map.try_put(seed_hash, delegation_hash)?;
 
-        // Create the user canister public key from the seed. From this key, the client can derive the
-        // user principal.
-        let user_canister_pubkey = create_user_canister_pubkey(canister_id, seed.to_vec())?;
+    // Create the user canister public key from the seed. From this key, the client can derive the
+    // user principal.
+    let user_canister_pubkey = create_user_canister_pubkey(canister_id, seed.to_vec())?;
+
+    #[cfg(feature = "debug-logging")]
+    log_event(LoginEvent::LoginSucceeded {
+        address: address.to_string(),
+        expiration,
+        recent_login,
+    });
 
-        Ok(LoginDetails {
+    Ok((
+        LoginDetails {
             expiration,
             user_canister_pubkey: ByteBuf::from(user_canister_pubkey),
-        })
-    })
+            session_token,
+        },
+        recent_login,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::SettingsBuilder;
+    use solana_sdk::signature::{Keypair, Signer};
+
+    const SESSION_KEY: &[u8] = &[
+        48, 42, 48, 5, 6, 3, 43, 101, 112, 3, 33, 0, 220, 227, 2, 129, 72, 36, 43, 220, 96, 102,
+        225, 92, 98, 163, 114, 182, 117, 181, 51, 15, 219, 197, 104, 55, 123, 245, 74, 181, 35,
+        181, 171, 196,
+    ]; // DER encoded session key
+
+    fn prepare_and_sign(keypair: &Keypair) -> (SolSignature, Nonce) {
+        let address = SolPubkey::from(keypair.pubkey().to_bytes());
+        let message = prepare_login(&address).unwrap();
+        let signature = keypair.sign_message(message.to_string().as_bytes());
+        (
+            SolSignature::try_from(signature.as_ref().to_vec()).unwrap(),
+            message.nonce,
+        )
+    }
+
+    #[test]
+    fn test_login_with_options() {
+        let settings = SettingsBuilder::new("example.com", "http://example.com", "some_salt")
+            .session_expires_in(60_000_000_000)
+            .max_session_expires_in(120_000_000_000)
+            .build()
+            .unwrap();
+        crate::SETTINGS.set(Some(std::rc::Rc::new(settings)));
+        let keypair = Keypair::new();
+        let address = SolPubkey::from(keypair.pubkey().to_bytes());
+        let canister_id = Principal::from_text("aaaaa-aa").unwrap();
+        let mut signature_map = SignatureMap::default();
+
+        let (signature, nonce) = prepare_and_sign(&keypair);
+        let result = login_with_options(
+            &signature,
+            &address,
+            ByteBuf::from(SESSION_KEY),
+            &mut signature_map,
+            &canister_id,
+            &nonce,
+            LoginOptions {
+                session_expires_in: Some(120_000_000_001),
+                ..Default::default()
+            },
+        );
+        assert!(matches!(
+            result,
+            Err(LoginError::InvalidSessionExpiresIn {
+                max_session_expires_in: 120_000_000_000,
+                ..
+            })
+        ));
+
+        // The message was not consumed by the rejected options.
+        let (login_details, _) = login_with_options(
+            &signature,
+            &address,
+            ByteBuf::from(SESSION_KEY),
+            &mut signature_map,
+            &canister_id,
+            &nonce,
+            LoginOptions {
+                session_expires_in: Some(120_000_000_000),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert!(login_details.expiration > get_current_time() + 60_000_000_000);
+    }
+
+    #[test]
+    fn test_login_with_custom_seed_salt_and_targets() {
+        let settings = SettingsBuilder::new("example.com", "http://example.com", "some_salt")
+            .build()
+            .unwrap();
+        crate::SETTINGS.set(Some(std::rc::Rc::new(settings)));
+        let keypair = Keypair::new();
+        let address = SolPubkey::from(keypair.pubkey().to_bytes());
+        let canister_id = Principal::from_text("aaaaa-aa").unwrap();
+        let target = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap();
+        let mut signature_map = SignatureMap::default();
+
+        let (signature, nonce) = prepare_and_sign(&keypair);
+        let result = login_with_options(
+            &signature,
+            &address,
+            ByteBuf::from(SESSION_KEY),
+            &mut signature_map,
+            &canister_id,
+            &nonce,
+            LoginOptions {
+                custom_seed_salt: Some(vec![1; 256]),
+                ..Default::default()
+            },
+        );
+        assert!(matches!(
+            result,
+            Err(LoginError::DelegationError(
+                DelegationError::InvalidSeedSalt(_)
+            ))
+        ));
+
+        let (login_details, _) = login_with_options(
+            &signature,
+            &address,
+            ByteBuf::from(SESSION_KEY),
+            &mut signature_map,
+            &canister_id,
+            &nonce,
+            LoginOptions {
+                delegation_targets: vec![target],
+                custom_seed_salt: Some(b"tenant_salt".to_vec()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let seed = generate_seed_with_salt(&address, b"tenant_salt");
+        assert_eq!(
+            login_details.user_canister_pubkey,
+            ByteBuf::from(create_user_canister_pubkey(&canister_id, seed.to_vec()).unwrap())
+        );
+        let delegation = create_delegation_with_targets(
+            ByteBuf::from(SESSION_KEY),
+            login_details.expiration,
+            vec![target],
+        )
+        .unwrap();
+        assert!(signature_map.contains(hash::hash_bytes(seed), create_delegation_hash(&delegation)));
+        assert!(!signature_map.contains_seed(hash::hash_bytes(generate_seed(&address))));
+    }
+
+    #[test]
+    fn test_login_address_mismatch() {
+        let settings = SettingsBuilder::new("example.com", "http://example.com", "some_salt")
+            .build()
+            .unwrap();
+        crate::SETTINGS.set(Some(std::rc::Rc::new(settings)));
+        let keypair = Keypair::new();
+        let address = SolPubkey::from(keypair.pubkey().to_bytes());
+        let other_address = SolPubkey::from(Keypair::new().pubkey().to_bytes());
+        let canister_id = Principal::from_text("aaaaa-aa").unwrap();
+        let mut signature_map = SignatureMap::default();
+
+        // Store a message created for another address under the key of the address that logs in.
+        let message = SiwsMessage::new(&other_address, "1234567890abcdef");
+        let nonce = message.nonce.clone();
+        let signature = keypair.sign_message(message.to_string().as_bytes());
+        let signature = SolSignature::try_from(signature.as_ref().to_vec()).unwrap();
+        SIWS_MESSAGES.with_borrow_mut(|siws_messages| {
+            siws_messages
+                .insert(&address, message.clone(), &message.nonce, 1, None)
+                .unwrap()
+        });
+
+        let result = login(
+            &signature,
+            &address,
+            ByteBuf::from(SESSION_KEY),
+            &mut signature_map,
+            &canister_id,
+            &nonce,
+        );
+        assert!(matches!(result, Err(LoginError::AddressMismatch)));
+        assert_eq!(pending_login_count(), 0);
+    }
+
+    #[test]
+    fn test_login_with_pre_check() {
+        let settings = SettingsBuilder::new("example.com", "http://example.com", "some_salt")
+            .build()
+            .unwrap();
+        crate::SETTINGS.set(Some(std::rc::Rc::new(settings)));
+        let keypair = Keypair::new();
+        let address = SolPubkey::from(keypair.pubkey().to_bytes());
+        let canister_id = Principal::from_text("aaaaa-aa").unwrap();
+        let mut signature_map = SignatureMap::default();
+
+        // The hook can read the library state, the message has already been taken out of the map.
+        let (signature, nonce) = prepare_and_sign(&keypair);
+        let result = login_with_pre_check(
+            &signature,
+            &address,
+            ByteBuf::from(SESSION_KEY),
+            &mut signature_map,
+            &canister_id,
+            &nonce,
+            |_| Err(format!("{} pending logins", pending_login_count())),
+        );
+        assert!(matches!(result, Err(LoginError::PreCheckFailed(e)) if e == "0 pending logins"));
+        assert_eq!(pending_login_count(), 0);
+
+        let (signature, nonce) = prepare_and_sign(&keypair);
+        let result = login_with_pre_check(
+            &signature,
+            &address,
+            ByteBuf::from(SESSION_KEY),
+            &mut signature_map,
+            &canister_id,
+            &nonce,
+            |pubkey| {
+                assert_eq!(*pubkey, address);
+                Ok(())
+            },
+        );
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_login_with_async_pre_check() {
+        let settings = SettingsBuilder::new("example.com", "http://example.com", "some_salt")
+            .build()
+            .unwrap();
+        crate::SETTINGS.set(Some(std::rc::Rc::new(settings)));
+        let keypair = Keypair::new();
+        let address = SolPubkey::from(keypair.pubkey().to_bytes());
+        let canister_id = Principal::from_text("aaaaa-aa").unwrap();
+        let mut signature_map = SignatureMap::default();
+
+        let (signature, nonce) = prepare_and_sign(&keypair);
+        let result = login_with_async_pre_check(
+            &signature,
+            &address,
+            ByteBuf::from(SESSION_KEY),
+            &mut signature_map,
+            &canister_id,
+            &nonce,
+            |_| async { Err("Token not owned".to_string()) },
+        )
+        .await;
+        assert!(matches!(result, Err(LoginError::PreCheckFailed(e)) if e == "Token not owned"));
+        assert!(!signature_map.contains_seed(hash::hash_bytes(generate_seed(&address))));
+
+        let (signature, nonce) = prepare_and_sign(&keypair);
+        let (login_details, recent_login) = login_with_async_pre_check(
+            &signature,
+            &address,
+            ByteBuf::from(SESSION_KEY),
+            &mut signature_map,
+            &canister_id,
+            &nonce,
+            |pubkey| async move {
+                assert_eq!(pubkey, address);
+                Ok(())
+            },
+        )
+        .await
+        .unwrap();
+        assert!(!recent_login);
+        assert!(login_details.expiration > get_current_time());
+    }
+
+    #[test]
+    fn test_login_idempotency() {
+        let settings = SettingsBuilder::new("example.com", "http://example.com", "some_salt")
+            .build()
+            .unwrap();
+        crate::SETTINGS.set(Some(std::rc::Rc::new(settings)));
+        let keypair = Keypair::new();
+        let address = SolPubkey::from(keypair.pubkey().to_bytes());
+        let canister_id = Principal::from_text("aaaaa-aa").unwrap();
+        let mut signature_map = SignatureMap::default();
+
+        let (signature, nonce) = prepare_and_sign(&keypair);
+        let login_once = |signature_map: &mut SignatureMap| {
+            login(
+                &signature,
+                &address,
+                ByteBuf::from(SESSION_KEY),
+                signature_map,
+                &canister_id,
+                &nonce,
+            )
+        };
+        let (first, recent_login) = login_once(&mut signature_map).unwrap();
+        assert!(!recent_login);
+        let (retried, recent_login) = login_once(&mut signature_map).unwrap();
+        assert_eq!(retried.expiration, first.expiration);
+        assert_eq!(retried.user_canister_pubkey, first.user_canister_pubkey);
+        assert!(!recent_login);
+
+        // The pre-check also runs for retries.
+        let result = login_with_pre_check(
+            &signature,
+            &address,
+            ByteBuf::from(SESSION_KEY),
+            &mut signature_map,
+            &canister_id,
+            &nonce,
+            |_| Err("Token not owned".to_string()),
+        );
+        assert!(matches!(result, Err(LoginError::PreCheckFailed(_))));
+
+        // Without idempotency the retry fails, as the SIWS message has been consumed.
+        let settings = SettingsBuilder::new("example.com", "http://example.com", "some_salt")
+            .disable_login_idempotency(true)
+            .build()
+            .unwrap();
+        crate::SETTINGS.set(Some(std::rc::Rc::new(settings)));
+        let (signature, nonce) = prepare_and_sign(&keypair);
+        let login_once = |signature_map: &mut SignatureMap| {
+            login(
+                &signature,
+                &address,
+                ByteBuf::from(SESSION_KEY),
+                signature_map,
+                &canister_id,
+                &nonce,
+            )
+        };
+        assert!(login_once(&mut signature_map).is_ok());
+        assert!(matches!(
+            login_once(&mut signature_map),
+            Err(LoginError::SiwsMessageError(_))
+        ));
+    }
+
+    #[test]
+    fn test_has_recent_login() {
+        let settings = SettingsBuilder::new("example.com", "http://example.com", "some_salt")
+            .build()
+            .unwrap();
+        crate::SETTINGS.set(Some(std::rc::Rc::new(settings)));
+        let keypair = Keypair::new();
+        let address = SolPubkey::from(keypair.pubkey().to_bytes());
+        let canister_id = Principal::from_text("aaaaa-aa").unwrap();
+        let mut signature_map = SignatureMap::default();
+        assert!(!has_recent_login(&address, &signature_map));
+
+        let (signature, nonce) = prepare_and_sign(&keypair);
+        login(
+            &signature,
+            &address,
+            ByteBuf::from(SESSION_KEY),
+            &mut signature_map,
+            &canister_id,
+            &nonce,
+        )
+        .unwrap();
+        assert!(has_recent_login(&address, &signature_map));
+        assert_eq!(recent_login_count(&address, &signature_map), 1);
+
+        let other = SolPubkey::from(Keypair::new().pubkey().to_bytes());
+        assert!(!has_recent_login(&other, &signature_map));
+        assert_eq!(recent_login_count(&other, &signature_map), 0);
+    }
+
+    #[test]
+    fn test_login_with_unsanitized_request_id() {
+        let settings = SettingsBuilder::new("example.com", "http://example.com", "some_salt")
+            .build()
+            .unwrap();
+        crate::SETTINGS.set(Some(std::rc::Rc::new(settings)));
+        let keypair = Keypair::new();
+        let address = SolPubkey::from(keypair.pubkey().to_bytes());
+        let canister_id = Principal::from_text("aaaaa-aa").unwrap();
+        let mut signature_map = SignatureMap::default();
+
+        let message = prepare_login_with_request_id(&address, "  req-1  ".to_string()).unwrap();
+        assert_eq!(message.request_id.as_deref(), Some("req-1"));
+        let signature = keypair.sign_message(message.to_string().as_bytes());
+        let signature = SolSignature::try_from(signature.as_ref().to_vec()).unwrap();
+        assert!(login(
+            &signature,
+            &address,
+            ByteBuf::from(SESSION_KEY),
+            &mut signature_map,
+            &canister_id,
+            &message.nonce,
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn test_raw_and_der_session_keys_match() {
+        let raw_session_key = &SESSION_KEY[12..];
+        assert_eq!(
+            session_key_resource(raw_session_key),
+            session_key_resource(SESSION_KEY)
+        );
+        assert_eq!(
+            session_token(&[1; 32], 100, raw_session_key),
+            session_token(&[1; 32], 100, SESSION_KEY)
+        );
+    }
+
+    #[test]
+    fn test_prepare_login_weak_address() {
+        let settings = SettingsBuilder::new("example.com", "http://example.com", "some_salt")
+            .build()
+            .unwrap();
+        crate::SETTINGS.set(Some(std::rc::Rc::new(settings)));
+        let mut identity = [0u8; 32];
+        identity[0] = 1;
+        assert!(matches!(
+            prepare_login(&SolPubkey::from(identity)),
+            Err(PrepareLoginError::WeakAddress)
+        ));
+    }
+
+    #[test]
+    fn test_prepare_login_rate_limit() {
+        let settings = SettingsBuilder::new("example.com", "http://example.com", "some_salt")
+            .prepare_login_rate_limit(1, 60_000_000_000)
+            .build()
+            .unwrap();
+        crate::SETTINGS.set(Some(std::rc::Rc::new(settings)));
+        let address = SolPubkey::from(Keypair::new().pubkey().to_bytes());
+
+        assert!(prepare_login(&address).is_ok());
+        assert!(matches!(
+            prepare_login(&address),
+            Err(PrepareLoginError::RateLimitExceeded { retry_after_ns })
+                if retry_after_ns > 0 && retry_after_ns <= 60_000_000_000
+        ));
+
+        // Calls that fail to build a message do not count against the limit.
+        let address = SolPubkey::from(Keypair::new().pubkey().to_bytes());
+        assert!(matches!(
+            prepare_login_with_request_id(&address, "x".repeat(5000)),
+            Err(PrepareLoginError::InvalidRequestId(_))
+        ));
+        assert!(prepare_login(&address).is_ok());
+    }
+
+    #[test]
+    fn test_message_too_old() {
+        let settings = SettingsBuilder::new("example.com", "http://example.com", "some_salt")
+            .max_message_age_ns(1)
+            .build()
+            .unwrap();
+        crate::SETTINGS.set(Some(std::rc::Rc::new(settings)));
+        let keypair = Keypair::new();
+        let address = SolPubkey::from(keypair.pubkey().to_bytes());
+        let canister_id = Principal::from_text("aaaaa-aa").unwrap();
+        let mut signature_map = SignatureMap::default();
+
+        let (signature, nonce) = prepare_and_sign(&keypair);
+        std::thread::sleep(Duration::from_millis(1));
+        let result = login(
+            &signature,
+            &address,
+            ByteBuf::from(SESSION_KEY),
+            &mut signature_map,
+            &canister_id,
+            &nonce,
+        );
+        assert!(matches!(
+            result,
+            Err(LoginError::IssueTimeTooOld {
+                threshold_ns: 1,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_message_prefix() {
+        for prefix in [
+            None,
+            Some("Solana Signed Message:\n"),
+            Some("Custom prefix: "),
+        ] {
+            let mut builder =
+                SettingsBuilder::new("example.com", "http://example.com", "some_salt");
+            if let Some(prefix) = prefix {
+                builder = builder.message_prefix(prefix);
+            }
+            crate::SETTINGS.set(Some(std::rc::Rc::new(builder.build().unwrap())));
+            let keypair = Keypair::new();
+            let address = SolPubkey::from(keypair.pubkey().to_bytes());
+            let canister_id = Principal::from_text("aaaaa-aa").unwrap();
+            let mut signature_map = SignatureMap::default();
+
+            let login_with_prefix = |signed_prefix: &str, signature_map: &mut SignatureMap| {
+                let message = prepare_login(&address).unwrap();
+                let signed = format!("{}{}", signed_prefix, message);
+                let signature = keypair.sign_message(signed.as_bytes());
+                login(
+                    &SolSignature::try_from(signature.as_ref().to_vec()).unwrap(),
+                    &address,
+                    ByteBuf::from(SESSION_KEY),
+                    signature_map,
+                    &canister_id,
+                    &message.nonce,
+                )
+            };
+
+            assert!(login_with_prefix(prefix.unwrap_or(""), &mut signature_map).is_ok());
+            if prefix.is_some() {
+                assert!(matches!(
+                    login_with_prefix("", &mut signature_map),
+                    Err(LoginError::SignatureError(_))
+                ));
+            }
+        }
+    }
+
+    #[test]
+    fn test_error_source() {
+        use std::error::Error;
+
+        let error = LoginError::from(DelegationError::SignatureNotFound);
+        assert_eq!(error.source().unwrap().to_string(), "Signature not found");
+        assert!(LoginError::AddressMismatch.source().is_none());
+        let boxed: Box<dyn Error> = Box::new(error);
+        assert_eq!(boxed.to_string(), "Signature not found");
+    }
+
+    #[test]
+    fn test_error_codes() {
+        assert_eq!(LoginError::AddressMismatch.code(), 1003);
+        assert_eq!(
+            LoginError::PreCheckFailed("denied".to_string()).code(),
+            1012
+        );
+        assert!(matches!(
+            LoginError::from(1003),
+            LoginError::AddressMismatch
+        ));
+        assert!(matches!(
+            LoginError::try_from(1012),
+            Ok(LoginError::Other { code: 1012 })
+        ));
+        assert_eq!(LoginError::from(9999).code(), 9999);
+    }
+
+    #[test]
+    fn test_session_expiry() {
+        let login_details = LoginDetails {
+            expiration: 1_000,
+            user_canister_pubkey: ByteBuf::new(),
+            session_token: None,
+        };
+        assert_eq!(login_details.session_expires_in_ns(400), Some(600));
+        assert!(!login_details.is_expired(999));
+        assert_eq!(login_details.session_expires_in_ns(1_000), None);
+        assert!(login_details.is_expired(1_000));
+        assert!(login_details.is_expired(2_000));
+    }
 }
@@ -0,0 +1,156 @@
+use crate::{
+    hash,
+    login::{LoginDetails, LoginOptions},
+    solana::{SolPubkey, SolSignature},
+};
+use ic_certified_map::Hash;
+use std::collections::HashMap;
+
+struct CachedLogin {
+    login_details: LoginDetails,
+    recent_login: bool,
+    expires_at: u64,
+}
+
+/// Remembers the result of recent logins, so that a login call that is retried with the same signature and
+/// session key, e.g. after a network error, returns the same login details instead of failing because the SIWS
+/// message has already been used. Can be disabled with `Settings::disable_login_idempotency`.
+#[derive(Default)]
+pub struct LoginCache {
+    logins: HashMap<Hash, CachedLogin>,
+}
+
+impl LoginCache {
+    pub fn new() -> LoginCache {
+        LoginCache::default()
+    }
+
+    /// Returns the login details stored for `key` and whether the user had logged in recently, see
+    /// [`login`](crate::login::login), unless the entry has expired.
+    pub fn get(&self, key: &Hash, current_time: u64) -> Option<(LoginDetails, bool)> {
+        self.logins
+            .get(key)
+            .filter(|cached| cached.expires_at > current_time)
+            .map(|cached| (cached.login_details.clone(), cached.recent_login))
+    }
+
+    /// Stores the result of a login until `expires_at`.
+    pub fn insert(
+        &mut self,
+        key: Hash,
+        login_details: LoginDetails,
+        recent_login: bool,
+        expires_at: u64,
+    ) {
+        self.logins.insert(
+            key,
+            CachedLogin {
+                login_details,
+                recent_login,
+                expires_at,
+            },
+        );
+    }
+
+    /// Removes expired entries.
+    pub fn prune(&mut self, current_time: u64) {
+        self.logins
+            .retain(|_, cached| cached.expires_at > current_time);
+    }
+
+    pub fn len(&self) -> usize {
+        self.logins.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.logins.is_empty()
+    }
+}
+
+/// The key of a login in the [`LoginCache`], the hash of everything a client sends to log in. The login
+/// options are included, as they change the login details, e.g. the principal with a custom seed salt.
+pub(crate) fn login_cache_key(
+    signature: &SolSignature,
+    address: &SolPubkey,
+    session_key: &[u8],
+    nonce: &str,
+    options: &LoginOptions,
+) -> Hash {
+    let mut bytes: Vec<u8> = vec![];
+    bytes.extend(signature.0);
+    bytes.extend(address.as_bytes());
+    bytes.extend((nonce.len() as u32).to_be_bytes());
+    bytes.extend(nonce.as_bytes());
+
+    bytes.extend(options.session_expires_in.unwrap_or(0).to_be_bytes());
+    bytes.extend((options.delegation_targets.len() as u32).to_be_bytes());
+    for target in &options.delegation_targets {
+        bytes.extend((target.as_slice().len() as u32).to_be_bytes());
+        bytes.extend(target.as_slice());
+    }
+    match &options.custom_seed_salt {
+        Some(salt) => {
+            bytes.push(1);
+            bytes.extend((salt.len() as u32).to_be_bytes());
+            bytes.extend(salt);
+        }
+        None => bytes.push(0),
+    }
+
+    bytes.extend(session_key);
+    hash::hash_bytes(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_bytes::ByteBuf;
+
+    #[test]
+    fn test_get_and_prune() {
+        let login_details = LoginDetails {
+            expiration: 1_000,
+            user_canister_pubkey: ByteBuf::from(vec![1, 2, 3]),
+            session_token: None,
+        };
+        let key = hash::hash_bytes(b"login");
+        let mut cache = LoginCache::new();
+        cache.insert(key, login_details, true, 100);
+
+        let (cached, recent_login) = cache.get(&key, 50).unwrap();
+        assert_eq!(cached.user_canister_pubkey, ByteBuf::from(vec![1, 2, 3]));
+        assert!(recent_login);
+        assert!(cache.get(&key, 100).is_none());
+        assert!(cache.get(&hash::hash_bytes(b"other"), 50).is_none());
+
+        cache.prune(50);
+        assert_eq!(cache.len(), 1);
+        cache.prune(100);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_key_includes_options() {
+        let signature = SolSignature([1; 64]);
+        let address = SolPubkey::from([2; 32]);
+        let key = |options: &LoginOptions| {
+            login_cache_key(&signature, &address, b"key", "nonce", options)
+        };
+        let default_key = key(&LoginOptions::default());
+        assert_eq!(default_key, key(&LoginOptions::default()));
+        assert_ne!(
+            default_key,
+            key(&LoginOptions {
+                custom_seed_salt: Some(b"tenant_salt".to_vec()),
+                ..Default::default()
+            })
+        );
+        assert_ne!(
+            default_key,
+            key(&LoginOptions {
+                session_expires_in: Some(1),
+                ..Default::default()
+            })
+        );
+    }
+}
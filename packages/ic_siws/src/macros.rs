@@ -17,13 +17,13 @@
 /// This macro will pass the global `Settings` instance to the closure, allowing you to use the settings without manually fetching them.
 #[macro_export]
 macro_rules! with_settings {
-    ($body:expr) => {
-        $crate::SETTINGS.with_borrow(|s| {
-            let settings = s
-                .as_ref()
-                .unwrap_or_else(|| ic_cdk::trap("Settings are not initialized."));
-            #[allow(clippy::redundant_closure_call)]
-            $body(settings)
-        })
-    };
+    ($body:expr) => {{
+        // The borrow of the settings cell ends before the closure runs, so the closure may call
+        // functions that replace the settings, e.g. `reload_settings`, without a borrow panic.
+        let settings = $crate::SETTINGS
+            .with_borrow(|s| s.clone())
+            .unwrap_or_else(|| ic_cdk::trap("Settings are not initialized."));
+        #[allow(clippy::redundant_closure_call)]
+        $body(&*settings)
+    }};
 }
@@ -1,20 +1,76 @@
-#[cfg(not(test))]
+use crate::time::get_current_time;
+use std::cell::Cell;
+
+thread_local! {
+    // The last value handed out by `generate_counter_nonce`.
+    static LAST_COUNTER_NONCE: Cell<u64> = const { Cell::new(0) };
+}
+
+/// Generates a random 10 byte nonce, hex encoded. The nonce is drawn from a ChaCha20 random number
+/// generator seeded with 32 bytes from the management canister `raw_rand` call during canister
+/// initialization. The generator must have been initialized before calling this function.
+///
+/// This is the same nonce that is used in SIWS messages, the function is public when the `nonce`
+/// feature is enabled.
+#[cfg(feature = "nonce")]
+pub fn generate_nonce() -> String {
+    hex::encode(random_nonce_bytes())
+}
+
+#[cfg(not(feature = "nonce"))]
 pub(crate) fn generate_nonce() -> String {
+    hex::encode(random_nonce_bytes())
+}
+
+/// Generates a nonce from a monotonically increasing counter, hex encoded as 20 characters. The counter
+/// is seeded from the current time in nanoseconds, so values keep increasing across canister upgrades.
+///
+/// The nonce contains no entropy and is predictable. It is guaranteed to be unique within a canister,
+/// which makes it suitable for idempotency keys, but it should not be used where unpredictability is
+/// required, such as for CSRF tokens.
+pub fn generate_counter_nonce() -> String {
+    let counter = LAST_COUNTER_NONCE.with(|last| {
+        let next = get_current_time().max(last.get().saturating_add(1));
+        last.set(next);
+        next
+    });
+    format!("{:020x}", counter)
+}
+
+#[cfg(not(test))]
+fn random_nonce_bytes() -> [u8; 10] {
     use crate::RNG;
     use rand_chacha::rand_core::RngCore;
 
     let mut buf = [0u8; 10];
     RNG.with_borrow_mut(|rng| rng.as_mut().unwrap().fill_bytes(&mut buf));
-
-    hex::encode(buf)
+    buf
 }
 
 #[cfg(test)]
-pub(crate) fn generate_nonce() -> String {
+fn random_nonce_bytes() -> [u8; 10] {
     use rand::{thread_rng, Rng};
 
     let mut rng = thread_rng();
     let mut nonce = [0u8; 10];
     rng.fill(&mut nonce);
-    hex::encode(nonce)
+    nonce
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generate_nonce_length() {
+        assert_eq!(generate_nonce().len(), 20);
+    }
+
+    #[test]
+    fn test_generate_counter_nonce_is_monotonic() {
+        let first = generate_counter_nonce();
+        let second = generate_counter_nonce();
+        assert_eq!(first.len(), 20);
+        assert!(second > first);
+    }
 }
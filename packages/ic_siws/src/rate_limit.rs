@@ -0,0 +1,142 @@
+use crate::solana::SolPubkey;
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+};
+
+#[derive(Debug, PartialEq)]
+pub enum RateLimitError {
+    RateLimitExceeded { retry_after_ns: u64 },
+}
+
+impl fmt::Display for RateLimitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RateLimitError::RateLimitExceeded { retry_after_ns } => {
+                write!(f, "Rate limit exceeded, retry after {} ns", retry_after_ns)
+            }
+        }
+    }
+}
+
+impl From<RateLimitError> for String {
+    fn from(error: RateLimitError) -> Self {
+        error.to_string()
+    }
+}
+
+/// Limits the number of calls per address within a sliding time window. The library uses it to limit
+/// `prepare_login` calls, see [`Settings::max_prepare_login_calls`](crate::settings::Settings::max_prepare_login_calls).
+#[derive(Default)]
+pub struct AddressRateLimiter {
+    // The times of the calls within the window, oldest first.
+    calls: HashMap<SolPubkey, VecDeque<u64>>,
+}
+
+impl AddressRateLimiter {
+    pub fn new() -> AddressRateLimiter {
+        AddressRateLimiter::default()
+    }
+
+    /// Returns [`RateLimitError::RateLimitExceeded`] with the time until the oldest call leaves the window if
+    /// `max_calls` calls for `address` have been recorded in the last `window_ns` nanoseconds. Does not record
+    /// the call, see [`AddressRateLimiter::record`].
+    pub fn check(
+        &self,
+        address: &SolPubkey,
+        current_time: u64,
+        max_calls: u32,
+        window_ns: u64,
+    ) -> Result<(), RateLimitError> {
+        let Some(calls) = self.calls.get(address) else {
+            return Ok(());
+        };
+        let mut in_window = calls
+            .iter()
+            .filter(|call| call.saturating_add(window_ns) > current_time);
+        if let Some(oldest) = in_window.next() {
+            if in_window.count() + 1 >= max_calls as usize {
+                return Err(RateLimitError::RateLimitExceeded {
+                    retry_after_ns: oldest.saturating_add(window_ns) - current_time,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Records a call for `address` at `current_time`, and drops its calls that are outside the window. Call it
+    /// once the call has succeeded, so that failed calls do not count against the limit.
+    pub fn record(&mut self, address: &SolPubkey, current_time: u64, window_ns: u64) {
+        let calls = self.calls.entry(*address).or_default();
+        while calls
+            .front()
+            .is_some_and(|call| call.saturating_add(window_ns) <= current_time)
+        {
+            calls.pop_front();
+        }
+        calls.push_back(current_time);
+    }
+
+    /// Removes calls that are outside the window, and addresses that have no calls left.
+    pub fn prune(&mut self, current_time: u64, window_ns: u64) {
+        self.calls.retain(|_, calls| {
+            calls.retain(|call| call.saturating_add(window_ns) > current_time);
+            !calls.is_empty()
+        });
+    }
+
+    /// Returns the number of addresses with calls that have not been pruned.
+    pub fn len(&self) -> usize {
+        self.calls.len()
+    }
+
+    /// Returns `true` if no calls are tracked.
+    pub fn is_empty(&self) -> bool {
+        self.calls.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_sliding_window() {
+        let address = SolPubkey::from_str("Awes4Tr6TX8JDzEhCZY2QVNimT6iD1zWHzf1vNyGvpLM").unwrap();
+        let mut limiter = AddressRateLimiter::new();
+        assert!(limiter.check(&address, 100, 2, 50).is_ok());
+        limiter.record(&address, 100, 50);
+        assert!(limiter.check(&address, 120, 2, 50).is_ok());
+        limiter.record(&address, 120, 50);
+        assert_eq!(
+            limiter.check(&address, 130, 2, 50),
+            Err(RateLimitError::RateLimitExceeded { retry_after_ns: 20 })
+        );
+
+        // Other addresses are limited separately.
+        let other = SolPubkey::from_str("11111111111111111111111111111112").unwrap();
+        assert!(limiter.check(&other, 130, 2, 50).is_ok());
+        limiter.record(&other, 130, 50);
+
+        // The first call has left the window.
+        assert!(limiter.check(&address, 150, 2, 50).is_ok());
+        limiter.record(&address, 150, 50);
+        assert!(limiter.check(&address, 160, 2, 50).is_err());
+
+        limiter.prune(180, 50);
+        assert_eq!(limiter.len(), 1);
+        limiter.prune(300, 50);
+        assert!(limiter.is_empty());
+    }
+
+    #[test]
+    fn test_check_does_not_record() {
+        let address = SolPubkey::from_str("Awes4Tr6TX8JDzEhCZY2QVNimT6iD1zWHzf1vNyGvpLM").unwrap();
+        let limiter = AddressRateLimiter::new();
+        for _ in 0..3 {
+            assert!(limiter.check(&address, 100, 1, 50).is_ok());
+        }
+        assert!(limiter.is_empty());
+    }
+}
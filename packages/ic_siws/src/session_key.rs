@@ -0,0 +1,32 @@
+use serde_bytes::ByteBuf;
+use subtle::ConstantTimeEq;
+
+/// Compares two session keys in constant time, to avoid leaking through timing how many leading bytes of a
+/// key an attacker has guessed. Any lookup of session keys by value should use this instead of `==`.
+///
+/// The shorter key is padded with zeros so that the comparison takes the same time whatever the keys contain.
+/// Keys of different lengths are never equal, lengths are not secret.
+pub fn ct_eq(a: &ByteBuf, b: &ByteBuf) -> bool {
+    let len = a.len().max(b.len());
+    let mut padded_a = vec![0u8; len];
+    let mut padded_b = vec![0u8; len];
+    padded_a[..a.len()].copy_from_slice(a);
+    padded_b[..b.len()].copy_from_slice(b);
+    let bytes_equal: bool = padded_a.ct_eq(&padded_b).into();
+    bytes_equal && a.len() == b.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ct_eq() {
+        let key = ByteBuf::from(vec![1, 2, 3]);
+        assert!(ct_eq(&key, &ByteBuf::from(vec![1, 2, 3])));
+        assert!(!ct_eq(&key, &ByteBuf::from(vec![1, 2, 4])));
+        assert!(!ct_eq(&key, &ByteBuf::from(vec![1, 2, 3, 0])));
+        assert!(!ct_eq(&key, &ByteBuf::from(vec![])));
+        assert!(ct_eq(&ByteBuf::new(), &ByteBuf::new()));
+    }
+}
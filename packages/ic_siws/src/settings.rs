@@ -1,4 +1,11 @@
+use crate::{
+    login::{cancel_message_pruning, schedule_message_pruning},
+    solana::SolPubkey,
+    with_settings, SETTINGS,
+};
 use candid::Principal;
+use serde::{Deserialize, Serialize};
+use std::{fmt, rc::Rc};
 use url::Url;
 
 const DEFAULT_SCHEME: &str = "https";
@@ -6,8 +13,16 @@ const DEFAULT_STATEMENT: &str = "SIWS Fields:";
 const DEFAULT_CHAIN_ID: &str = "mainnet";
 const DEFAULT_SIGN_IN_EXPIRES_IN: u64 = 60 * 5 * 1_000_000_000; // 5 minutes
 const DEFAULT_SESSION_EXPIRES_IN: u64 = 30 * 60 * 1_000_000_000; // 30 minutes
-
-#[derive(Debug, Clone, PartialEq)]
+const DEFAULT_SIGNATURE_MAP_INITIAL_CAPACITY: usize = 16;
+const DEFAULT_MAX_SIGS_TO_PRUNE: usize = 10;
+const DEFAULT_MAX_DELEGATION_MEMO_BYTES: usize = 256;
+const DEFAULT_MAX_PENDING_MESSAGES_PER_ADDRESS: usize = 1;
+const DEFAULT_MAX_PREPARE_LOGIN_CALLS: u32 = 10;
+const DEFAULT_PREPARE_LOGIN_WINDOW_NS: u64 = 60 * 1_000_000_000; // 1 minute
+const MAX_STATEMENT_LEN: usize = 1000;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum RuntimeFeature {
     // Enabling this feature will include the app frontend URI as part of the identity seed.
     IncludeUriInSeed,
@@ -22,7 +37,9 @@ pub enum RuntimeFeature {
 ///
 /// The SIWS library needs to be initialized with a `Settings` instance before it can be used. Call the [`crate::init()`] function
 /// to initialize the library.
-#[derive(Default, Debug, Clone)]
+///
+/// `Settings::default()` returns settings for local development only, see the [`Default`] implementation.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Settings {
     /// The domain from where the frontend that uses SIWS is served.
     pub domain: String,
@@ -34,10 +51,12 @@ pub struct Settings {
     /// printable ASCII characters.
     pub salt: String,
 
-    /// The Solana chain ID, defaults to "mainnet"
+    /// The Solana chain ID, defaults to "mainnet". Solana chain IDs are cluster names such as "devnet" or
+    /// "solana:devnet" rather than numeric EVM chain IDs, see [validate_chain_id] for the valid values.
     pub chain_id: String,
 
-    /// The scheme used to serve the frontend that uses SIWS. Defaults to "https".
+    /// The scheme used to serve the frontend that uses SIWS. Defaults to "https". If empty, the scheme of the
+    /// `uri` is used, see [`Settings::effective_scheme`].
     pub scheme: String,
 
     /// The statement is a message or declaration, often presented to the user by the Solana wallet
@@ -55,6 +74,115 @@ pub struct Settings {
 
     /// Optional runtime features that can be enabled for SIWS.
     pub runtime_features: Option<Vec<RuntimeFeature>>,
+
+    /// The number of signatures to pre-allocate space for when creating the [`SignatureMap`](crate::signature_map::SignatureMap).
+    /// Defaults to 16.
+    pub signature_map_initial_capacity: usize,
+
+    /// Program addresses, in addition to the well-known Solana native programs, that are not allowed to sign in.
+    pub additional_blocked_programs: Vec<SolPubkey>,
+
+    /// The maximum number of expired signatures to prune from the signature map on each login. Defaults to 10.
+    pub max_sigs_to_prune: usize,
+
+    /// If `true`, a successful login also issues a stateless session token, see
+    /// [`LoginDetails::session_token`](crate::login::LoginDetails::session_token). Defaults to `false`.
+    pub issue_session_tokens: bool,
+
+    /// The interval in nanoseconds at which expired SIWS messages are pruned by a timer. Defaults to `None`,
+    /// which means that expired messages are only pruned on login.
+    pub auto_prune_interval_ns: Option<u64>,
+
+    /// If set, SIWS messages get a `not_before` time of `default_not_before_offset` nanoseconds after the time
+    /// they are issued. Defaults to `None`, which means messages are valid immediately.
+    pub default_not_before_offset: Option<u64>,
+
+    /// The maximum size in bytes of the memo attached to a delegation, see
+    /// [`create_delegation_with_memo`](crate::delegation::create_delegation_with_memo). Defaults to 256.
+    pub max_delegation_memo_bytes: usize,
+
+    /// Resources that are included in every SIWS message. Each resource must be a valid URI. Defaults to an
+    /// empty list.
+    pub default_resources: Vec<String>,
+
+    /// A statement with `{address}` and `{domain}` placeholders that are replaced when a SIWS message is
+    /// created. When set, the template overrides `statement`. Defaults to `None`.
+    pub statement_template: Option<String>,
+
+    /// The maximum session TTL in nanoseconds that can be requested per login, see
+    /// [`login_with_options`](crate::login::login_with_options). Defaults to `None`, in which case per-login
+    /// overrides may not exceed `session_expires_in`.
+    pub max_session_expires_in: Option<u64>,
+
+    /// The maximum number of SIWS messages that can be pending for a single address. Further `prepare_login`
+    /// calls for the address evict its oldest pending message. Defaults to 1.
+    #[serde(default = "default_max_pending_messages_per_address")]
+    pub max_pending_messages_per_address: usize,
+
+    /// The maximum number of SIWS messages that can be pending across all addresses. Expired messages are
+    /// pruned before the limit is enforced. Defaults to `None`, which means the number of pending messages is
+    /// unbounded. A limit of around 1000 is recommended for canisters that are open to the public.
+    pub max_pending_logins: Option<usize>,
+
+    /// The maximum age in nanoseconds of a SIWS message at login, measured from its `issued_at` time. Defaults
+    /// to `None`, in which case `sign_in_expires_in` is used.
+    pub max_message_age_ns: Option<u64>,
+
+    /// The maximum number of `prepare_login` calls per address within `prepare_login_window_ns`. Defaults to 10.
+    #[serde(default = "default_max_prepare_login_calls")]
+    pub max_prepare_login_calls: u32,
+
+    /// The length in nanoseconds of the sliding window for `max_prepare_login_calls`. Defaults to 1 minute.
+    #[serde(default = "default_prepare_login_window_ns")]
+    pub prepare_login_window_ns: u64,
+
+    /// If set, only these addresses are allowed to sign in, e.g. a beta cohort. Defaults to `None`, which means
+    /// all addresses are allowed. See [`update_allowed_addresses`] to change the allowlist at runtime.
+    pub allowed_addresses: Option<Vec<SolPubkey>>,
+
+    /// A prefix that is prepended to the SIWS message before the signature is verified, for wallets that sign
+    /// the message with a prefix such as `"Solana Signed Message:\n"`. Defaults to `None`, which means the
+    /// signature is verified against the SIWS message as is.
+    pub message_prefix: Option<String>,
+
+    /// If `true`, retried login calls with the same signature and session key fail instead of returning the
+    /// login details of the first call. Defaults to `false`.
+    #[serde(default)]
+    pub disable_login_idempotency: bool,
+}
+
+/// Partial settings that override fields of a base [`Settings`] instance, see [`Settings::merge`]. Fields
+/// mirror the [`Settings`] struct, a `None` value keeps the value of the base settings.
+#[derive(Default, Debug, Clone)]
+pub struct PartialSettings {
+    pub domain: Option<String>,
+    pub uri: Option<String>,
+    pub salt: Option<String>,
+    pub chain_id: Option<String>,
+    pub scheme: Option<String>,
+    pub statement: Option<String>,
+    pub sign_in_expires_in: Option<u64>,
+    pub session_expires_in: Option<u64>,
+    pub targets: Option<Vec<Principal>>,
+    pub runtime_features: Option<Vec<RuntimeFeature>>,
+    pub signature_map_initial_capacity: Option<usize>,
+    pub additional_blocked_programs: Option<Vec<SolPubkey>>,
+    pub max_sigs_to_prune: Option<usize>,
+    pub issue_session_tokens: Option<bool>,
+    pub auto_prune_interval_ns: Option<u64>,
+    pub default_not_before_offset: Option<u64>,
+    pub max_delegation_memo_bytes: Option<usize>,
+    pub default_resources: Option<Vec<String>>,
+    pub statement_template: Option<String>,
+    pub max_session_expires_in: Option<u64>,
+    pub max_pending_messages_per_address: Option<usize>,
+    pub max_pending_logins: Option<usize>,
+    pub max_message_age_ns: Option<u64>,
+    pub max_prepare_login_calls: Option<u32>,
+    pub prepare_login_window_ns: Option<u64>,
+    pub allowed_addresses: Option<Vec<SolPubkey>>,
+    pub message_prefix: Option<String>,
+    pub disable_login_idempotency: Option<bool>,
 }
 
 /// A builder for creating `Settings` instances.
@@ -110,6 +238,24 @@ impl SettingsBuilder {
                 session_expires_in: DEFAULT_SESSION_EXPIRES_IN,
                 targets: None,
                 runtime_features: None,
+                signature_map_initial_capacity: DEFAULT_SIGNATURE_MAP_INITIAL_CAPACITY,
+                additional_blocked_programs: vec![],
+                max_sigs_to_prune: DEFAULT_MAX_SIGS_TO_PRUNE,
+                issue_session_tokens: false,
+                auto_prune_interval_ns: None,
+                default_not_before_offset: None,
+                max_delegation_memo_bytes: DEFAULT_MAX_DELEGATION_MEMO_BYTES,
+                default_resources: vec![],
+                statement_template: None,
+                max_session_expires_in: None,
+                max_pending_messages_per_address: DEFAULT_MAX_PENDING_MESSAGES_PER_ADDRESS,
+                max_pending_logins: None,
+                max_message_age_ns: None,
+                max_prepare_login_calls: DEFAULT_MAX_PREPARE_LOGIN_CALLS,
+                prepare_login_window_ns: DEFAULT_PREPARE_LOGIN_WINDOW_NS,
+                allowed_addresses: None,
+                message_prefix: None,
+                disable_login_idempotency: false,
             },
         }
     }
@@ -123,6 +269,7 @@ impl SettingsBuilder {
 
     /// The scheme used to serve the frontend that uses SIWS.
     /// The `scheme` is typically "http" or "https", defining the protocol part of the URI. Defaults to "https".
+    /// Set it to an empty string to use the scheme of the `uri`.
     pub fn scheme<S: Into<String>>(mut self, scheme: S) -> Self {
         self.settings.scheme = scheme.into();
         self
@@ -162,35 +309,685 @@ impl SettingsBuilder {
         self
     }
 
+    /// Canisters expecting many concurrent sessions can pre-allocate space in the signature map to avoid
+    /// frequent reallocations. Defaults to 16.
+    pub fn signature_map_initial_capacity(mut self, capacity: usize) -> Self {
+        self.settings.signature_map_initial_capacity = capacity;
+        self
+    }
+
+    /// Well-known Solana native program addresses are always blocked from signing in. Use `additional_blocked_programs`
+    /// to block additional program addresses.
+    pub fn additional_blocked_programs(mut self, programs: Vec<SolPubkey>) -> Self {
+        self.settings.additional_blocked_programs = programs;
+        self
+    }
+
+    /// Restricts sign in to the `addresses` in the allowlist. Other addresses fail to prepare a login with
+    /// [`PrepareLoginError::AddressNotAllowed`](crate::login::PrepareLoginError::AddressNotAllowed).
+    pub fn allowed_addresses(mut self, addresses: Vec<SolPubkey>) -> Self {
+        self.settings.allowed_addresses = Some(addresses);
+        self
+    }
+
+    /// Expired signatures are pruned from the signature map on each login. The `max_sigs_to_prune` value limits
+    /// the number of signatures pruned per login. High-traffic canisters may want to increase this value.
+    /// Defaults to 10.
+    pub fn max_sigs_to_prune(mut self, max_sigs_to_prune: usize) -> Self {
+        self.settings.max_sigs_to_prune = max_sigs_to_prune;
+        self
+    }
+
+    /// Enables stateless session tokens. When enabled, [`crate::login::login`] includes a session token in
+    /// the returned login details that HTTP gateways can use instead of delegations. Defaults to `false`.
+    pub fn issue_session_tokens(mut self, issue_session_tokens: bool) -> Self {
+        self.settings.issue_session_tokens = issue_session_tokens;
+        self
+    }
+
+    /// Expired SIWS messages are pruned lazily on login. Setting `auto_prune_interval_ns` additionally prunes
+    /// them periodically using a timer, see [`crate::login::schedule_message_pruning`].
+    pub fn auto_prune_interval_ns(mut self, interval_ns: u64) -> Self {
+        self.settings.auto_prune_interval_ns = Some(interval_ns);
+        self
+    }
+
+    /// Sets the `not_before` time of SIWS messages to `offset` nanoseconds after the time they are issued. Login
+    /// attempts before that time are rejected. Must be less than `sign_in_expires_in`.
+    pub fn default_not_before_offset(mut self, offset: u64) -> Self {
+        self.settings.default_not_before_offset = Some(offset);
+        self
+    }
+
+    /// Limits the size of memos attached to delegations. Defaults to 256 bytes.
+    pub fn max_delegation_memo_bytes(mut self, max_bytes: usize) -> Self {
+        self.settings.max_delegation_memo_bytes = max_bytes;
+        self
+    }
+
+    /// Resources listed in `default_resources` are included in every SIWS message, e.g. to let the user
+    /// authorize capabilities that are required by the application. Each resource must be a valid URI.
+    pub fn default_resources(mut self, resources: Vec<String>) -> Self {
+        self.settings.default_resources = resources;
+        self
+    }
+
+    /// Sets a statement template, e.g. `"Sign in to {domain} as {address}"`. The `{address}` and `{domain}`
+    /// placeholders are replaced when a SIWS message is created, no other placeholders are allowed. The
+    /// template overrides the `statement`.
+    pub fn statement_template<S: Into<String>>(mut self, template: S) -> Self {
+        self.settings.statement_template = Some(template.into());
+        self
+    }
+
+    /// Verifies signatures against the SIWS message with `prefix` prepended, e.g.
+    /// `"Solana Signed Message:\n"`. Only set a prefix if the wallets used with the canister add it when
+    /// signing, logins fail otherwise.
+    pub fn message_prefix<S: Into<String>>(mut self, prefix: S) -> Self {
+        self.settings.message_prefix = Some(prefix.into());
+        self
+    }
+
+    /// Login calls that are retried with the same signature and session key, e.g. after a network error, return
+    /// the login details of the first call for `sign_in_expires_in`, but no longer than the delegation signature
+    /// is kept, about a minute. Set `disable_login_idempotency` to make retried calls fail instead. Defaults to
+    /// `false`.
+    pub fn disable_login_idempotency(mut self, disable: bool) -> Self {
+        self.settings.disable_login_idempotency = disable;
+        self
+    }
+
+    /// Allows individual logins to request sessions of up to `max_expires_in` nanoseconds, e.g. longer sessions
+    /// for premium users. Must not be less than `session_expires_in`.
+    pub fn max_session_expires_in(mut self, max_expires_in: u64) -> Self {
+        self.settings.max_session_expires_in = Some(max_expires_in);
+        self
+    }
+
+    /// Limits the number of SIWS messages that can be pending for a single address. When the limit is reached,
+    /// `prepare_login` evicts the oldest pending message of the address. Defaults to 1.
+    pub fn max_pending_messages_per_address(mut self, max_pending: usize) -> Self {
+        self.settings.max_pending_messages_per_address = max_pending;
+        self
+    }
+
+    /// Limits the number of SIWS messages that can be pending across all addresses, protecting the canister
+    /// from running out of memory when flooded with `prepare_login` calls. Further calls fail with
+    /// [`PrepareLoginError::TooManyPendingLogins`](crate::login::PrepareLoginError::TooManyPendingLogins)
+    /// while the limit is reached. 1000 is a reasonable value for most canisters.
+    pub fn max_pending_logins(mut self, max_pending: usize) -> Self {
+        self.settings.max_pending_logins = Some(max_pending);
+        self
+    }
+
+    /// Rejects logins with SIWS messages that were issued more than `max_age_ns` nanoseconds ago, in addition
+    /// to the message expiry check. Defaults to `sign_in_expires_in`.
+    pub fn max_message_age_ns(mut self, max_age_ns: u64) -> Self {
+        self.settings.max_message_age_ns = Some(max_age_ns);
+        self
+    }
+
+    /// Limits each address to `max_calls` `prepare_login` calls within a sliding window of `window_ns`
+    /// nanoseconds. Further calls fail with
+    /// [`PrepareLoginError::RateLimitExceeded`](crate::login::PrepareLoginError::RateLimitExceeded). Defaults to
+    /// 10 calls per minute.
+    pub fn prepare_login_rate_limit(mut self, max_calls: u32, window_ns: u64) -> Self {
+        self.settings.max_prepare_login_calls = max_calls;
+        self.settings.prepare_login_window_ns = window_ns;
+        self
+    }
+
+    /// Validates and sets the domain, allowing canisters to check a single field before building the settings.
+    pub fn try_domain<S: Into<String>>(mut self, domain: S) -> Result<Self, SettingsError> {
+        let domain = domain.into();
+        validate_domain(&self.settings.effective_scheme(), &domain)?;
+        self.settings.domain = domain;
+        Ok(self)
+    }
+
+    /// Validates and sets the URI.
+    pub fn try_uri<S: Into<String>>(mut self, uri: S) -> Result<Self, SettingsError> {
+        let uri = uri.into();
+        validate_uri(&uri)?;
+        self.settings.uri = uri;
+        Ok(self)
+    }
+
+    /// Validates and sets the sign in expiration time. The value must be greater than 0, that it is less than
+    /// `session_expires_in` is validated when the settings are built.
+    pub fn try_sign_in_expires_in(mut self, expires_in: u64) -> Result<Self, SettingsError> {
+        if expires_in == 0 {
+            return Err(SettingsError::InvalidSignInExpiresIn(expires_in));
+        }
+        self.settings.sign_in_expires_in = expires_in;
+        Ok(self)
+    }
+
+    /// Validates and sets the session expiration time.
+    pub fn try_session_expires_in(mut self, expires_in: u64) -> Result<Self, SettingsError> {
+        validate_session_expires_in(expires_in)?;
+        self.settings.session_expires_in = expires_in;
+        Ok(self)
+    }
+
     pub fn build(self) -> Result<Settings, String> {
-        validate_domain(&self.settings.scheme, &self.settings.domain)?;
-        validate_uri(&self.settings.uri)?;
-        validate_salt(&self.settings.salt)?;
-        validate_chain_id(&self.settings.chain_id)?;
-        validate_scheme(&self.settings.scheme)?;
-        validate_statement(&self.settings.statement)?;
-        validate_sign_in_expires_in(self.settings.sign_in_expires_in)?;
-        validate_session_expires_in(self.settings.session_expires_in)?;
-        validate_targets(&self.settings.targets)?;
+        validate_settings(&self.settings)?;
+
+        // Warn canister developers that SIWS messages are served over an insecure scheme. Local development uses
+        // http, so this is only printed with the `debug-logging` feature.
+        #[cfg(all(feature = "debug-logging", target_arch = "wasm32"))]
+        if self.settings.effective_scheme() != "https" {
+            ic_cdk::println!(
+                "ic_siws: the scheme is {}, use https in production",
+                self.settings.effective_scheme()
+            );
+        }
 
         Ok(self.settings)
     }
 }
 
-fn validate_domain(scheme: &str, domain: &str) -> Result<String, String> {
+/// Default settings, suitable for development only:
+///
+/// - `domain`: `localhost`
+/// - `uri`: `https://localhost`
+/// - `salt`: `default_salt`
+/// - `scheme`: `https`
+/// - `statement`: `Sign in`
+/// - `chain_id`: `devnet`
+/// - `session_expires_in`: 30 minutes
+/// - `sign_in_expires_in`: 5 minutes
+///
+/// All other fields use the [`SettingsBuilder`] defaults. Never use the default salt in production, it makes
+/// user principals predictable.
+impl Default for Settings {
+    fn default() -> Self {
+        SettingsBuilder::new("localhost", "https://localhost", "default_salt")
+            .scheme("https")
+            .statement("Sign in")
+            .chain_id("devnet")
+            .session_expires_in(30 * 60 * 1_000_000_000)
+            .sign_in_expires_in(5 * 60 * 1_000_000_000)
+            .build()
+            .expect("Default settings should be valid")
+    }
+}
+
+/// Validates and replaces the settings of an already initialized SIWS library. This allows settings to be
+/// updated after deployment without upgrading the canister. Implementing canisters should only expose this
+/// functionality to the canister controllers.
+///
+/// ## Caveats
+///
+/// - SIWS messages created before the reload keep the expiration time of the old settings. Only messages
+///   created after the reload use the new settings.
+/// - Messages created before the reload fail to log in if the `uri` has changed.
+/// - Changing the `salt` or `uri` (with the `IncludeUriInSeed` runtime feature enabled) changes the principal
+///   of all users.
+///
+/// If `auto_prune_interval_ns` has changed, the pruning timer is cancelled and rescheduled.
+pub fn reload_settings(settings: Settings) -> Result<(), String> {
+    validate_settings(&settings)?;
+
+    let previous_interval_ns =
+        SETTINGS.with_borrow(|s| s.as_ref().and_then(|s| s.auto_prune_interval_ns));
+    let auto_prune_interval_ns = settings.auto_prune_interval_ns;
+    SETTINGS.set(Some(Rc::new(settings)));
+
+    if auto_prune_interval_ns != previous_interval_ns {
+        match auto_prune_interval_ns {
+            Some(interval_ns) => schedule_message_pruning(interval_ns),
+            None => cancel_message_pruning(),
+        }
+    }
+
+    Ok(())
+}
+
+/// Replaces the `allowed_addresses` of the current settings, e.g. to admit a new beta cohort without
+/// reinstalling the canister. The addresses must be base58 encoded Solana addresses, otherwise the settings are
+/// left unchanged. Use [`reload_settings`] to remove the allowlist.
+///
+/// Messages prepared by addresses that are no longer allowed can still be used to log in until they expire.
+pub fn update_allowed_addresses(addresses: Vec<String>) -> Result<(), SettingsError> {
+    let addresses = parse_addresses(&addresses).map_err(SettingsError::ValidationError)?;
+    let mut settings = with_settings!(|settings: &Settings| settings.clone());
+    settings.allowed_addresses = Some(addresses);
+    SETTINGS.set(Some(Rc::new(settings)));
+    Ok(())
+}
+
+fn parse_addresses(addresses: &[String]) -> Result<Vec<SolPubkey>, String> {
+    addresses
+        .iter()
+        .map(|a| {
+            a.parse::<SolPubkey>()
+                .map_err(|e| format!("Invalid address {}: {}", a, e))
+        })
+        .collect()
+}
+
+fn validate_settings(settings: &Settings) -> Result<(), String> {
+    let scheme = settings.effective_scheme();
+    validate_domain(&scheme, &settings.domain)?;
+    validate_uri(&settings.uri)?;
+    validate_salt(&settings.salt)?;
+    validate_chain_id(&settings.chain_id)?;
+    validate_scheme(&scheme)?;
+    validate_statement(&settings.statement)?;
+    validate_sign_in_expires_in(settings.sign_in_expires_in, settings.session_expires_in)?;
+    validate_session_expires_in(settings.session_expires_in)?;
+    validate_max_session_expires_in(settings.max_session_expires_in, settings.session_expires_in)?;
+    validate_targets(&settings.targets)?;
+    validate_auto_prune_interval_ns(settings.auto_prune_interval_ns)?;
+    validate_resources(&settings.default_resources)?;
+    validate_statement_template(&settings.statement_template, &settings.domain)?;
+    validate_max_pending_messages_per_address(settings.max_pending_messages_per_address)?;
+    validate_max_pending_logins(settings.max_pending_logins)?;
+    validate_max_message_age_ns(settings.max_message_age_ns)?;
+    validate_default_not_before_offset(
+        settings.default_not_before_offset,
+        settings.sign_in_expires_in,
+    )?;
+    validate_prepare_login_rate_limit(
+        settings.max_prepare_login_calls,
+        settings.prepare_login_window_ns,
+    )?;
+
+    Ok(())
+}
+
+/// A validation error for a single settings field, including the offending value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SettingsError {
+    InvalidDomain(String),
+    InvalidUri(String),
+    InvalidSignInExpiresIn(u64),
+    InvalidSessionExpiresIn(u64),
+    InvalidMaxSessionExpiresIn(u64),
+    DecodeError(String),
+    ValidationError(String),
+}
+
+impl fmt::Display for SettingsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SettingsError::InvalidDomain(domain) => write!(f, "Invalid domain: {}", domain),
+            SettingsError::InvalidUri(uri) => write!(f, "Invalid URI: {}", uri),
+            SettingsError::InvalidSignInExpiresIn(expires_in) => write!(
+                f,
+                "Invalid sign_in_expires_in: {}, must be greater than 0 and less than session_expires_in",
+                expires_in
+            ),
+            SettingsError::InvalidSessionExpiresIn(expires_in) => write!(
+                f,
+                "Invalid session_expires_in: {}, must be greater than 0",
+                expires_in
+            ),
+            SettingsError::InvalidMaxSessionExpiresIn(max_expires_in) => write!(
+                f,
+                "Invalid max_session_expires_in: {}, must not be less than session_expires_in",
+                max_expires_in
+            ),
+            SettingsError::DecodeError(e) => write!(f, "Failed to decode settings: {}", e),
+            SettingsError::ValidationError(e) => write!(f, "Invalid settings: {}", e),
+        }
+    }
+}
+
+impl From<SettingsError> for String {
+    fn from(error: SettingsError) -> Self {
+        error.to_string()
+    }
+}
+
+#[derive(Debug)]
+pub enum SettingsLoadError {
+    ParseError(String),
+    ValidationError(String),
+}
+
+impl fmt::Display for SettingsLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SettingsLoadError::ParseError(e) => write!(f, "Failed to parse settings: {}", e),
+            SettingsLoadError::ValidationError(e) => write!(f, "Invalid settings: {}", e),
+        }
+    }
+}
+
+impl From<SettingsLoadError> for String {
+    fn from(error: SettingsLoadError) -> Self {
+        error.to_string()
+    }
+}
+
+#[cfg(feature = "toml")]
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TomlSettings {
+    domain: String,
+    uri: String,
+    salt: String,
+    chain_id: Option<String>,
+    scheme: Option<String>,
+    statement: Option<String>,
+    sign_in_expires_in: Option<u64>,
+    session_expires_in: Option<u64>,
+    targets: Option<Vec<String>>,
+    runtime_features: Option<Vec<RuntimeFeature>>,
+    signature_map_initial_capacity: Option<usize>,
+    additional_blocked_programs: Option<Vec<String>>,
+    max_sigs_to_prune: Option<usize>,
+    issue_session_tokens: Option<bool>,
+    auto_prune_interval_ns: Option<u64>,
+    default_not_before_offset: Option<u64>,
+    max_delegation_memo_bytes: Option<usize>,
+    default_resources: Option<Vec<String>>,
+    statement_template: Option<String>,
+    max_session_expires_in: Option<u64>,
+    max_pending_messages_per_address: Option<usize>,
+    max_pending_logins: Option<usize>,
+    max_message_age_ns: Option<u64>,
+    max_prepare_login_calls: Option<u32>,
+    prepare_login_window_ns: Option<u64>,
+    allowed_addresses: Option<Vec<String>>,
+    message_prefix: Option<String>,
+    disable_login_idempotency: Option<bool>,
+}
+
+const LOCALNET_DEFAULT_CHAIN_ID: &str = "devnet";
+
+impl Settings {
+    /// Applies the non-`None` fields of `overrides` on top of `base`. This allows multi-tenant canisters to
+    /// share base settings across tenants and customize specific fields per tenant.
+    ///
+    /// The merged settings are not validated.
+    pub fn merge(base: Settings, overrides: PartialSettings) -> Settings {
+        Settings {
+            domain: overrides.domain.unwrap_or(base.domain),
+            uri: overrides.uri.unwrap_or(base.uri),
+            salt: overrides.salt.unwrap_or(base.salt),
+            chain_id: overrides.chain_id.unwrap_or(base.chain_id),
+            scheme: overrides.scheme.unwrap_or(base.scheme),
+            statement: overrides.statement.unwrap_or(base.statement),
+            sign_in_expires_in: overrides
+                .sign_in_expires_in
+                .unwrap_or(base.sign_in_expires_in),
+            session_expires_in: overrides
+                .session_expires_in
+                .unwrap_or(base.session_expires_in),
+            targets: overrides.targets.or(base.targets),
+            runtime_features: overrides.runtime_features.or(base.runtime_features),
+            signature_map_initial_capacity: overrides
+                .signature_map_initial_capacity
+                .unwrap_or(base.signature_map_initial_capacity),
+            additional_blocked_programs: overrides
+                .additional_blocked_programs
+                .unwrap_or(base.additional_blocked_programs),
+            max_sigs_to_prune: overrides
+                .max_sigs_to_prune
+                .unwrap_or(base.max_sigs_to_prune),
+            issue_session_tokens: overrides
+                .issue_session_tokens
+                .unwrap_or(base.issue_session_tokens),
+            auto_prune_interval_ns: overrides
+                .auto_prune_interval_ns
+                .or(base.auto_prune_interval_ns),
+            default_not_before_offset: overrides
+                .default_not_before_offset
+                .or(base.default_not_before_offset),
+            max_delegation_memo_bytes: overrides
+                .max_delegation_memo_bytes
+                .unwrap_or(base.max_delegation_memo_bytes),
+            default_resources: overrides
+                .default_resources
+                .unwrap_or(base.default_resources),
+            statement_template: overrides.statement_template.or(base.statement_template),
+            max_session_expires_in: overrides
+                .max_session_expires_in
+                .or(base.max_session_expires_in),
+            max_pending_messages_per_address: overrides
+                .max_pending_messages_per_address
+                .unwrap_or(base.max_pending_messages_per_address),
+            max_pending_logins: overrides.max_pending_logins.or(base.max_pending_logins),
+            max_message_age_ns: overrides.max_message_age_ns.or(base.max_message_age_ns),
+            max_prepare_login_calls: overrides
+                .max_prepare_login_calls
+                .unwrap_or(base.max_prepare_login_calls),
+            prepare_login_window_ns: overrides
+                .prepare_login_window_ns
+                .unwrap_or(base.prepare_login_window_ns),
+            allowed_addresses: overrides.allowed_addresses.or(base.allowed_addresses),
+            message_prefix: overrides.message_prefix.or(base.message_prefix),
+            disable_login_idempotency: overrides
+                .disable_login_idempotency
+                .unwrap_or(base.disable_login_idempotency),
+        }
+    }
+
+    /// Returns `scheme`, or the scheme of `uri` if `scheme` is empty, e.g. "https" for "https://example.com".
+    /// Falls back to "https" if the URI can't be parsed.
+    pub fn effective_scheme(&self) -> String {
+        if !self.scheme.is_empty() {
+            return self.scheme.clone();
+        }
+        uri_scheme(&self.uri).unwrap_or_else(|| DEFAULT_SCHEME.to_string())
+    }
+
+    /// Returns `true` if `pubkey` may sign in, that is if no `allowed_addresses` are set or `pubkey` is one of
+    /// them.
+    pub fn is_address_allowed(&self, pubkey: &SolPubkey) -> bool {
+        self.allowed_addresses
+            .as_ref()
+            .map_or(true, |allowed| allowed.contains(pubkey))
+    }
+
+    /// Returns the statement for a SIWS message signed by `address`. If a `statement_template` is set, its
+    /// placeholders are replaced, otherwise the `statement` is returned.
+    pub fn render_statement(&self, address: &str) -> String {
+        match &self.statement_template {
+            Some(template) => render_statement_template(template, address, &self.domain),
+            None => self.statement.clone(),
+        }
+    }
+
+    /// Encodes the settings as CBOR, allowing canisters that construct settings at deploy time to keep them in
+    /// stable memory across upgrades, see [`crate::restore_settings`].
+    pub fn to_stable_bytes(&self) -> Vec<u8> {
+        serde_cbor::to_vec(self).expect("Settings should be CBOR serializable")
+    }
+
+    /// Decodes and validates settings created by [`Settings::to_stable_bytes`].
+    pub fn from_stable_bytes(bytes: &[u8]) -> Result<Settings, SettingsError> {
+        let settings: Settings =
+            serde_cbor::from_slice(bytes).map_err(|e| SettingsError::DecodeError(e.to_string()))?;
+        validate_settings(&settings).map_err(SettingsError::ValidationError)?;
+        Ok(settings)
+    }
+
+    /// Returns the chain ID to use for the current environment. This is `chain_id` unless it is empty, in
+    /// which case `"devnet"` is returned as a sensible default for local development.
+    pub fn effective_chain_id(&self) -> &str {
+        if self.chain_id.is_empty() {
+            LOCALNET_DEFAULT_CHAIN_ID
+        } else {
+            &self.chain_id
+        }
+    }
+
+    /// Returns `true` if the frontend is served from the local machine, i.e. `domain` is `localhost` or
+    /// `127.0.0.1`, optionally followed by a port number.
+    pub fn is_localnet(&self) -> bool {
+        let host = self.domain.split(':').next().unwrap_or_default();
+        host == "localhost" || host == "127.0.0.1"
+    }
+}
+
+#[cfg(feature = "toml")]
+impl Settings {
+    /// Loads and validates `Settings` from a TOML string. Useful for local development setups where the
+    /// settings are kept in a configuration file. Requires the `toml` feature.
+    ///
+    /// Field names match the [`Settings`] struct. Only `domain`, `uri` and `salt` are required, all other
+    /// fields fall back to the same defaults as the [`SettingsBuilder`].
+    ///
+    /// # Example
+    ///
+    /// ```toml
+    /// domain = "example.com"
+    /// uri = "https://example.com"
+    /// salt = "some_salt"
+    ///
+    /// # chain_id = "mainnet"
+    /// # scheme = "https"
+    /// # statement = "SIWS Fields:"
+    /// # sign_in_expires_in = 300000000000      # 5 minutes in nanoseconds
+    /// # session_expires_in = 1800000000000     # 30 minutes in nanoseconds
+    /// # targets = ["aaaaa-aa"]                 # Defaults to all canisters
+    /// # runtime_features = ["include_uri_in_seed"]
+    /// # signature_map_initial_capacity = 16
+    /// # additional_blocked_programs = []       # Base58 encoded program addresses
+    /// # max_sigs_to_prune = 10
+    /// # issue_session_tokens = false
+    /// # auto_prune_interval_ns = 60000000000   # Defaults to pruning on login only
+    /// # default_not_before_offset = 0          # Defaults to no Not Before field
+    /// # max_delegation_memo_bytes = 256
+    /// # default_resources = ["https://example.com/terms"]
+    /// # statement_template = "Sign in to {domain} as {address}"
+    /// # max_session_expires_in = 2592000000000000  # 30 days, defaults to session_expires_in
+    /// # max_pending_messages_per_address = 1
+    /// # max_pending_logins = 1000              # Defaults to no limit
+    /// # max_message_age_ns = 300000000000      # Defaults to sign_in_expires_in
+    /// # max_prepare_login_calls = 10
+    /// # prepare_login_window_ns = 60000000000  # 1 minute in nanoseconds
+    /// # allowed_addresses = []                 # Base58 encoded addresses, defaults to all addresses
+    /// # message_prefix = "Solana Signed Message:\n"  # Defaults to no prefix
+    /// # disable_login_idempotency = false
+    /// ```
+    pub fn from_toml(s: &str) -> Result<Settings, SettingsLoadError> {
+        let input: TomlSettings =
+            toml::from_str(s).map_err(|e| SettingsLoadError::ParseError(e.to_string()))?;
+
+        let mut builder = SettingsBuilder::new(input.domain, input.uri, input.salt);
+        if let Some(chain_id) = input.chain_id {
+            builder = builder.chain_id(chain_id);
+        }
+        if let Some(scheme) = input.scheme {
+            builder = builder.scheme(scheme);
+        }
+        if let Some(statement) = input.statement {
+            builder = builder.statement(statement);
+        }
+        if let Some(expires_in) = input.sign_in_expires_in {
+            builder = builder.sign_in_expires_in(expires_in);
+        }
+        if let Some(expires_in) = input.session_expires_in {
+            builder = builder.session_expires_in(expires_in);
+        }
+        if let Some(targets) = input.targets {
+            let targets = targets
+                .iter()
+                .map(Principal::from_text)
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| SettingsLoadError::ValidationError(e.to_string()))?;
+            builder = builder.targets(targets);
+        }
+        if let Some(features) = input.runtime_features {
+            builder = builder.runtime_features(features);
+        }
+        if let Some(capacity) = input.signature_map_initial_capacity {
+            builder = builder.signature_map_initial_capacity(capacity);
+        }
+        if let Some(programs) = input.additional_blocked_programs {
+            let programs = programs
+                .iter()
+                .map(|p| p.parse::<SolPubkey>())
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| SettingsLoadError::ValidationError(e.to_string()))?;
+            builder = builder.additional_blocked_programs(programs);
+        }
+        if let Some(addresses) = input.allowed_addresses {
+            let addresses =
+                parse_addresses(&addresses).map_err(SettingsLoadError::ValidationError)?;
+            builder = builder.allowed_addresses(addresses);
+        }
+        if let Some(prefix) = input.message_prefix {
+            builder = builder.message_prefix(prefix);
+        }
+        if let Some(disable) = input.disable_login_idempotency {
+            builder = builder.disable_login_idempotency(disable);
+        }
+        if let Some(max_sigs_to_prune) = input.max_sigs_to_prune {
+            builder = builder.max_sigs_to_prune(max_sigs_to_prune);
+        }
+        if let Some(issue_session_tokens) = input.issue_session_tokens {
+            builder = builder.issue_session_tokens(issue_session_tokens);
+        }
+        if let Some(interval_ns) = input.auto_prune_interval_ns {
+            builder = builder.auto_prune_interval_ns(interval_ns);
+        }
+        if let Some(offset) = input.default_not_before_offset {
+            builder = builder.default_not_before_offset(offset);
+        }
+        if let Some(max_bytes) = input.max_delegation_memo_bytes {
+            builder = builder.max_delegation_memo_bytes(max_bytes);
+        }
+        if let Some(resources) = input.default_resources {
+            builder = builder.default_resources(resources);
+        }
+        if let Some(template) = input.statement_template {
+            builder = builder.statement_template(template);
+        }
+        if let Some(max_expires_in) = input.max_session_expires_in {
+            builder = builder.max_session_expires_in(max_expires_in);
+        }
+        if let Some(max_pending) = input.max_pending_messages_per_address {
+            builder = builder.max_pending_messages_per_address(max_pending);
+        }
+        if let Some(max_pending) = input.max_pending_logins {
+            builder = builder.max_pending_logins(max_pending);
+        }
+        if let Some(max_age_ns) = input.max_message_age_ns {
+            builder = builder.max_message_age_ns(max_age_ns);
+        }
+        if input.max_prepare_login_calls.is_some() || input.prepare_login_window_ns.is_some() {
+            builder = builder.prepare_login_rate_limit(
+                input
+                    .max_prepare_login_calls
+                    .unwrap_or(DEFAULT_MAX_PREPARE_LOGIN_CALLS),
+                input
+                    .prepare_login_window_ns
+                    .unwrap_or(DEFAULT_PREPARE_LOGIN_WINDOW_NS),
+            );
+        }
+
+        builder.build().map_err(SettingsLoadError::ValidationError)
+    }
+}
+
+fn validate_domain(scheme: &str, domain: &str) -> Result<String, SettingsError> {
+    let invalid = || SettingsError::InvalidDomain(domain.to_string());
+    if domain.is_empty() {
+        return Err(invalid());
+    }
     let url_str = format!("{}://{}", scheme, domain);
-    let parsed_url = Url::parse(&url_str).map_err(|_| String::from("Invalid domain"))?;
+    let parsed_url = Url::parse(&url_str).map_err(|_| invalid())?;
     if !parsed_url.has_authority() {
-        Err(String::from("Invalid domain"))
+        Err(invalid())
     } else {
         Ok(parsed_url.host_str().unwrap().to_string())
     }
 }
 
-fn validate_uri(uri: &str) -> Result<String, String> {
-    let parsed_uri = Url::parse(uri).map_err(|_| String::from("Invalid URI"))?;
+fn uri_scheme(uri: &str) -> Option<String> {
+    Url::parse(uri).ok().map(|url| url.scheme().to_string())
+}
+
+fn validate_uri(uri: &str) -> Result<String, SettingsError> {
+    let parsed_uri = Url::parse(uri).map_err(|_| SettingsError::InvalidUri(uri.to_string()))?;
     if !parsed_uri.has_host() {
-        Err(String::from("Invalid URI"))
+        Err(SettingsError::InvalidUri(uri.to_string()))
     } else {
         Ok(uri.to_string())
     }
@@ -228,28 +1025,160 @@ fn validate_scheme(scheme: &str) -> Result<String, String> {
     Err(String::from("Invalid scheme"))
 }
 
-fn validate_statement(statement: &str) -> Result<String, String> {
+pub(crate) fn validate_statement(statement: &str) -> Result<String, String> {
     if statement.contains('\n') {
         return Err(String::from("Invalid statement"));
     }
     Ok(statement.to_string())
 }
 
-fn validate_sign_in_expires_in(expires_in: u64) -> Result<u64, String> {
-    if expires_in == 0 {
-        return Err(String::from("Sign in expires in must be greater than 0"));
+fn render_statement_template(template: &str, address: &str, domain: &str) -> String {
+    template
+        .replace("{address}", address)
+        .replace("{domain}", domain)
+}
+
+fn validate_statement_template(template: &Option<String>, domain: &str) -> Result<(), String> {
+    let Some(template) = template else {
+        return Ok(());
+    };
+    validate_statement(template)?;
+
+    let mut rest = template.as_str();
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| String::from("Unclosed placeholder in statement template"))?;
+        let placeholder = &rest[start + 1..start + end];
+        if placeholder != "address" && placeholder != "domain" {
+            return Err(format!(
+                "Unknown placeholder in statement template: {{{}}}",
+                placeholder
+            ));
+        }
+        rest = &rest[start + end + 1..];
+    }
+
+    // Base58 encoded Solana addresses are at most 44 characters long.
+    let longest_statement = render_statement_template(template, &"x".repeat(44), domain);
+    if longest_statement.chars().count() > MAX_STATEMENT_LEN {
+        return Err(format!(
+            "Statement template produces statements longer than {} characters",
+            MAX_STATEMENT_LEN
+        ));
+    }
+    Ok(())
+}
+
+fn validate_sign_in_expires_in(
+    expires_in: u64,
+    session_expires_in: u64,
+) -> Result<u64, SettingsError> {
+    if expires_in == 0 || expires_in >= session_expires_in {
+        return Err(SettingsError::InvalidSignInExpiresIn(expires_in));
     }
     Ok(expires_in)
 }
 
-fn validate_session_expires_in(expires_in: u64) -> Result<u64, String> {
+fn validate_session_expires_in(expires_in: u64) -> Result<u64, SettingsError> {
     if expires_in == 0 {
-        return Err(String::from("Session expires in must be greater than 0"));
+        return Err(SettingsError::InvalidSessionExpiresIn(expires_in));
     }
     Ok(expires_in)
 }
 
-fn validate_targets(targets: &Option<Vec<Principal>>) -> Result<Option<Vec<Principal>>, String> {
+fn validate_max_session_expires_in(
+    max_expires_in: Option<u64>,
+    session_expires_in: u64,
+) -> Result<Option<u64>, SettingsError> {
+    match max_expires_in {
+        Some(max_expires_in) if max_expires_in < session_expires_in => {
+            Err(SettingsError::InvalidMaxSessionExpiresIn(max_expires_in))
+        }
+        _ => Ok(max_expires_in),
+    }
+}
+
+// Settings serialized before `max_pending_messages_per_address` was added decode with the default.
+fn default_max_pending_messages_per_address() -> usize {
+    DEFAULT_MAX_PENDING_MESSAGES_PER_ADDRESS
+}
+
+fn validate_max_pending_messages_per_address(max_pending: usize) -> Result<usize, String> {
+    if max_pending == 0 {
+        return Err(String::from(
+            "Max pending messages per address must be greater than 0",
+        ));
+    }
+    Ok(max_pending)
+}
+
+fn validate_max_pending_logins(max_pending: Option<usize>) -> Result<Option<usize>, String> {
+    if max_pending == Some(0) {
+        return Err(String::from("Max pending logins must be greater than 0"));
+    }
+    Ok(max_pending)
+}
+
+fn validate_max_message_age_ns(max_age_ns: Option<u64>) -> Result<Option<u64>, String> {
+    if max_age_ns == Some(0) {
+        return Err(String::from("Max message age must be greater than 0"));
+    }
+    Ok(max_age_ns)
+}
+
+// A message that only becomes valid once it has expired can never be used to log in.
+fn validate_default_not_before_offset(
+    offset: Option<u64>,
+    sign_in_expires_in: u64,
+) -> Result<Option<u64>, String> {
+    if offset.is_some_and(|offset| offset >= sign_in_expires_in) {
+        return Err(String::from(
+            "Default not before offset must be less than sign_in_expires_in",
+        ));
+    }
+    Ok(offset)
+}
+
+// Settings serialized before the prepare_login rate limit was added decode with the defaults.
+fn default_max_prepare_login_calls() -> u32 {
+    DEFAULT_MAX_PREPARE_LOGIN_CALLS
+}
+
+fn default_prepare_login_window_ns() -> u64 {
+    DEFAULT_PREPARE_LOGIN_WINDOW_NS
+}
+
+fn validate_prepare_login_rate_limit(max_calls: u32, window_ns: u64) -> Result<(), String> {
+    if max_calls == 0 {
+        return Err(String::from(
+            "Max prepare login calls must be greater than 0",
+        ));
+    }
+    if window_ns == 0 {
+        return Err(String::from("Prepare login window must be greater than 0"));
+    }
+    Ok(())
+}
+
+fn validate_auto_prune_interval_ns(interval_ns: Option<u64>) -> Result<Option<u64>, String> {
+    if interval_ns == Some(0) {
+        return Err(String::from("Auto prune interval must be greater than 0"));
+    }
+    Ok(interval_ns)
+}
+
+/// Validates that every resource is a valid URI.
+pub(crate) fn validate_resources(resources: &[String]) -> Result<(), String> {
+    for resource in resources {
+        Url::parse(resource).map_err(|_| format!("Invalid resource URI: {}", resource))?;
+    }
+    Ok(())
+}
+
+pub(crate) fn validate_targets(
+    targets: &Option<Vec<Principal>>,
+) -> Result<Option<Vec<Principal>>, String> {
     if let Some(targets) = targets {
         if targets.is_empty() {
             return Err(String::from("Targets cannot be empty"));
@@ -275,6 +1204,7 @@ fn validate_targets(targets: &Option<Vec<Principal>>) -> Result<Option<Vec<Princ
 mod tests {
     use super::*;
     use candid::Principal;
+    use std::str::FromStr;
 
     // Test successful settings creation with default values
     #[test]
@@ -292,6 +1222,122 @@ mod tests {
         assert_eq!(settings.sign_in_expires_in, DEFAULT_SIGN_IN_EXPIRES_IN);
         assert_eq!(settings.session_expires_in, DEFAULT_SESSION_EXPIRES_IN);
         assert!(settings.targets.is_none());
+        assert_eq!(
+            settings.signature_map_initial_capacity,
+            DEFAULT_SIGNATURE_MAP_INITIAL_CAPACITY
+        );
+        assert_eq!(settings.max_sigs_to_prune, DEFAULT_MAX_SIGS_TO_PRUNE);
+        assert!(!settings.issue_session_tokens);
+        assert!(settings.auto_prune_interval_ns.is_none());
+        assert!(settings.default_not_before_offset.is_none());
+        assert_eq!(
+            settings.max_delegation_memo_bytes,
+            DEFAULT_MAX_DELEGATION_MEMO_BYTES
+        );
+        assert!(settings.statement_template.is_none());
+        assert!(settings.max_session_expires_in.is_none());
+        assert_eq!(
+            settings.max_pending_messages_per_address,
+            DEFAULT_MAX_PENDING_MESSAGES_PER_ADDRESS
+        );
+        assert!(settings.max_pending_logins.is_none());
+        assert!(settings.max_message_age_ns.is_none());
+        assert_eq!(
+            settings.max_prepare_login_calls,
+            DEFAULT_MAX_PREPARE_LOGIN_CALLS
+        );
+        assert_eq!(
+            settings.prepare_login_window_ns,
+            DEFAULT_PREPARE_LOGIN_WINDOW_NS
+        );
+        assert!(settings.allowed_addresses.is_none());
+        assert!(settings.message_prefix.is_none());
+        assert!(!settings.disable_login_idempotency);
+    }
+
+    #[test]
+    fn test_statement_template() {
+        let settings = SettingsBuilder::new("example.com", "http://example.com", "some_salt")
+            .statement_template("Sign in to {domain} as {address}")
+            .build()
+            .unwrap();
+        assert_eq!(
+            settings.render_statement("Awes4Tr6TX8JDzEhCZY2QVNimT6iD1zWHzf1vNyGvpLM"),
+            "Sign in to example.com as Awes4Tr6TX8JDzEhCZY2QVNimT6iD1zWHzf1vNyGvpLM"
+        );
+
+        for template in [
+            "Sign in as {user}",
+            "Sign in as {address",
+            "Sign in\n{address}",
+        ] {
+            let result = SettingsBuilder::new("example.com", "http://example.com", "some_salt")
+                .statement_template(template)
+                .build();
+            assert!(result.is_err(), "{}", template);
+        }
+
+        let result = SettingsBuilder::new("example.com", "http://example.com", "some_salt")
+            .statement_template(format!("{}{{address}}", "a".repeat(960)))
+            .build();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_stable_bytes_roundtrip() {
+        let settings = SettingsBuilder::new("example.com", "http://example.com", "some_salt")
+            .targets(vec![Principal::anonymous()])
+            .runtime_features(vec![RuntimeFeature::IncludeUriInSeed])
+            .additional_blocked_programs(vec![SolPubkey::from([1u8; 32])])
+            .statement_template("Sign in as {address}")
+            .auto_prune_interval_ns(60_000_000_000)
+            .build()
+            .unwrap();
+
+        // Simulate an upgrade: the settings are written to stable memory in pre_upgrade and restored in
+        // post_upgrade.
+        let bytes = settings.to_stable_bytes();
+        let restored = Settings::from_stable_bytes(&bytes).unwrap();
+        assert_eq!(format!("{:?}", restored), format!("{:?}", settings));
+
+        assert!(matches!(
+            Settings::from_stable_bytes(b"not cbor"),
+            Err(SettingsError::DecodeError(_))
+        ));
+        let mut invalid = settings;
+        invalid.salt = String::new();
+        assert!(matches!(
+            Settings::from_stable_bytes(&invalid.to_stable_bytes()),
+            Err(SettingsError::ValidationError(_))
+        ));
+    }
+
+    #[test]
+    fn test_default_settings() {
+        let settings = Settings::default();
+        assert_eq!(settings.domain, "localhost");
+        assert_eq!(settings.uri, "https://localhost");
+        assert_eq!(settings.salt, "default_salt");
+        assert_eq!(settings.scheme, "https");
+        assert_eq!(settings.statement, "Sign in");
+        assert_eq!(settings.chain_id, "devnet");
+        assert_eq!(settings.session_expires_in, 30 * 60 * 1_000_000_000);
+        assert_eq!(settings.sign_in_expires_in, 5 * 60 * 1_000_000_000);
+        assert!(validate_settings(&settings).is_ok());
+    }
+
+    #[test]
+    fn test_effective_chain_id_and_is_localnet() {
+        let mut settings = SettingsBuilder::new("localhost:8080", "http://localhost:8080", "salt")
+            .build()
+            .unwrap();
+        assert_eq!(settings.effective_chain_id(), DEFAULT_CHAIN_ID);
+        assert!(settings.is_localnet());
+
+        settings.chain_id = String::new();
+        settings.domain = "example.com".to_string();
+        assert_eq!(settings.effective_chain_id(), "devnet");
+        assert!(!settings.is_localnet());
     }
 
     // Test successful settings creation with custom values
@@ -331,6 +1377,20 @@ mod tests {
         assert!(builder.build().is_err());
     }
 
+    #[test]
+    fn test_effective_scheme() {
+        let settings = SettingsBuilder::new("example.com", "http://example.com", "some_salt")
+            .scheme("")
+            .build()
+            .unwrap();
+        assert_eq!(settings.effective_scheme(), "http");
+
+        let settings = SettingsBuilder::new("example.com", "http://example.com", "some_salt")
+            .build()
+            .unwrap();
+        assert_eq!(settings.effective_scheme(), "https");
+    }
+
     // Test invalid scheme
     #[test]
     fn test_invalid_scheme() {
@@ -339,6 +1399,86 @@ mod tests {
         assert!(builder.build().is_err());
     }
 
+    #[test]
+    fn test_merge() {
+        let base = SettingsBuilder::new("example.com", "http://example.com", "some_salt")
+            .statement("Base statement")
+            .build()
+            .unwrap();
+        let overrides = PartialSettings {
+            domain: Some("tenant.example.com".to_string()),
+            uri: Some("http://tenant.example.com".to_string()),
+            targets: Some(vec![Principal::anonymous()]),
+            ..Default::default()
+        };
+        let merged = Settings::merge(base, overrides);
+        assert_eq!(merged.domain, "tenant.example.com");
+        assert_eq!(merged.uri, "http://tenant.example.com");
+        assert_eq!(merged.salt, "some_salt");
+        assert_eq!(merged.statement, "Base statement");
+        assert_eq!(merged.targets, Some(vec![Principal::anonymous()]));
+        assert_eq!(merged.session_expires_in, DEFAULT_SESSION_EXPIRES_IN);
+    }
+
+    // Test invalid default resources
+    #[test]
+    fn test_invalid_default_resources() {
+        let builder = SettingsBuilder::new("example.com", "http://example.com", "some_salt")
+            .default_resources(vec!["not a uri".to_string()]);
+        assert!(builder.build().is_err());
+    }
+
+    #[test]
+    fn test_allowed_addresses() {
+        let allowed = SolPubkey::from_str("Awes4Tr6TX8JDzEhCZY2QVNimT6iD1zWHzf1vNyGvpLM").unwrap();
+        let other = SolPubkey::from([1u8; 32]);
+        let settings = SettingsBuilder::new("example.com", "http://example.com", "some_salt")
+            .build()
+            .unwrap();
+        assert!(settings.is_address_allowed(&other));
+
+        let settings = SettingsBuilder::new("example.com", "http://example.com", "some_salt")
+            .allowed_addresses(vec![allowed])
+            .build()
+            .unwrap();
+        assert!(settings.is_address_allowed(&allowed));
+        assert!(!settings.is_address_allowed(&other));
+
+        SETTINGS.set(Some(Rc::new(settings)));
+        assert!(update_allowed_addresses(vec!["not base58!".to_string()]).is_err());
+        update_allowed_addresses(vec![other.to_string()]).unwrap();
+        let settings = SETTINGS.with_borrow(|s| s.clone()).unwrap();
+        assert!(settings.is_address_allowed(&other));
+        assert!(!settings.is_address_allowed(&allowed));
+    }
+
+    // Test invalid auto prune interval
+    #[test]
+    fn test_invalid_auto_prune_interval() {
+        let builder = SettingsBuilder::new("example.com", "http://example.com", "some_salt")
+            .auto_prune_interval_ns(0);
+        assert!(builder.build().is_err());
+    }
+
+    // Test invalid max pending logins
+    #[test]
+    fn test_invalid_max_pending_logins() {
+        let builder = SettingsBuilder::new("example.com", "http://example.com", "some_salt")
+            .max_pending_logins(0);
+        assert!(builder.build().is_err());
+    }
+
+    // Test invalid prepare login rate limit
+    #[test]
+    fn test_invalid_prepare_login_rate_limit() {
+        let builder = SettingsBuilder::new("example.com", "http://example.com", "some_salt")
+            .prepare_login_rate_limit(0, 60_000_000_000);
+        assert!(builder.build().is_err());
+        let builder = SettingsBuilder::new("example.com", "http://example.com", "some_salt")
+            .prepare_login_rate_limit(10, 0);
+        assert!(builder.build().is_err());
+    }
+
     // Test invalid statement
     #[test]
     fn test_invalid_statement() {
@@ -355,6 +1495,69 @@ mod tests {
         assert!(builder.build().is_err());
     }
 
+    // Test sign in expires in is not less than session expires in
+    #[test]
+    fn test_sign_in_expires_in_exceeds_session() {
+        let builder = SettingsBuilder::new("example.com", "http://example.com", "some_salt")
+            .sign_in_expires_in(20_000_000_000)
+            .session_expires_in(10_000_000_000);
+        assert_eq!(
+            builder.build().unwrap_err(),
+            SettingsError::InvalidSignInExpiresIn(20_000_000_000).to_string()
+        );
+    }
+
+    // Test per-field validation
+    #[test]
+    fn test_try_setters() {
+        let builder = || SettingsBuilder::new("example.com", "http://example.com", "some_salt");
+        assert_eq!(
+            builder().try_domain("").err(),
+            Some(SettingsError::InvalidDomain(String::new()))
+        );
+        assert_eq!(
+            builder().try_uri("not a uri").err(),
+            Some(SettingsError::InvalidUri("not a uri".to_string()))
+        );
+        assert_eq!(
+            builder().try_sign_in_expires_in(0).err(),
+            Some(SettingsError::InvalidSignInExpiresIn(0))
+        );
+        assert_eq!(
+            builder().try_session_expires_in(0).err(),
+            Some(SettingsError::InvalidSessionExpiresIn(0))
+        );
+
+        let settings = builder()
+            .try_domain("sub.example.com")
+            .and_then(|b| b.try_uri("https://sub.example.com"))
+            .unwrap()
+            .build()
+            .unwrap();
+        assert_eq!(settings.domain, "sub.example.com");
+    }
+
+    // Test max session expires in is less than session expires in
+    #[test]
+    fn test_max_session_expires_in_too_small() {
+        let builder = SettingsBuilder::new("example.com", "http://example.com", "some_salt")
+            .session_expires_in(20_000_000_000)
+            .max_session_expires_in(10_000_000_000);
+        assert!(builder.build().is_err());
+    }
+
+    #[test]
+    fn test_default_not_before_offset() {
+        let builder = SettingsBuilder::new("example.com", "http://example.com", "some_salt")
+            .sign_in_expires_in(60_000_000_000)
+            .default_not_before_offset(59_999_999_999);
+        assert!(builder.build().is_ok());
+        let builder = SettingsBuilder::new("example.com", "http://example.com", "some_salt")
+            .sign_in_expires_in(60_000_000_000)
+            .default_not_before_offset(60_000_000_000);
+        assert!(builder.build().is_err());
+    }
+
     // Test session expires in is zero
     #[test]
     fn test_session_expires_in_zero() {
@@ -546,6 +1749,83 @@ mod tests {
         assert!(builder.build().is_err());
     }
 
+    #[cfg(feature = "toml")]
+    #[test]
+    fn test_from_toml() {
+        let settings = Settings::from_toml(
+            r#"
+            domain = "example.com"
+            uri = "http://example.com"
+            salt = "some_salt"
+            chain_id = "devnet"
+            runtime_features = ["include_uri_in_seed"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(settings.domain, "example.com");
+        assert_eq!(settings.chain_id, "devnet");
+        assert_eq!(settings.scheme, DEFAULT_SCHEME);
+        assert_eq!(
+            settings.runtime_features,
+            Some(vec![RuntimeFeature::IncludeUriInSeed])
+        );
+    }
+
+    #[cfg(feature = "toml")]
+    #[test]
+    fn test_from_toml_invalid() {
+        assert!(Settings::from_toml("domain = ").is_err());
+        assert!(Settings::from_toml(
+            r#"
+            domain = "example.com"
+            uri = "http://example.com"
+            salt = ""
+            "#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_reload_settings() {
+        let settings = SettingsBuilder::new("example.com", "http://example.com", "some_salt")
+            .build()
+            .unwrap();
+        reload_settings(settings).unwrap();
+
+        let mut settings = SettingsBuilder::new("example.org", "http://example.org", "some_salt")
+            .build()
+            .unwrap();
+        settings.statement = "Invalid\nStatement".to_string();
+        assert!(reload_settings(settings.clone()).is_err());
+        SETTINGS.with_borrow(|s| assert_eq!(s.as_ref().unwrap().domain, "example.com"));
+
+        settings.statement = "Valid statement".to_string();
+        reload_settings(settings).unwrap();
+        SETTINGS.with_borrow(|s| assert_eq!(s.as_ref().unwrap().domain, "example.org"));
+    }
+
+    #[test]
+    fn test_reload_settings_while_reading() {
+        let settings = SettingsBuilder::new("example.com", "http://example.com", "some_salt")
+            .build()
+            .unwrap();
+        reload_settings(settings).unwrap();
+
+        let domain = crate::with_settings!(|settings: &Settings| {
+            let new_settings =
+                SettingsBuilder::new("example.org", "http://example.org", "some_salt")
+                    .build()
+                    .unwrap();
+            reload_settings(new_settings).unwrap();
+            settings.domain.clone()
+        });
+        assert_eq!(domain, "example.com");
+        assert_eq!(
+            crate::with_settings!(|settings: &Settings| settings.domain.clone()),
+            "example.org"
+        );
+    }
+
     // Test Domain with International Characters
     #[test]
     fn test_domain_with_international_characters() {
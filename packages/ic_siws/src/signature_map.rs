@@ -1,10 +1,14 @@
+use candid::CandidType;
 use ic_certified_map::{leaf_hash, AsHashTree, Hash, HashTree, RbTree};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
 use std::borrow::Cow;
-use std::collections::BinaryHeap;
+use std::collections::{BTreeMap, BinaryHeap, HashMap};
+use std::fmt;
 
 use crate::time::get_current_time;
 
-const DELEGATION_SIGNATURE_EXPIRES_AT: u64 = 60 * 1_000_000_000; // 1 minute
+pub(crate) const DELEGATION_SIGNATURE_EXPIRES_AT: u64 = 60 * 1_000_000_000; // 1 minute
 
 #[derive(Default)]
 struct Unit;
@@ -22,6 +26,7 @@ impl AsHashTree for Unit {
 struct SigExpiration {
     seed_hash: Hash,
     delegation_hash: Hash,
+    inserted_at: u64,
     signature_expires_at: u64,
 }
 
@@ -39,17 +44,248 @@ impl PartialOrd for SigExpiration {
     }
 }
 
+#[derive(Debug)]
+pub enum SignatureMapMergeError {
+    KeyConflict { key: Hash },
+}
+
+impl fmt::Display for SignatureMapMergeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SignatureMapMergeError::KeyConflict { key } => write!(
+                f,
+                "Seed hash {} has conflicting delegation hashes",
+                hex::encode(key)
+            ),
+        }
+    }
+}
+
+impl From<SignatureMapMergeError> for String {
+    fn from(error: SignatureMapMergeError) -> Self {
+        error.to_string()
+    }
+}
+
+#[derive(Debug)]
+pub enum SignatureMapError {
+    Full { capacity: usize },
+    DecodeError(String),
+}
+
+impl fmt::Display for SignatureMapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SignatureMapError::Full { capacity } => {
+                write!(
+                    f,
+                    "Signature map is full, the capacity is {} entries",
+                    capacity
+                )
+            }
+            SignatureMapError::DecodeError(e) => write!(f, "Invalid signature map bytes: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for SignatureMapError {}
+
+impl From<SignatureMapError> for String {
+    fn from(error: SignatureMapError) -> Self {
+        error.to_string()
+    }
+}
+
+/// Decides what happens when an entry is added to a [`SignatureMap`] that holds the maximum number of entries,
+/// see [`SignatureMap::with_max_entries`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EvictionPolicy {
+    /// The entry that was added first is removed.
+    #[default]
+    Oldest,
+    /// The entry that was added or used least recently is removed, see [`SignatureMap::mark_used`].
+    LeastRecentlyUsed,
+    /// The entry is not added and [`SignatureMapError::Full`] is returned.
+    ErrorOnFull,
+}
+
+impl EvictionPolicy {
+    // Whether using an entry moves it to the back of the eviction order.
+    fn tracks_use(&self) -> bool {
+        *self == EvictionPolicy::LeastRecentlyUsed
+    }
+}
+
+// The serialized form of a SignatureMap, see SignatureMap::to_bytes. Hashes are stored as byte strings, which
+// CBOR encodes more compactly than arrays.
+#[derive(Serialize, Deserialize)]
+struct SignatureMapSnapshot {
+    max_entries: Option<usize>,
+    eviction_policy: EvictionPolicy,
+    // The entries in eviction order.
+    entries: Vec<(ByteBuf, ByteBuf)>,
+    // Tuples of seed hash, delegation hash, insertion time and signature expiration time.
+    expirations: Vec<(ByteBuf, ByteBuf, u64, u64)>,
+    #[serde(default)]
+    pruned_count: u64,
+    #[serde(default)]
+    health_thresholds: SignatureMapHealthThresholds,
+}
+
+/// Statistics about a [`SignatureMap`] for operators, see [`SignatureMap::stats`].
+#[derive(Debug, Clone, PartialEq, Eq, CandidType, Deserialize)]
+pub struct SignatureMapStats {
+    /// The number of delegation hashes in the map.
+    pub total_entries: usize,
+    /// The number of delegation hashes whose signature has expired but that have not been pruned yet.
+    pub expired_entries: usize,
+    /// The number of signatures pruned over the lifetime of the map.
+    pub pruned_count: u64,
+    /// The time in nanoseconds since the oldest delegation hash in the map was added.
+    pub oldest_entry_age_ns: Option<u64>,
+}
+
+/// The fill level of a [`SignatureMap`], see [`SignatureMap::health_check`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, CandidType, Deserialize)]
+pub enum SignatureMapHealth {
+    Healthy,
+    NearCapacity { pct: u8 },
+    Overloaded,
+}
+
+/// The fill levels, in percent of the maximum number of entries, at which a [`SignatureMap`] is reported as
+/// near capacity or overloaded. Defaults to 80 and 100 percent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignatureMapHealthThresholds {
+    pub near_capacity_pct: u8,
+    pub overloaded_pct: u8,
+}
+
+impl Default for SignatureMapHealthThresholds {
+    fn default() -> Self {
+        Self {
+            near_capacity_pct: 80,
+            overloaded_pct: 100,
+        }
+    }
+}
+
+/// A delegation hash in the [`SignatureMap`] and the time in nanoseconds it was added.
+#[derive(Debug, Clone, PartialEq, Eq, CandidType, Deserialize)]
+pub struct DelegationEntry {
+    pub delegation_hash: Hash,
+    pub inserted_at: u64,
+}
+
 /// The SignatureMap maintains the tree of delegation hashes required for authentication.
 #[derive(Default)]
 pub struct SignatureMap {
     certified_map: RbTree<Hash, RbTree<Hash, Unit>>,
     expiration_queue: BinaryHeap<SigExpiration>,
+
+    // The eviction order of the entries. Every entry gets a number from a monotonic counter when it is added,
+    // or used with the LRU policy. The entry with the lowest number is evicted first.
+    entry_order: HashMap<(Hash, Hash), u64>,
+    eviction_order: BTreeMap<u64, (Hash, Hash)>,
+    next_order: u64,
+
+    max_entries: Option<usize>,
+    eviction_policy: EvictionPolicy,
+
+    pruned_count: u64,
+    health_thresholds: SignatureMapHealthThresholds,
 }
 
 impl SignatureMap {
+    /// Creates an empty `SignatureMap` with space pre-allocated for at least `n` signatures, avoiding
+    /// reallocations of the expiration queue as sessions are added.
+    pub fn with_capacity(n: usize) -> Self {
+        SignatureMap {
+            expiration_queue: BinaryHeap::with_capacity(n),
+            ..Default::default()
+        }
+    }
+
+    /// Creates an empty `SignatureMap` that holds at most `max_entries` delegation hashes. When the map is full,
+    /// `policy` decides whether an entry is evicted or the new entry is rejected. This bounds the memory used by
+    /// the map if many logins happen before expired signatures are pruned.
+    pub fn with_max_entries(max_entries: usize, policy: EvictionPolicy) -> Self {
+        SignatureMap {
+            max_entries: Some(max_entries),
+            eviction_policy: policy,
+            ..Default::default()
+        }
+    }
+
+    /// Returns the number of delegation hashes in the map.
+    pub fn len(&self) -> usize {
+        self.entry_order.len()
+    }
+
+    /// Returns `true` if the map holds no delegation hashes.
+    pub fn is_empty(&self) -> bool {
+        self.entry_order.is_empty()
+    }
+
+    /// Returns the maximum number of delegation hashes the map holds, `usize::MAX` for unbounded maps.
+    pub fn capacity(&self) -> usize {
+        self.max_entries.unwrap_or(usize::MAX)
+    }
+
+    /// Returns `true` if the map holds the maximum number of delegation hashes.
+    pub fn is_full(&self) -> bool {
+        self.len() >= self.capacity()
+    }
+
+    /// Adds the delegation hash for the seed hash. If the map is full, an entry is evicted according to the
+    /// eviction policy.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the map is full and the eviction policy is [`EvictionPolicy::ErrorOnFull`], use
+    /// [`SignatureMap::try_put`] to handle that case.
     pub fn put(&mut self, seed_hash: Hash, delegation_hash: Hash) {
-        let signature_expires_at =
-            get_current_time().saturating_add(DELEGATION_SIGNATURE_EXPIRES_AT);
+        if let Err(e) = self.try_put(seed_hash, delegation_hash) {
+            panic!("Failed to add the delegation hash: {}", e);
+        }
+    }
+
+    /// Works like [`SignatureMap::put`] but returns [`SignatureMapError::Full`] if the map is full and the
+    /// eviction policy is [`EvictionPolicy::ErrorOnFull`].
+    pub fn try_put(
+        &mut self,
+        seed_hash: Hash,
+        delegation_hash: Hash,
+    ) -> Result<(), SignatureMapError> {
+        let key = (seed_hash, delegation_hash);
+        let is_new = !self.entry_order.contains_key(&key);
+        if is_new && self.is_full() {
+            if self.eviction_policy == EvictionPolicy::ErrorOnFull {
+                return Err(SignatureMapError::Full {
+                    capacity: self.capacity(),
+                });
+            }
+            if let Some((_, (seed_hash, delegation_hash))) = self.eviction_order.pop_first() {
+                self.delete(seed_hash, delegation_hash);
+            }
+        }
+        if is_new || self.eviction_policy.tracks_use() {
+            self.track(key);
+        }
+
+        let inserted_at = get_current_time();
+        let signature_expires_at = inserted_at.saturating_add(DELEGATION_SIGNATURE_EXPIRES_AT);
+        self.insert_entry(seed_hash, delegation_hash);
+        self.expiration_queue.push(SigExpiration {
+            seed_hash,
+            delegation_hash,
+            inserted_at,
+            signature_expires_at,
+        });
+        Ok(())
+    }
+
+    fn insert_entry(&mut self, seed_hash: Hash, delegation_hash: Hash) {
         if self.certified_map.get(&seed_hash[..]).is_none() {
             let mut submap = RbTree::new();
             submap.insert(delegation_hash, Unit);
@@ -59,11 +295,137 @@ impl SignatureMap {
                 submap.insert(delegation_hash, Unit);
             });
         }
-        self.expiration_queue.push(SigExpiration {
-            seed_hash,
-            delegation_hash,
-            signature_expires_at,
-        });
+    }
+
+    /// Serializes the map to CBOR, e.g. to save it to stable memory before a canister upgrade. The entries,
+    /// their signature expiration times and eviction order as well as the maximum number of entries and the
+    /// eviction policy are preserved. See [`crate::upgrade`].
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let entries = self
+            .eviction_order
+            .values()
+            .map(|(seed_hash, delegation_hash)| {
+                (
+                    ByteBuf::from(seed_hash.to_vec()),
+                    ByteBuf::from(delegation_hash.to_vec()),
+                )
+            })
+            .collect();
+        // Expirations of deleted entries are left out, they would be skipped by prune_expired anyway.
+        let expirations = self
+            .expiration_queue
+            .iter()
+            .filter(|e| self.contains(e.seed_hash, e.delegation_hash))
+            .map(|e| {
+                (
+                    ByteBuf::from(e.seed_hash.to_vec()),
+                    ByteBuf::from(e.delegation_hash.to_vec()),
+                    e.inserted_at,
+                    e.signature_expires_at,
+                )
+            })
+            .collect();
+        let snapshot = SignatureMapSnapshot {
+            max_entries: self.max_entries,
+            eviction_policy: self.eviction_policy,
+            entries,
+            expirations,
+            pruned_count: self.pruned_count,
+            health_thresholds: self.health_thresholds,
+        };
+        serde_cbor::to_vec(&snapshot).expect("Signature map should be CBOR serializable")
+    }
+
+    /// Restores a map serialized with [`SignatureMap::to_bytes`]. The root hash of the restored map equals the
+    /// root hash of the serialized map.
+    pub fn from_bytes(bytes: &[u8]) -> Result<SignatureMap, SignatureMapError> {
+        let snapshot: SignatureMapSnapshot = serde_cbor::from_slice(bytes)
+            .map_err(|e| SignatureMapError::DecodeError(e.to_string()))?;
+        let to_hash = |bytes: &ByteBuf| -> Result<Hash, SignatureMapError> {
+            bytes
+                .as_slice()
+                .try_into()
+                .map_err(|_| SignatureMapError::DecodeError("Hashes must be 32 bytes".to_string()))
+        };
+
+        let mut map = SignatureMap {
+            max_entries: snapshot.max_entries,
+            eviction_policy: snapshot.eviction_policy,
+            pruned_count: snapshot.pruned_count,
+            health_thresholds: snapshot.health_thresholds,
+            ..Default::default()
+        };
+        for (seed_hash, delegation_hash) in &snapshot.entries {
+            let key = (to_hash(seed_hash)?, to_hash(delegation_hash)?);
+            map.insert_entry(key.0, key.1);
+            map.track(key);
+        }
+        for (seed_hash, delegation_hash, inserted_at, signature_expires_at) in &snapshot.expirations
+        {
+            map.expiration_queue.push(SigExpiration {
+                seed_hash: to_hash(seed_hash)?,
+                delegation_hash: to_hash(delegation_hash)?,
+                inserted_at: *inserted_at,
+                signature_expires_at: *signature_expires_at,
+            });
+        }
+        Ok(map)
+    }
+
+    /// Marks the entry as used, so that it is evicted last with the [`EvictionPolicy::LeastRecentlyUsed`]
+    /// policy. Canisters call this when they return the signature for a delegation.
+    pub fn mark_used(&mut self, seed_hash: Hash, delegation_hash: Hash) {
+        let key = (seed_hash, delegation_hash);
+        if self.eviction_policy.tracks_use() && self.entry_order.contains_key(&key) {
+            self.track(key);
+        }
+    }
+
+    // Moves the entry to the back of the eviction order.
+    fn track(&mut self, key: (Hash, Hash)) {
+        if let Some(order) = self.entry_order.insert(key, self.next_order) {
+            self.eviction_order.remove(&order);
+        }
+        self.eviction_order.insert(self.next_order, key);
+        self.next_order += 1;
+    }
+
+    fn untrack(&mut self, key: (Hash, Hash)) {
+        if let Some(order) = self.entry_order.remove(&key) {
+            self.eviction_order.remove(&order);
+        }
+    }
+
+    /// Merges `other` into this map, for example when aggregating signature maps from several canister
+    /// shards. Signature expiration times are carried over from `other`. Returns
+    /// [`SignatureMapMergeError::KeyConflict`] if both maps contain the same seed hash but `other` holds a
+    /// delegation hash for it that this map does not. The root hash of the merged map is the same as if all
+    /// entries had been added to a single map. The maximum number of entries is not enforced when merging.
+    pub fn merge(mut self, other: SignatureMap) -> Result<SignatureMap, SignatureMapMergeError> {
+        for (seed_hash, other_submap) in other.certified_map.iter() {
+            if let Some(submap) = self.certified_map.get(&seed_hash[..]) {
+                let has_conflict = other_submap
+                    .iter()
+                    .any(|(delegation_hash, _)| submap.get(&delegation_hash[..]).is_none());
+                if has_conflict {
+                    return Err(SignatureMapMergeError::KeyConflict { key: *seed_hash });
+                }
+            }
+        }
+
+        for (seed_hash, other_submap) in other.certified_map.iter() {
+            if self.certified_map.get(&seed_hash[..]).is_none() {
+                let mut submap = RbTree::new();
+                for (delegation_hash, _) in other_submap.iter() {
+                    submap.insert(*delegation_hash, Unit);
+                    self.track((*seed_hash, *delegation_hash));
+                }
+                self.certified_map.insert(*seed_hash, submap);
+            }
+        }
+        self.expiration_queue.extend(other.expiration_queue);
+
+        Ok(self)
     }
 
     pub fn delete(&mut self, seed_hash: Hash, delegation_hash: Hash) {
@@ -75,6 +437,27 @@ impl SignatureMap {
         if is_empty {
             self.certified_map.delete(&seed_hash[..]);
         }
+        self.untrack((seed_hash, delegation_hash));
+    }
+
+    /// Removes all delegation hashes for the seed hash and returns how many were removed.
+    pub fn delete_seed(&mut self, seed_hash: Hash) -> usize {
+        let delegation_hashes: Vec<Hash> =
+            self.certified_map
+                .get(&seed_hash[..])
+                .map_or(vec![], |submap| {
+                    submap
+                        .iter()
+                        .map(|(delegation_hash, _)| *delegation_hash)
+                        .collect()
+                });
+        if !delegation_hashes.is_empty() {
+            self.certified_map.delete(&seed_hash[..]);
+        }
+        for delegation_hash in &delegation_hashes {
+            self.untrack((seed_hash, *delegation_hash));
+        }
+        delegation_hashes.len()
     }
 
     pub fn prune_expired(&mut self, now: u64, max_to_prune: usize) -> usize {
@@ -93,11 +476,57 @@ impl SignatureMap {
                 self.delete(expiration.seed_hash, expiration.delegation_hash);
             }
             num_pruned += 1;
+            self.pruned_count += 1;
         }
 
         num_pruned
     }
 
+    /// Returns statistics about the entries of the map, e.g. to monitor a canister in production.
+    pub fn stats(&self) -> SignatureMapStats {
+        let now = get_current_time();
+        let expired_entries = self
+            .delegations_iter()
+            .filter(|&(_, _, expires_at)| now > expires_at)
+            .count();
+        let oldest_inserted_at = self
+            .expiration_queue
+            .iter()
+            .filter(|e| self.contains(e.seed_hash, e.delegation_hash))
+            .map(|e| e.inserted_at)
+            .min();
+        SignatureMapStats {
+            total_entries: self.len(),
+            expired_entries,
+            pruned_count: self.pruned_count,
+            oldest_entry_age_ns: oldest_inserted_at
+                .map(|inserted_at| now.saturating_sub(inserted_at)),
+        }
+    }
+
+    /// Sets the fill levels at which [`SignatureMap::health_check`] reports the map as near capacity or
+    /// overloaded.
+    pub fn set_health_thresholds(&mut self, thresholds: SignatureMapHealthThresholds) {
+        self.health_thresholds = thresholds;
+    }
+
+    /// Reports how full the map is relative to its maximum number of entries. Maps without a maximum, see
+    /// [`SignatureMap::with_max_entries`], are always healthy.
+    pub fn health_check(&self) -> SignatureMapHealth {
+        let pct = match self.max_entries {
+            None => return SignatureMapHealth::Healthy,
+            Some(0) => 100,
+            Some(max_entries) => (self.len() as u128 * 100 / max_entries as u128).min(100) as u8,
+        };
+        if pct >= self.health_thresholds.overloaded_pct {
+            SignatureMapHealth::Overloaded
+        } else if pct >= self.health_thresholds.near_capacity_pct {
+            SignatureMapHealth::NearCapacity { pct }
+        } else {
+            SignatureMapHealth::Healthy
+        }
+    }
+
     pub fn is_expired(&self, now: u64, seed_hash: Hash, delegation_hash: Hash) -> bool {
         let expiration = self
             .expiration_queue
@@ -109,6 +538,83 @@ impl SignatureMap {
         false
     }
 
+    /// Returns `true` if the map holds the delegation hash for the seed hash.
+    pub fn contains(&self, seed_hash: Hash, delegation_hash: Hash) -> bool {
+        self.certified_map
+            .get(&seed_hash[..])
+            .is_some_and(|submap| submap.get(&delegation_hash[..]).is_some())
+    }
+
+    /// Returns the delegation hashes for the seed hash with the time they were added, e.g. to show users their
+    /// active sessions. Entries are ordered by delegation hash. If a delegation hash was added more than once,
+    /// the latest insertion time is returned.
+    pub fn delegations_for_seed(&self, seed_hash: Hash) -> Vec<DelegationEntry> {
+        let mut entries = BTreeMap::new();
+        for expiration in self.expiration_queue.iter() {
+            if expiration.seed_hash == seed_hash
+                && self.contains(seed_hash, expiration.delegation_hash)
+            {
+                let inserted_at = entries
+                    .entry(expiration.delegation_hash)
+                    .or_insert(expiration.inserted_at);
+                *inserted_at = (*inserted_at).max(expiration.inserted_at);
+            }
+        }
+        entries
+            .into_iter()
+            .map(|(delegation_hash, inserted_at)| DelegationEntry {
+                delegation_hash,
+                inserted_at,
+            })
+            .collect()
+    }
+
+    /// Returns all seed hashes in the map, for admin inspection.
+    pub fn all_seeds(&self) -> Vec<Hash> {
+        self.certified_map
+            .iter()
+            .map(|(seed_hash, _)| *seed_hash)
+            .collect()
+    }
+
+    /// Returns `true` if the map holds at least one delegation hash for the seed hash.
+    pub fn contains_seed(&self, seed_hash: Hash) -> bool {
+        self.certified_map.get(&seed_hash[..]).is_some()
+    }
+
+    /// Returns an iterator over all entries of the map as `(seed_hash, delegation_hash, signature_expires_at)`
+    /// tuples, for example to list the sessions of a canister. Entries are ordered by seed hash and delegation
+    /// hash. If a delegation hash was added more than once, the latest expiration time is returned.
+    pub fn delegations_iter(&self) -> impl Iterator<Item = (Hash, Hash, u64)> {
+        let mut entries = BTreeMap::new();
+        for expiration in self.expiration_queue.iter() {
+            let is_present = self
+                .certified_map
+                .get(&expiration.seed_hash[..])
+                .is_some_and(|submap| submap.get(&expiration.delegation_hash[..]).is_some());
+            if is_present {
+                let expires_at = entries
+                    .entry((expiration.seed_hash, expiration.delegation_hash))
+                    .or_insert(expiration.signature_expires_at);
+                *expires_at = (*expires_at).max(expiration.signature_expires_at);
+            }
+        }
+        entries
+            .into_iter()
+            .map(|((seed_hash, delegation_hash), expires_at)| {
+                (seed_hash, delegation_hash, expires_at)
+            })
+    }
+
+    /// Works like [`SignatureMap::delegations_iter`] but skips entries that have expired at `current_time`.
+    pub fn active_delegations_iter(
+        &self,
+        current_time: u64,
+    ) -> impl Iterator<Item = (Hash, Hash, u64)> {
+        self.delegations_iter()
+            .filter(move |&(_, _, expires_at)| current_time <= expires_at)
+    }
+
     pub fn root_hash(&self) -> Hash {
         self.certified_map.root_hash()
     }
@@ -136,6 +642,180 @@ mod signature_map_tests {
         Hash::from(arr)
     }
 
+    #[test]
+    fn test_with_capacity() {
+        let mut map = SignatureMap::with_capacity(32);
+        assert!(map.expiration_queue.capacity() >= 32);
+        let seed_hash = random_hash();
+        let delegation_hash = random_hash();
+        map.put(seed_hash, delegation_hash);
+        assert!(map.witness(seed_hash, delegation_hash).is_some());
+    }
+
+    #[test]
+    fn test_max_entries() {
+        let entries: Vec<_> = (0..3).map(|_| (random_hash(), random_hash())).collect();
+
+        let mut map = SignatureMap::with_max_entries(2, EvictionPolicy::Oldest);
+        assert_eq!(map.capacity(), 2);
+        map.put(entries[0].0, entries[0].1);
+        map.put(entries[1].0, entries[1].1);
+        assert!(map.is_full());
+        // Adding an existing entry does not evict anything.
+        map.put(entries[0].0, entries[0].1);
+        assert!(map.contains(entries[1].0, entries[1].1));
+        map.put(entries[2].0, entries[2].1);
+        assert_eq!(map.len(), 2);
+        assert!(!map.contains(entries[0].0, entries[0].1));
+        assert!(map.contains(entries[2].0, entries[2].1));
+
+        let mut map = SignatureMap::with_max_entries(1, EvictionPolicy::ErrorOnFull);
+        map.try_put(entries[0].0, entries[0].1).unwrap();
+        assert!(matches!(
+            map.try_put(entries[1].0, entries[1].1),
+            Err(SignatureMapError::Full { capacity: 1 })
+        ));
+        map.delete(entries[0].0, entries[0].1);
+        assert!(map.is_empty());
+        assert!(map.try_put(entries[1].0, entries[1].1).is_ok());
+
+        let map = SignatureMap::default();
+        assert_eq!(map.capacity(), usize::MAX);
+        assert!(!map.is_full());
+    }
+
+    #[test]
+    #[should_panic(expected = "Failed to add the delegation hash")]
+    fn test_put_panics_when_full() {
+        let mut map = SignatureMap::with_max_entries(1, EvictionPolicy::ErrorOnFull);
+        map.put(random_hash(), random_hash());
+        map.put(random_hash(), random_hash());
+    }
+
+    #[test]
+    fn test_least_recently_used() {
+        let entries: Vec<_> = (0..3).map(|_| (random_hash(), random_hash())).collect();
+        let mut map = SignatureMap::with_max_entries(2, EvictionPolicy::LeastRecentlyUsed);
+        map.put(entries[0].0, entries[0].1);
+        map.put(entries[1].0, entries[1].1);
+        map.mark_used(entries[0].0, entries[0].1);
+        map.put(entries[2].0, entries[2].1);
+        assert!(map.contains(entries[0].0, entries[0].1));
+        assert!(!map.contains(entries[1].0, entries[1].1));
+    }
+
+    #[test]
+    fn test_bytes_roundtrip() {
+        let entries: Vec<_> = (0..4).map(|_| (random_hash(), random_hash())).collect();
+        let mut map = SignatureMap::with_max_entries(10, EvictionPolicy::ErrorOnFull);
+        for &(seed_hash, delegation_hash) in &entries {
+            map.put(seed_hash, delegation_hash);
+        }
+        map.put(entries[0].0, random_hash());
+        map.delete(entries[1].0, entries[1].1);
+
+        let restored = SignatureMap::from_bytes(&map.to_bytes()).unwrap();
+        assert_eq!(restored.root_hash(), map.root_hash());
+        assert_eq!(restored.len(), map.len());
+        assert_eq!(restored.capacity(), 10);
+        assert_eq!(
+            restored.delegations_iter().collect::<Vec<_>>(),
+            map.delegations_iter().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            restored.delegations_for_seed(entries[0].0),
+            map.delegations_for_seed(entries[0].0)
+        );
+        assert!(!restored.contains(entries[1].0, entries[1].1));
+
+        assert!(matches!(
+            SignatureMap::from_bytes(b"not cbor"),
+            Err(SignatureMapError::DecodeError(_))
+        ));
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut map = SignatureMap::default();
+        let stats = map.stats();
+        assert_eq!(stats.total_entries, 0);
+        assert!(stats.oldest_entry_age_ns.is_none());
+
+        let seed_hash = random_hash();
+        map.put(seed_hash, random_hash());
+        map.put(seed_hash, random_hash());
+        let stats = map.stats();
+        assert_eq!(stats.total_entries, 2);
+        assert_eq!(stats.expired_entries, 0);
+        assert!(stats.oldest_entry_age_ns.is_some());
+
+        let later = get_current_time() + DELEGATION_SIGNATURE_EXPIRES_AT + 1;
+        assert_eq!(map.prune_expired(later, 10), 2);
+        let stats = map.stats();
+        assert_eq!(stats.total_entries, 0);
+        assert_eq!(stats.pruned_count, 2);
+    }
+
+    #[test]
+    fn test_health_check() {
+        let mut map = SignatureMap::with_max_entries(10, EvictionPolicy::ErrorOnFull);
+        for _ in 0..7 {
+            map.put(random_hash(), random_hash());
+        }
+        assert_eq!(map.health_check(), SignatureMapHealth::Healthy);
+        map.put(random_hash(), random_hash());
+        assert_eq!(
+            map.health_check(),
+            SignatureMapHealth::NearCapacity { pct: 80 }
+        );
+        map.set_health_thresholds(SignatureMapHealthThresholds {
+            near_capacity_pct: 50,
+            overloaded_pct: 80,
+        });
+        assert_eq!(map.health_check(), SignatureMapHealth::Overloaded);
+        assert_eq!(
+            SignatureMap::default().health_check(),
+            SignatureMapHealth::Healthy
+        );
+    }
+
+    #[test]
+    fn test_merge() {
+        let entries: Vec<_> = (0..6).map(|_| (random_hash(), random_hash())).collect();
+        let mut a = SignatureMap::default();
+        let mut b = SignatureMap::default();
+        let mut sequential = SignatureMap::default();
+        for (i, &(seed_hash, delegation_hash)) in entries.iter().enumerate() {
+            if i % 2 == 0 {
+                a.put(seed_hash, delegation_hash);
+            } else {
+                b.put(seed_hash, delegation_hash);
+            }
+            sequential.put(seed_hash, delegation_hash);
+        }
+        // An entry present in both maps is not a conflict.
+        b.put(entries[0].0, entries[0].1);
+
+        let merged = a.merge(b).unwrap();
+        assert_eq!(merged.root_hash(), sequential.root_hash());
+        for &(seed_hash, delegation_hash) in &entries {
+            assert!(merged.witness(seed_hash, delegation_hash).is_some());
+        }
+    }
+
+    #[test]
+    fn test_merge_key_conflict() {
+        let seed_hash = random_hash();
+        let mut a = SignatureMap::default();
+        let mut b = SignatureMap::default();
+        a.put(seed_hash, random_hash());
+        b.put(seed_hash, random_hash());
+        match a.merge(b) {
+            Err(SignatureMapMergeError::KeyConflict { key }) => assert_eq!(key, seed_hash),
+            _ => panic!("Expected a key conflict"),
+        }
+    }
+
     #[test]
     fn test_put_signature() {
         let mut map = SignatureMap::default();
@@ -145,6 +825,70 @@ mod signature_map_tests {
         assert!(map.certified_map.get(&seed_hash[..]).is_some());
     }
 
+    #[test]
+    fn test_contains_seed() {
+        let mut map = SignatureMap::default();
+        let seed_hash = random_hash();
+        assert!(!map.contains_seed(seed_hash));
+        map.put(seed_hash, random_hash());
+        assert!(map.contains_seed(seed_hash));
+    }
+
+    #[test]
+    fn test_delegations_iter() {
+        let mut map = SignatureMap::default();
+        let (seed_hash, delegation_hash) = (random_hash(), random_hash());
+        let (deleted_seed_hash, deleted_delegation_hash) = (random_hash(), random_hash());
+        map.put(seed_hash, delegation_hash);
+        map.put(deleted_seed_hash, deleted_delegation_hash);
+        map.delete(deleted_seed_hash, deleted_delegation_hash);
+
+        let entries: Vec<_> = map.delegations_iter().collect();
+        assert_eq!(entries.len(), 1);
+        let (entry_seed_hash, entry_delegation_hash, expires_at) = entries[0];
+        assert_eq!(entry_seed_hash, seed_hash);
+        assert_eq!(entry_delegation_hash, delegation_hash);
+
+        assert_eq!(map.active_delegations_iter(expires_at).count(), 1);
+        assert_eq!(map.active_delegations_iter(expires_at + 1).count(), 0);
+    }
+
+    #[test]
+    fn test_delete_seed() {
+        let mut map = SignatureMap::default();
+        let seed_hash = random_hash();
+        let other_seed_hash = random_hash();
+        map.put(seed_hash, random_hash());
+        map.put(seed_hash, random_hash());
+        map.put(other_seed_hash, random_hash());
+        assert_eq!(map.delete_seed(seed_hash), 2);
+        assert!(!map.contains_seed(seed_hash));
+        assert!(map.contains_seed(other_seed_hash));
+        assert_eq!(map.delete_seed(seed_hash), 0);
+    }
+
+    #[test]
+    fn test_delegations_for_seed() {
+        let mut map = SignatureMap::default();
+        let seed_hash = random_hash();
+        let other_seed_hash = random_hash();
+        let delegation_hash = random_hash();
+        let before = get_current_time();
+        map.put(seed_hash, delegation_hash);
+        map.put(other_seed_hash, random_hash());
+
+        let entries = map.delegations_for_seed(seed_hash);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].delegation_hash, delegation_hash);
+        assert!(entries[0].inserted_at >= before);
+
+        let mut seeds = map.all_seeds();
+        seeds.sort();
+        let mut expected = vec![seed_hash, other_seed_hash];
+        expected.sort();
+        assert_eq!(seeds, expected);
+    }
+
     #[test]
     fn test_delete_signature() {
         let mut map = SignatureMap::default();
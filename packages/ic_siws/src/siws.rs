@@ -1,29 +1,147 @@
-use crate::{hash, settings::Settings, solana::SolPubkey, time::get_current_time, with_settings};
+use crate::{
+    hash,
+    rand::generate_nonce,
+    settings::{validate_resources, validate_statement, Settings},
+    solana::SolPubkey,
+    time::get_current_time,
+    with_settings,
+};
 use candid::CandidType;
 use ic_certified_map::Hash;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, fmt};
-use time::{macros::format_description, OffsetDateTime};
+use std::{borrow::Cow, collections::HashMap, fmt};
+use time::{format_description::well_known::Rfc3339, macros::format_description, OffsetDateTime};
+
+const MAX_REQUEST_ID_LEN: usize = 4096;
 
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum SiwsMessageError {
     MessageNotFound,
+    AlreadyExists,
+    InvalidNonce,
+    RequestIdTooLong,
+    InvalidRequestId,
+    InvalidStatement,
+    InvalidResource(String),
+    ExpirationTooLong { ttl_ns: u64, max_ttl_ns: u64 },
+    TooManyPendingMessages { max: usize },
 }
 
 impl fmt::Display for SiwsMessageError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             SiwsMessageError::MessageNotFound => write!(f, "Message not found"),
+            SiwsMessageError::AlreadyExists => {
+                write!(f, "A message already exists for this address")
+            }
+            SiwsMessageError::InvalidNonce => {
+                write!(f, "Nonce must be at least 8 alphanumeric characters")
+            }
+            SiwsMessageError::RequestIdTooLong => write!(
+                f,
+                "Request ID must not exceed {} characters",
+                MAX_REQUEST_ID_LEN
+            ),
+            SiwsMessageError::InvalidRequestId => {
+                write!(f, "Request ID must not contain control characters")
+            }
+            SiwsMessageError::InvalidStatement => {
+                write!(f, "Statement must not contain newline characters")
+            }
+            SiwsMessageError::InvalidResource(e) => write!(f, "{}", e),
+            SiwsMessageError::ExpirationTooLong { ttl_ns, max_ttl_ns } => write!(
+                f,
+                "Expiration of {} ns exceeds the maximum of {} ns",
+                ttl_ns, max_ttl_ns
+            ),
+            SiwsMessageError::TooManyPendingMessages { max } => {
+                write!(f, "Too many pending messages, the maximum is {}", max)
+            }
         }
     }
 }
 
+impl std::error::Error for SiwsMessageError {}
+
 impl From<SiwsMessageError> for String {
     fn from(error: SiwsMessageError) -> Self {
         error.to_string()
     }
 }
 
+#[derive(Debug)]
+pub enum SiwsParseError {
+    InvalidFormat(String),
+    MissingField(String),
+    InvalidTimestamp(String),
+}
+
+impl fmt::Display for SiwsParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SiwsParseError::InvalidFormat(e) => write!(f, "Invalid message format: {}", e),
+            SiwsParseError::MissingField(field) => write!(f, "Missing field: {}", field),
+            SiwsParseError::InvalidTimestamp(e) => write!(f, "Invalid timestamp: {}", e),
+        }
+    }
+}
+
+impl From<SiwsParseError> for String {
+    fn from(error: SiwsParseError) -> Self {
+        error.to_string()
+    }
+}
+
+#[cfg(feature = "bech32")]
+#[derive(Debug)]
+pub enum Bech32Error {
+    CborError(String),
+    EncodeError(String),
+}
+
+#[cfg(feature = "bech32")]
+impl fmt::Display for Bech32Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Bech32Error::CborError(e) => write!(f, "CBOR error: {}", e),
+            Bech32Error::EncodeError(e) => write!(f, "Bech32 encode error: {}", e),
+        }
+    }
+}
+
+#[cfg(feature = "bech32")]
+impl From<Bech32Error> for String {
+    fn from(error: Bech32Error) -> Self {
+        error.to_string()
+    }
+}
+
+#[cfg(feature = "cbor")]
+#[derive(Debug)]
+pub enum CborError {
+    DecodeError(String),
+}
+
+#[cfg(feature = "cbor")]
+impl fmt::Display for CborError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CborError::DecodeError(e) => write!(f, "CBOR decode error: {}", e),
+        }
+    }
+}
+
+#[cfg(feature = "cbor")]
+impl From<CborError> for String {
+    fn from(error: CborError) -> Self {
+        error.to_string()
+    }
+}
+
+#[cfg(feature = "bech32")]
+const BECH32_HRP: &str = "siws";
+
 /// Constructs a new [`SiwsMessage`] for a given Solana address using the settings defined in the
 /// global [`Settings`] struct.
 ///
@@ -51,7 +169,7 @@ pub struct SiwsMessage {
     // Current version of the message.
     pub version: u32,
 
-    // Chain ID to which the session is bound, optional
+    // Chain ID to which the session is bound, a Solana cluster name such as "mainnet" or "solana:mainnet"; optional
     pub chain_id: String,
 
     // Randomized token used to prevent replay attacks
@@ -62,60 +180,294 @@ pub struct SiwsMessage {
 
     /// Timestamp in nanoseconds
     pub expiration_time: u64,
+
+    /// Timestamp in nanoseconds before which the message is not valid, optional
+    pub not_before: Option<u64>,
+
+    // System-specific identifier used to correlate the message with an application-level request, optional
+    pub request_id: Option<String>,
+
+    // List of RFC 3986 URIs the user wishes to have resolved as part of the authentication; optional
+    pub resources: Vec<String>,
 }
 
 impl SiwsMessage {
+    /// Creates a SIWS message using the settings the library was initialized with, see
+    /// [`SiwsMessage::with_settings`].
     pub fn new(pubkey: &SolPubkey, nonce: &str) -> SiwsMessage {
+        with_settings!(|settings: &Settings| SiwsMessage::with_settings(pubkey, nonce, settings))
+    }
+
+    /// Creates a SIWS message using the provided `settings` instead of the settings the library was initialized
+    /// with, e.g. in tests or tools that don't call [`crate::init`]. The message is issued at the current time.
+    pub fn with_settings(pubkey: &SolPubkey, nonce: &str, settings: &Settings) -> SiwsMessage {
         let current_time = get_current_time();
-        with_settings!(|settings: &Settings| {
-            SiwsMessage {
-                domain: settings.domain.clone(),
-                address: pubkey.to_string(),
-                statement: settings.statement.clone(),
-                uri: settings.uri.clone(),
-                version: 1,
-                chain_id: settings.chain_id.clone(),
-                nonce: nonce.to_string(),
-                issued_at: get_current_time(),
-                expiration_time: current_time.saturating_add(settings.sign_in_expires_in),
-            }
-        })
+        SiwsMessage {
+            domain: settings.domain.clone(),
+            address: pubkey.to_string(),
+            statement: settings.render_statement(&pubkey.to_string()),
+            uri: settings.uri.clone(),
+            version: 1,
+            chain_id: settings.chain_id.clone(),
+            nonce: nonce.to_string(),
+            issued_at: current_time,
+            expiration_time: current_time.saturating_add(settings.sign_in_expires_in),
+            not_before: settings
+                .default_not_before_offset
+                .map(|offset| current_time.saturating_add(offset)),
+            request_id: None,
+            resources: settings.default_resources.clone(),
+        }
+    }
+
+    /// Checks if the SIWS message has passed its expiration time. Use [`SiwsMessage::is_valid_now`] to also
+    /// check the `not_before` time.
+    pub fn is_expired(&self) -> bool {
+        get_current_time() > self.expiration_time
+    }
+
+    /// Returns `true` if the message was issued at most `max_age_ns` nanoseconds ago and has not expired, for
+    /// applications that reject old messages before they expire.
+    pub fn is_fresh(&self, max_age_ns: u64) -> bool {
+        get_current_time().saturating_sub(self.issued_at) <= max_age_ns && !self.is_expired()
+    }
+
+    /// Returns `true` if the current time is before the `not_before` time of the message.
+    pub fn is_not_yet_valid(&self) -> bool {
+        self.is_before_not_before(get_current_time())
     }
 
     /// Checks if the SIWS message is currently valid.
     ///
     /// # Returns
     ///
-    /// `true` if the message is within its valid time period, `false` otherwise.
-    pub fn is_expired(&self) -> bool {
-        let current_time = get_current_time();
-        self.issued_at < current_time || current_time > self.expiration_time
+    /// `true` if the message is neither expired nor before its `not_before` time, `false` otherwise.
+    pub fn is_valid_now(&self) -> bool {
+        !self.is_expired() && !self.is_not_yet_valid()
     }
-}
 
-impl fmt::Display for SiwsMessage {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let json = serde_json::to_string(self).map_err(|_| fmt::Error)?;
-        write!(f, "{}", json)
+    /// Returns `true` if `current_time` is before the `not_before` time of the message.
+    pub fn is_before_not_before(&self, current_time: u64) -> bool {
+        self.not_before
+            .is_some_and(|not_before| current_time < not_before)
     }
-}
 
-impl From<SiwsMessage> for String {
-    fn from(val: SiwsMessage) -> Self {
+    /// Checks if the SIWS message was created for the provided Solana public key.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the address embedded in the message matches the base58 encoded `pubkey`, `false` otherwise.
+    pub fn is_for_address(&self, pubkey: &SolPubkey) -> bool {
+        self.address == pubkey.to_string()
+    }
+
+    /// Trims leading and trailing whitespace from all string fields of the message, including the resources.
+    pub fn sanitize(mut self) -> Self {
+        self.domain = self.domain.trim().to_string();
+        self.address = self.address.trim().to_string();
+        self.statement = self.statement.trim().to_string();
+        self.uri = self.uri.trim().to_string();
+        self.chain_id = self.chain_id.trim().to_string();
+        self.nonce = self.nonce.trim().to_string();
+        self.request_id = self.request_id.map(|r| r.trim().to_string());
+        self.resources = self
+            .resources
+            .iter()
+            .map(|r| r.trim().to_string())
+            .collect();
+        self
+    }
+
+    /// Replaces the nonce of the message, for example with a nonce proposed by the client. The nonce must be at
+    /// least 8 alphanumeric characters.
+    pub fn with_nonce(mut self, nonce: impl Into<String>) -> Result<Self, SiwsMessageError> {
+        let nonce = nonce.into();
+        if nonce.len() < 8 || !nonce.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(SiwsMessageError::InvalidNonce);
+        }
+        self.nonce = nonce;
+        Ok(self)
+    }
+
+    /// Attaches a request ID to the message, allowing relying parties to correlate the message with an
+    /// application-level request. The request ID must not exceed 4096 characters or contain control characters,
+    /// which could inject extra lines into the message shown to the user.
+    pub fn with_request_id(
+        mut self,
+        request_id: impl Into<String>,
+    ) -> Result<Self, SiwsMessageError> {
+        let request_id = request_id.into();
+        if request_id.chars().count() > MAX_REQUEST_ID_LEN {
+            return Err(SiwsMessageError::RequestIdTooLong);
+        }
+        if request_id.chars().any(char::is_control) {
+            return Err(SiwsMessageError::InvalidRequestId);
+        }
+        self.request_id = Some(request_id);
+        Ok(self)
+    }
+
+    /// Sets the expiration time of the message to `ttl_ns` nanoseconds from now, for example to give users on
+    /// slow devices more time to sign. The TTL can not exceed `sign_in_expires_in` from the settings.
+    pub fn set_expiration_from_now(&mut self, ttl_ns: u64) -> Result<(), SiwsMessageError> {
+        let max_ttl_ns = with_settings!(|settings: &Settings| settings.sign_in_expires_in);
+        if ttl_ns > max_ttl_ns {
+            return Err(SiwsMessageError::ExpirationTooLong { ttl_ns, max_ttl_ns });
+        }
+        self.expiration_time = get_current_time().saturating_add(ttl_ns);
+        Ok(())
+    }
+
+    /// Works like [`SiwsMessage::set_expiration_from_now`] but returns the updated message.
+    pub fn with_expiration_from_now(mut self, ttl_ns: u64) -> Result<Self, SiwsMessageError> {
+        self.set_expiration_from_now(ttl_ns)?;
+        Ok(self)
+    }
+
+    /// Replaces the nonce of the message with a freshly generated one.
+    pub fn regenerate_nonce(&mut self) {
+        self.nonce = generate_nonce();
+    }
+
+    /// Returns `true` if the message is bound to Solana mainnet.
+    pub fn chain_id_is_solana_mainnet(&self) -> bool {
+        self.chain_id == "mainnet" || self.chain_id == "solana:mainnet"
+    }
+
+    /// Returns `true` if the message is bound to Solana testnet.
+    pub fn chain_id_is_solana_testnet(&self) -> bool {
+        self.chain_id == "testnet" || self.chain_id == "solana:testnet"
+    }
+
+    /// Returns `true` if the message is bound to Solana devnet.
+    pub fn chain_id_is_solana_devnet(&self) -> bool {
+        self.chain_id == "devnet" || self.chain_id == "solana:devnet"
+    }
+
+    /// Returns `true` if the message is bound to one of the public Solana clusters: mainnet, testnet or devnet.
+    pub fn chain_id_is_solana(&self) -> bool {
+        self.chain_id_is_solana_mainnet()
+            || self.chain_id_is_solana_testnet()
+            || self.chain_id_is_solana_devnet()
+    }
+
+    /// Some clients embed resource URIs in the statement, one per line prefixed with `"- "`. Splits such a
+    /// statement into the clean statement and the list of resources.
+    pub fn split_statement_and_resources(&self) -> (String, Vec<String>) {
+        let mut statement_lines = vec![];
+        let mut resources = vec![];
+        for line in self.statement.lines() {
+            match line.strip_prefix("- ") {
+                Some(resource) => resources.push(resource.to_string()),
+                None => statement_lines.push(line),
+            }
+        }
+        (statement_lines.join("\n"), resources)
+    }
+
+    /// The inverse of [`SiwsMessage::split_statement_and_resources`]. Combines a statement and a list of
+    /// resources into a single statement with the resources appended, one per line prefixed with `"- "`.
+    pub fn merge_statement_and_resources(statement: &str, resources: &[String]) -> String {
+        let mut lines = vec![];
+        if !statement.is_empty() {
+            lines.push(statement.to_string());
+        }
+        lines.extend(resources.iter().map(|r| format!("- {}", r)));
+        lines.join("\n")
+    }
+
+    /// Serializes the SIWS message to YAML. Field names and types match the JSON representation, so
+    /// JSON and YAML representations are interchangeable. Requires the `yaml` feature.
+    #[cfg(feature = "yaml")]
+    pub fn to_yaml(&self) -> Result<String, serde_yaml::Error> {
+        serde_yaml::to_string(self)
+    }
+
+    /// Deserializes a SIWS message from YAML. Requires the `yaml` feature.
+    #[cfg(feature = "yaml")]
+    pub fn from_yaml(s: &str) -> Result<SiwsMessage, SiwsParseError> {
+        serde_yaml::from_str(s).map_err(|e| SiwsParseError::InvalidFormat(e.to_string()))
+    }
+
+    /// Encodes the SIWS message as CBOR wrapped in a bech32m string with the human-readable part `siws`. The
+    /// compact encoding is suitable for sharing the message via QR codes for offline signing. Requires the
+    /// `bech32` feature.
+    #[cfg(feature = "bech32")]
+    pub fn to_bech32(&self) -> Result<String, Bech32Error> {
+        let data = serde_cbor::to_vec(self).map_err(|e| Bech32Error::CborError(e.to_string()))?;
+        let hrp = bech32::Hrp::parse_unchecked(BECH32_HRP);
+        bech32::encode::<bech32::Bech32m>(hrp, &data)
+            .map_err(|e| Bech32Error::EncodeError(e.to_string()))
+    }
+
+    /// Decodes a SIWS message from a bech32m string created by [`SiwsMessage::to_bech32`]. Requires the
+    /// `bech32` feature.
+    #[cfg(feature = "bech32")]
+    pub fn from_bech32(s: &str) -> Result<SiwsMessage, SiwsParseError> {
+        let (hrp, data) =
+            bech32::decode(s).map_err(|e| SiwsParseError::InvalidFormat(e.to_string()))?;
+        if hrp.as_str() != BECH32_HRP {
+            return Err(SiwsParseError::InvalidFormat(format!(
+                "Unexpected human-readable part: {}",
+                hrp
+            )));
+        }
+        serde_cbor::from_slice(&data).map_err(|e| SiwsParseError::InvalidFormat(e.to_string()))
+    }
+
+    /// Encodes the message as CBOR, a compact binary format. All fields are included, optional fields that are
+    /// not set are encoded as null. Requires the `cbor` feature.
+    #[cfg(feature = "cbor")]
+    pub fn to_cbor(&self) -> Vec<u8> {
+        serde_cbor::to_vec(self).expect("SIWS message should be CBOR serializable")
+    }
+
+    /// Decodes a SIWS message created by [`SiwsMessage::to_cbor`]. Requires the `cbor` feature.
+    #[cfg(feature = "cbor")]
+    pub fn from_cbor(bytes: &[u8]) -> Result<SiwsMessage, CborError> {
+        serde_cbor::from_slice(bytes).map_err(|e| CborError::DecodeError(e.to_string()))
+    }
+
+    /// Returns the number of whitespace separated words in the statement.
+    pub fn statement_word_count(&self) -> usize {
+        self.statement.split_whitespace().count()
+    }
+
+    /// Returns the number of characters in the statement.
+    pub fn statement_char_count(&self) -> usize {
+        self.statement.chars().count()
+    }
+
+    /// Returns the statement shortened to `max_chars` characters, the last three of which are `"..."`, or the
+    /// full statement if it is not longer than `max_chars`. If `max_chars` is less than 4, the statement is cut
+    /// off without the ellipsis. Useful for wallets with small screens.
+    pub fn statement_truncated(&self, max_chars: usize) -> String {
+        if self.statement_char_count() <= max_chars {
+            return self.statement.clone();
+        }
+        if max_chars <= 3 {
+            return self.statement.chars().take(max_chars).collect();
+        }
+        let truncated: String = self.statement.chars().take(max_chars - 3).collect();
+        format!("{}...", truncated)
+    }
+
+    /// Returns the SIWS message in the multi-line format that is presented to the user for signing.
+    pub fn to_display_string(&self) -> String {
         // Custom date format to match the JS ISO 8601 format that has less precision than the default Rfc3339 format.
         let js_iso_format = format_description!(
             "[year]-[month]-[day]T[hour]:[minute]:[second].[subsecond digits:3]Z"
         );
 
         let issued_at_datetime =
-            OffsetDateTime::from_unix_timestamp_nanos(val.issued_at as i128).unwrap();
+            OffsetDateTime::from_unix_timestamp_nanos(self.issued_at as i128).unwrap();
         let issued_at_iso_8601 = issued_at_datetime.format(&js_iso_format).unwrap();
 
         let expiration_datetime =
-            OffsetDateTime::from_unix_timestamp_nanos(val.expiration_time as i128).unwrap();
+            OffsetDateTime::from_unix_timestamp_nanos(self.expiration_time as i128).unwrap();
         let expiration_iso_8601 = expiration_datetime.format(&js_iso_format).unwrap();
 
-        format!(
+        let mut message = format!(
             "{domain} wants you to sign in with your Solana account:\n\
             {address}\n\
             \n\
@@ -127,19 +479,304 @@ impl From<SiwsMessage> for String {
             Nonce: {nonce}\n\
             Issued At: {issued_at_iso_8601}\n\
             Expiration Time: {expiration_iso_8601}",
-            domain = val.domain,
-            address = val.address,
-            statement = val.statement,
-            uri = val.uri,
-            version = val.version,
-            chain_id = val.chain_id,
-            nonce = val.nonce,
-        )
+            domain = self.domain,
+            address = self.address,
+            statement = self.statement,
+            uri = self.uri,
+            version = self.version,
+            chain_id = self.chain_id,
+            nonce = self.nonce,
+        );
+
+        if let Some(not_before) = self.not_before {
+            let not_before_datetime =
+                OffsetDateTime::from_unix_timestamp_nanos(not_before as i128).unwrap();
+            let not_before_iso_8601 = not_before_datetime.format(&js_iso_format).unwrap();
+            message.push_str(&format!("\nNot Before: {}", not_before_iso_8601));
+        }
+
+        if let Some(request_id) = &self.request_id {
+            message.push_str(&format!("\nRequest ID: {}", request_id));
+        }
+
+        if !self.resources.is_empty() {
+            message.push_str("\nResources:");
+            for resource in self.resources.iter() {
+                message.push_str(&format!("\n- {}", resource));
+            }
+        }
+
+        message
+    }
+
+    /// Returns the JSON representation of the SIWS message.
+    pub fn to_json_string(&self) -> String {
+        serde_json::to_string(self).expect("Failed to serialize SIWS message")
+    }
+
+    /// Returns the SIWS message as a JSON object for template engines such as Handlebars or Tera. Besides the
+    /// message fields, the map contains `issued_at_rfc3339`, `expiration_time_rfc3339` and `address_short`, the
+    /// first and last four characters of the address.
+    pub fn to_json_map(&self) -> serde_json::Map<String, serde_json::Value> {
+        let mut map = match serde_json::to_value(self).expect("Failed to serialize SIWS message") {
+            serde_json::Value::Object(map) => map,
+            _ => unreachable!("SIWS messages serialize to JSON objects"),
+        };
+        map.insert(
+            "issued_at_rfc3339".to_string(),
+            format_timestamp(self.issued_at).into(),
+        );
+        map.insert(
+            "expiration_time_rfc3339".to_string(),
+            format_timestamp(self.expiration_time).into(),
+        );
+        // Count characters rather than bytes, messages parsed from strings may hold any address.
+        let address_short = if self.address.chars().count() > 8 {
+            let head: String = self.address.chars().take(4).collect();
+            let tail: String = self.address.chars().rev().take(4).collect();
+            format!("{}...{}", head, tail.chars().rev().collect::<String>())
+        } else {
+            self.address.clone()
+        };
+        map.insert("address_short".to_string(), address_short.into());
+        map
+    }
+
+    /// Returns the SHA-256 hash of the SIWS message in the format presented to the user for signing.
+    pub fn message_hash(&self) -> Hash {
+        hash::hash_bytes(self.to_display_string())
+    }
+
+    /// Encodes the SIWS message as a Candid blob. The binary format is more compact than JSON and is
+    /// suitable for stable memory, e.g. when implementing `Storable` for `ic_stable_structures`.
+    pub fn to_stable_bytes(&self) -> Vec<u8> {
+        candid::encode_one(self).expect("Failed to encode SIWS message")
+    }
+
+    /// Decodes a SIWS message from a Candid blob created by [`SiwsMessage::to_stable_bytes`].
+    pub fn from_stable_bytes(bytes: &[u8]) -> Result<SiwsMessage, candid::Error> {
+        candid::decode_one(bytes)
+    }
+}
+
+/// Builds a [`SiwsMessage`] with per-message customizations. Fields that are not set are taken from the
+/// settings, the same way as [`SiwsMessage::new`] does.
+///
+/// ```ignore
+/// let message = SiwsMessageBuilder::new()
+///     .statement("Sign in to vote")
+///     .resources(vec!["https://example.com/poll/1".to_string()])
+///     .build(&pubkey)?;
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct SiwsMessageBuilder {
+    statement: Option<String>,
+    nonce: Option<String>,
+    not_before: Option<u64>,
+    request_id: Option<String>,
+    resources: Vec<String>,
+}
+
+impl SiwsMessageBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replaces the statement from the settings. The statement must not contain newline characters.
+    pub fn statement(mut self, statement: &str) -> Self {
+        self.statement = Some(statement.to_string());
+        self
+    }
+
+    /// Sets the nonce of the message. A nonce is generated if none is set.
+    pub fn nonce(mut self, nonce: &str) -> Self {
+        self.nonce = Some(nonce.to_string());
+        self
+    }
+
+    /// Sets the timestamp in nanoseconds before which the message is not valid. Overrides the
+    /// `default_not_before_offset` from the settings.
+    pub fn not_before(mut self, not_before: u64) -> Self {
+        self.not_before = Some(not_before);
+        self
+    }
+
+    pub fn request_id(mut self, request_id: &str) -> Self {
+        self.request_id = Some(request_id.to_string());
+        self
+    }
+
+    /// Adds resources to the message. The resources are added after the `default_resources` from the
+    /// settings.
+    pub fn resources(mut self, resources: Vec<String>) -> Self {
+        self.resources = resources;
+        self
+    }
+
+    /// Builds the message for `pubkey`, validating the customized fields.
+    pub fn build(self, pubkey: &SolPubkey) -> Result<SiwsMessage, SiwsMessageError> {
+        validate_resources(&self.resources).map_err(SiwsMessageError::InvalidResource)?;
+
+        let mut message = SiwsMessage::new(pubkey, &generate_nonce());
+        if let Some(statement) = self.statement {
+            validate_statement(&statement).map_err(|_| SiwsMessageError::InvalidStatement)?;
+            message.statement = statement;
+        }
+        if let Some(nonce) = self.nonce {
+            message = message.with_nonce(nonce)?;
+        }
+        if let Some(not_before) = self.not_before {
+            message.not_before = Some(not_before);
+        }
+        if let Some(request_id) = self.request_id {
+            message = message.with_request_id(request_id)?;
+        }
+        for resource in self.resources {
+            if !message.resources.contains(&resource) {
+                message.resources.push(resource);
+            }
+        }
+
+        Ok(message)
     }
 }
 
+impl fmt::Display for SiwsMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.to_display_string())
+    }
+}
+
+impl From<SiwsMessage> for String {
+    fn from(val: SiwsMessage) -> Self {
+        val.to_display_string()
+    }
+}
+
+const MESSAGE_HEADER_SUFFIX: &str = " wants you to sign in with your Solana account:";
+
+/// Parses a SIWS message from the plaintext format that is signed by the wallet, the inverse of
+/// `From<SiwsMessage> for String`. Fields may appear in any order and unknown fields are ignored.
+///
+/// Timestamps are formatted with millisecond precision, so a message round-trips exactly only if its
+/// timestamps are whole milliseconds.
+impl TryFrom<&str> for SiwsMessage {
+    type Error = SiwsParseError;
+
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
+        let mut lines = s.lines();
+
+        let domain = lines
+            .next()
+            .and_then(|line| line.strip_suffix(MESSAGE_HEADER_SUFFIX))
+            .ok_or_else(|| SiwsParseError::InvalidFormat("Invalid header".to_string()))?;
+        let address = lines
+            .next()
+            .filter(|line| !line.is_empty())
+            .ok_or_else(|| SiwsParseError::MissingField("Address".to_string()))?;
+        if lines.next() != Some("") {
+            return Err(SiwsParseError::InvalidFormat(
+                "Expected an empty line after the address".to_string(),
+            ));
+        }
+
+        // The statement spans all lines up to the next empty line.
+        let statement = lines
+            .by_ref()
+            .take_while(|line| !line.is_empty())
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        let mut uri = None;
+        let mut version = None;
+        let mut chain_id = None;
+        let mut nonce = None;
+        let mut issued_at = None;
+        let mut expiration_time = None;
+        let mut not_before = None;
+        let mut request_id = None;
+        let mut resources = vec![];
+        let mut in_resources = false;
+
+        for line in lines {
+            if in_resources {
+                if let Some(resource) = line.strip_prefix("- ") {
+                    resources.push(resource.to_string());
+                    continue;
+                }
+                in_resources = false;
+            }
+            if line == "Resources:" {
+                in_resources = true;
+                continue;
+            }
+            let Some((key, value)) = line.split_once(": ") else {
+                continue;
+            };
+            match key {
+                "URI" => uri = Some(value.to_string()),
+                "Version" => {
+                    version = Some(value.parse::<u32>().map_err(|_| {
+                        SiwsParseError::InvalidFormat(format!("Invalid version: {}", value))
+                    })?)
+                }
+                "Chain ID" => chain_id = Some(value.to_string()),
+                "Nonce" => nonce = Some(value.to_string()),
+                "Issued At" => issued_at = Some(parse_timestamp(value)?),
+                "Expiration Time" => expiration_time = Some(parse_timestamp(value)?),
+                "Not Before" => not_before = Some(parse_timestamp(value)?),
+                "Request ID" => request_id = Some(value.to_string()),
+                // Unknown fields are tolerated.
+                _ => (),
+            }
+        }
+
+        let missing = |field: &str| SiwsParseError::MissingField(field.to_string());
+        Ok(SiwsMessage {
+            domain: domain.to_string(),
+            address: address.to_string(),
+            statement,
+            uri: uri.ok_or_else(|| missing("URI"))?,
+            version: version.ok_or_else(|| missing("Version"))?,
+            chain_id: chain_id.ok_or_else(|| missing("Chain ID"))?,
+            nonce: nonce.ok_or_else(|| missing("Nonce"))?,
+            issued_at: issued_at.ok_or_else(|| missing("Issued At"))?,
+            expiration_time: expiration_time.ok_or_else(|| missing("Expiration Time"))?,
+            not_before,
+            request_id,
+            resources,
+        })
+    }
+}
+
+/// Parses a SIWS message from the signed plaintext format, the same way as the `TryFrom<&str>` implementation.
+impl std::str::FromStr for SiwsMessage {
+    type Err = SiwsParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        SiwsMessage::try_from(s)
+    }
+}
+
+/// Parses an RFC 3339 timestamp into nanoseconds since the UNIX epoch.
+fn parse_timestamp(value: &str) -> Result<u64, SiwsParseError> {
+    let datetime = OffsetDateTime::parse(value, &Rfc3339)
+        .map_err(|_| SiwsParseError::InvalidTimestamp(value.to_string()))?;
+    u64::try_from(datetime.unix_timestamp_nanos())
+        .map_err(|_| SiwsParseError::InvalidTimestamp(value.to_string()))
+}
+
+/// Formats nanoseconds since the UNIX epoch as an RFC 3339 timestamp.
+fn format_timestamp(value: u64) -> String {
+    OffsetDateTime::from_unix_timestamp_nanos(value as i128)
+        .unwrap()
+        .format(&Rfc3339)
+        .unwrap()
+}
+
 /// The SiwsMessageMap map hash is the hash of the caller pubkey and the message nonce.
 /// This ensures every call to `siws_prepare_login` leads to one new copy of the SIWS message being stored.
+/// Leading and trailing whitespace in the nonce is ignored.
 pub fn siws_message_map_hash(pubkey: &SolPubkey, nonce: &str) -> Hash {
     let mut bytes: Vec<u8> = vec![];
 
@@ -147,54 +784,247 @@ pub fn siws_message_map_hash(pubkey: &SolPubkey, nonce: &str) -> Hash {
     bytes.push(pubkey_bytes.len() as u8);
     bytes.extend(pubkey_bytes);
 
-    let nonce_bytes = nonce.as_bytes();
+    let nonce_bytes = nonce.trim().as_bytes();
     bytes.push(nonce_bytes.len() as u8);
     bytes.extend(nonce_bytes);
 
     hash::hash_bytes(bytes)
 }
 
+// With the `cbor` feature, pending messages are stored CBOR encoded to reduce heap usage.
+#[cfg(feature = "cbor")]
+type StoredMessage = Vec<u8>;
+#[cfg(not(feature = "cbor"))]
+type StoredMessage = SiwsMessage;
+
+#[cfg(feature = "cbor")]
+fn to_stored(message: SiwsMessage) -> StoredMessage {
+    message.to_cbor()
+}
+
+#[cfg(not(feature = "cbor"))]
+fn to_stored(message: SiwsMessage) -> StoredMessage {
+    message
+}
+
+#[cfg(feature = "cbor")]
+fn from_stored(stored: &StoredMessage) -> Cow<'_, SiwsMessage> {
+    Cow::Owned(SiwsMessage::from_cbor(stored).expect("Stored SIWS message should be valid CBOR"))
+}
+
+#[cfg(not(feature = "cbor"))]
+fn from_stored(stored: &StoredMessage) -> Cow<'_, SiwsMessage> {
+    Cow::Borrowed(stored)
+}
+
+// Key of a message in the SiwsMessageMap, see `siws_message_map_hash`. A newtype so the key can't be
+// confused with the message content hashes in the secondary index.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct SiwsMessageKey(Hash);
+
+impl SiwsMessageKey {
+    fn new(pubkey: &SolPubkey, nonce: &str) -> Self {
+        SiwsMessageKey(siws_message_map_hash(pubkey, nonce))
+    }
+}
+
 /// The SiwsMessageMap is a map of SIWS messages keyed by the Solana address of the user. SIWS messages
 /// are stored in the map during the course of the login process and are removed once the login process
 /// is complete. The map is also pruned periodically to remove expired SIWS messages.
 pub struct SiwsMessageMap {
-    map: HashMap<[u8; 32], SiwsMessage>,
+    map: HashMap<SiwsMessageKey, StoredMessage>,
+
+    // Secondary index from message content hash to the key of the message in `map`.
+    message_hash_index: HashMap<Hash, SiwsMessageKey>,
+
+    // Keys of the messages pending for each address, oldest first.
+    address_index: HashMap<SolPubkey, Vec<SiwsMessageKey>>,
+
+    max_size: Option<usize>,
 }
 
 impl SiwsMessageMap {
     pub fn new() -> SiwsMessageMap {
         SiwsMessageMap {
             map: HashMap::new(),
+            message_hash_index: HashMap::new(),
+            address_index: HashMap::new(),
+            max_size: None,
         }
     }
 
-    /// Removes SIWS messages that have exceeded their time to live.
+    /// Creates a map that holds at most `max_size` SIWS messages, see [`SiwsMessageMap::insert`]. The
+    /// `max_pending` limit passed to `insert` applies in addition to this limit.
+    pub fn with_max_size(max_size: usize) -> SiwsMessageMap {
+        SiwsMessageMap {
+            max_size: Some(max_size),
+            ..SiwsMessageMap::new()
+        }
+    }
+
+    /// Removes SIWS messages that have exceeded their time to live. Messages that are not yet valid because of
+    /// their `not_before` time are kept.
     pub fn prune_expired(&mut self) {
         let current_time = get_current_time();
         self.map
-            .retain(|_, message| message.expiration_time > current_time);
+            .retain(|_, message| from_stored(message).expiration_time > current_time);
+        let map = &self.map;
+        self.message_hash_index
+            .retain(|_, key| map.contains_key(key));
+        self.address_index.retain(|_, keys| {
+            keys.retain(|key| map.contains_key(key));
+            !keys.is_empty()
+        });
+    }
+
+    /// Adds a SIWS message to the map. The message is sanitized before it is stored.
+    ///
+    /// An address can have at most `max_per_address` pending messages. If the address is at the limit, its
+    /// oldest message is evicted, so a user who cancelled the wallet prompt can retry right away. Replacing
+    /// the message for the same nonce does not count against the limit.
+    ///
+    /// When the map is at its maximum size, the smaller of the size given to [`SiwsMessageMap::with_max_size`]
+    /// and `max_pending`, expired messages are pruned first. If the map is still full,
+    /// [`SiwsMessageError::TooManyPendingMessages`] is returned.
+    pub fn insert(
+        &mut self,
+        pubkey: &SolPubkey,
+        message: SiwsMessage,
+        nonce: &str,
+        max_per_address: usize,
+        max_pending: Option<usize>,
+    ) -> Result<(), SiwsMessageError> {
+        let key = SiwsMessageKey::new(pubkey, nonce);
+        let max_size = match (self.max_size, max_pending) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        if let Some(max_size) = max_size {
+            if !self.map.contains_key(&key) && self.map.len() >= max_size {
+                self.prune_expired();
+                if self.map.len() >= max_size {
+                    return Err(SiwsMessageError::TooManyPendingMessages { max: max_size });
+                }
+            }
+        }
+
+        let message = message.sanitize();
+        self.remove_key(pubkey, &key);
+        let keys = self.address_index.entry(*pubkey).or_default();
+        let excess = keys.len().saturating_sub(max_per_address.saturating_sub(1));
+        let evicted: Vec<SiwsMessageKey> = keys.drain(..excess).collect();
+        keys.push(key);
+        for evicted in evicted {
+            if let Some(message) = self.map.remove(&evicted) {
+                self.message_hash_index
+                    .remove(&from_stored(&message).message_hash());
+            }
+        }
+        self.message_hash_index.insert(message.message_hash(), key);
+        self.map.insert(key, to_stored(message));
+        Ok(())
+    }
+
+    /// Returns a cloned SIWS message by the hash of its content, see [`SiwsMessage::message_hash`].
+    pub fn get_by_message_hash(&self, hash: &[u8; 32]) -> Option<SiwsMessage> {
+        let key = self.message_hash_index.get(hash)?;
+        self.map
+            .get(key)
+            .map(|message| from_stored(message).into_owned())
     }
 
-    /// Adds a SIWS message to the map.
-    pub fn insert(&mut self, pubkey: &SolPubkey, message: SiwsMessage, nonce: &str) {
-        let hash = siws_message_map_hash(pubkey, nonce);
-        self.map.insert(hash, message);
+    /// Works like [`SiwsMessageMap::insert`] but returns [`SiwsMessageError::AlreadyExists`] instead of
+    /// storing the message if a non-expired message already exists for the address. Callers can then decide
+    /// to return an error or call `insert` anyway.
+    pub fn try_insert(
+        &mut self,
+        pubkey: &SolPubkey,
+        message: SiwsMessage,
+        nonce: &str,
+        max_per_address: usize,
+        max_pending: Option<usize>,
+    ) -> Result<(), SiwsMessageError> {
+        let current_time = get_current_time();
+        let exists = self
+            .address_index
+            .get(pubkey)
+            .into_iter()
+            .flatten()
+            .filter_map(|key| self.map.get(key))
+            .any(|existing| from_stored(existing).expiration_time > current_time);
+        if exists {
+            return Err(SiwsMessageError::AlreadyExists);
+        }
+        self.insert(pubkey, message, nonce, max_per_address, max_pending)
     }
 
     /// Returns a cloned SIWS message associated with the provided address or an error if the message
     /// does not exist.
     pub fn get(&self, pubkey: &SolPubkey, nonce: &str) -> Result<SiwsMessage, SiwsMessageError> {
-        let hash = siws_message_map_hash(pubkey, nonce);
         self.map
-            .get(&hash)
-            .cloned()
+            .get(&SiwsMessageKey::new(pubkey, nonce))
+            .map(|message| from_stored(message).into_owned())
             .ok_or(SiwsMessageError::MessageNotFound)
     }
 
+    /// Returns the number of SIWS messages in the map, including expired messages that have not been pruned.
+    ///
+    /// ```
+    /// use ic_siws::siws::SiwsMessageMap;
+    ///
+    /// let map = SiwsMessageMap::new();
+    /// assert_eq!(map.len(), 0);
+    /// ```
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    /// Returns `true` if the map holds no SIWS messages.
+    ///
+    /// ```
+    /// use ic_siws::siws::SiwsMessageMap;
+    ///
+    /// let map = SiwsMessageMap::new();
+    /// assert!(map.is_empty());
+    /// ```
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// Returns an iterator over the SIWS messages in the map, e.g. to monitor logins in progress. Each item is
+    /// the address the message was stored for and the message. The messages of an address are returned oldest
+    /// first. The message is borrowed, unless the `cbor` feature is enabled and it has to be decoded.
+    ///
+    /// ```
+    /// use ic_siws::siws::SiwsMessageMap;
+    ///
+    /// let map = SiwsMessageMap::new();
+    /// assert_eq!(map.iter().count(), 0);
+    /// ```
+    pub fn iter(&self) -> impl Iterator<Item = (&SolPubkey, Cow<'_, SiwsMessage>)> {
+        self.address_index.iter().flat_map(move |(pubkey, keys)| {
+            keys.iter()
+                .filter_map(move |key| self.map.get(key))
+                .map(move |message| (pubkey, from_stored(message)))
+        })
+    }
+
     /// Removes the SIWS message associated with the provided address.
     pub fn remove(&mut self, pubkey: &SolPubkey, nonce: &str) {
-        let hash = siws_message_map_hash(pubkey, nonce);
-        self.map.remove(&hash);
+        self.remove_key(pubkey, &SiwsMessageKey::new(pubkey, nonce));
+    }
+
+    fn remove_key(&mut self, pubkey: &SolPubkey, key: &SiwsMessageKey) {
+        if let Some(message) = self.map.remove(key) {
+            self.message_hash_index
+                .remove(&from_stored(&message).message_hash());
+        }
+        if let Some(keys) = self.address_index.get_mut(pubkey) {
+            keys.retain(|existing| existing != key);
+            if keys.is_empty() {
+                self.address_index.remove(pubkey);
+            }
+        }
     }
 }
 
@@ -203,3 +1033,543 @@ impl Default for SiwsMessageMap {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn test_message() -> SiwsMessage {
+        SiwsMessage {
+            domain: "example.com".to_string(),
+            address: "Awes4Tr6TX8JDzEhCZY2QVNimT6iD1zWHzf1vNyGvpLM".to_string(),
+            statement: "SIWS Fields:".to_string(),
+            uri: "http://example.com".to_string(),
+            version: 1,
+            chain_id: "mainnet".to_string(),
+            nonce: "1234567890abcdef".to_string(),
+            issued_at: 1_700_000_000_000_000_000,
+            expiration_time: 1_700_000_300_000_000_000,
+            not_before: None,
+            request_id: None,
+            resources: vec![],
+        }
+    }
+
+    #[test]
+    fn test_split_and_merge_statement_and_resources() {
+        let resources = vec![
+            "ipfs://bafybeiemxf5abjwjbikoz4mc3a3dla6ual3jsgpdr4cjr3oz3evfyavhwq".to_string(),
+            "https://example.com/my-web2-claim.json".to_string(),
+        ];
+        let mut message = test_message();
+        message.statement =
+            SiwsMessage::merge_statement_and_resources("Sign in to Example", &resources);
+        assert_eq!(
+            message.statement,
+            "Sign in to Example\n- ipfs://bafybeiemxf5abjwjbikoz4mc3a3dla6ual3jsgpdr4cjr3oz3evfyavhwq\n- https://example.com/my-web2-claim.json"
+        );
+
+        let (statement, split_resources) = message.split_statement_and_resources();
+        assert_eq!(statement, "Sign in to Example");
+        assert_eq!(split_resources, resources);
+    }
+
+    #[test]
+    fn test_split_statement_without_resources() {
+        let message = test_message();
+        let (statement, resources) = message.split_statement_and_resources();
+        assert_eq!(statement, message.statement);
+        assert!(resources.is_empty());
+    }
+
+    #[test]
+    fn test_stable_bytes_roundtrip() {
+        let mut message = test_message();
+        message.resources = vec!["https://example.com/resource".to_string()];
+        let bytes = message.to_stable_bytes();
+        let decoded = SiwsMessage::from_stable_bytes(&bytes).unwrap();
+        assert_eq!(String::from(decoded), String::from(message));
+        assert!(SiwsMessage::from_stable_bytes(b"not candid").is_err());
+    }
+
+    #[cfg(feature = "bech32")]
+    #[test]
+    fn test_bech32_roundtrip() {
+        let mut message = test_message();
+        message.resources = vec!["https://example.com/resource".to_string()];
+        let encoded = message.to_bech32().unwrap();
+        assert!(encoded.starts_with("siws1"));
+        let decoded = SiwsMessage::from_bech32(&encoded).unwrap();
+        assert_eq!(decoded.to_json_string(), message.to_json_string());
+        assert!(SiwsMessage::from_bech32("siws1invalid").is_err());
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn test_cbor_roundtrip() {
+        let mut message = test_message().with_request_id("request-1").unwrap();
+        message.not_before = Some(1_700_000_060_000_000_000);
+        message.resources = vec!["https://example.com/resource".to_string()];
+        let encoded = message.to_cbor();
+        assert!(encoded.len() < message.to_json_string().len());
+        let decoded = SiwsMessage::from_cbor(&encoded).unwrap();
+        assert_eq!(decoded.to_json_string(), message.to_json_string());
+        assert!(SiwsMessage::from_cbor(b"not cbor").is_err());
+    }
+
+    #[test]
+    fn test_is_fresh() {
+        let mut message = test_message();
+        message.issued_at = get_current_time() - 10_000_000_000;
+        message.expiration_time = get_current_time() + 60_000_000_000;
+        assert!(message.is_fresh(20_000_000_000));
+        assert!(!message.is_fresh(5_000_000_000));
+
+        // Expired messages are never fresh.
+        message.expiration_time = get_current_time() - 1;
+        assert!(!message.is_fresh(20_000_000_000));
+    }
+
+    #[test]
+    fn test_with_settings() {
+        // No global settings are needed.
+        let settings =
+            crate::settings::SettingsBuilder::new("example.com", "http://example.com", "some_salt")
+                .sign_in_expires_in(60_000_000_000)
+                .build()
+                .unwrap();
+        let pubkey = SolPubkey::from_str("Awes4Tr6TX8JDzEhCZY2QVNimT6iD1zWHzf1vNyGvpLM").unwrap();
+        let message = SiwsMessage::with_settings(&pubkey, "nonce123", &settings);
+        assert_eq!(message.domain, "example.com");
+        assert_eq!(message.address, pubkey.to_string());
+        assert_eq!(message.nonce, "nonce123");
+        assert_eq!(message.expiration_time, message.issued_at + 60_000_000_000);
+    }
+
+    #[test]
+    fn test_is_for_address() {
+        let message = test_message();
+        let pubkey = SolPubkey::from_str(&message.address).unwrap();
+        assert!(message.is_for_address(&pubkey));
+        let other = SolPubkey::from_str("11111111111111111111111111111112").unwrap();
+        assert!(!message.is_for_address(&other));
+    }
+
+    fn init_settings() {
+        let settings =
+            crate::settings::SettingsBuilder::new("example.com", "http://example.com", "some_salt")
+                .build()
+                .unwrap();
+        crate::SETTINGS.set(Some(std::rc::Rc::new(settings)));
+    }
+
+    #[test]
+    fn test_try_insert() {
+        init_settings();
+        let pubkey = SolPubkey::from_str("Awes4Tr6TX8JDzEhCZY2QVNimT6iD1zWHzf1vNyGvpLM").unwrap();
+        let mut message = test_message();
+        message.expiration_time = get_current_time() + 60_000_000_000;
+        let mut map = SiwsMessageMap::new();
+        assert!(map
+            .try_insert(&pubkey, message.clone(), "nonce1", 1, None)
+            .is_ok());
+        assert!(matches!(
+            map.try_insert(&pubkey, message.clone(), "nonce2", 1, None),
+            Err(SiwsMessageError::AlreadyExists)
+        ));
+        assert!(map.get(&pubkey, "nonce2").is_err());
+
+        // Expired messages do not block new ones.
+        let mut map = SiwsMessageMap::new();
+        map.insert(&pubkey, test_message(), "nonce1", 1, None)
+            .unwrap();
+        assert!(map.try_insert(&pubkey, message, "nonce2", 1, None).is_ok());
+    }
+
+    #[test]
+    fn test_per_address_eviction() {
+        init_settings();
+        let pubkey = SolPubkey::from_str("Awes4Tr6TX8JDzEhCZY2QVNimT6iD1zWHzf1vNyGvpLM").unwrap();
+        let mut message = test_message();
+        message.expiration_time = get_current_time() + 60_000_000_000;
+
+        let mut map = SiwsMessageMap::new();
+        map.insert(&pubkey, message.clone(), "nonce1", 2, None)
+            .unwrap();
+        map.insert(&pubkey, message.clone(), "nonce2", 2, None)
+            .unwrap();
+        map.insert(&pubkey, message.clone(), "nonce3", 2, None)
+            .unwrap();
+        assert_eq!(map.len(), 2);
+        assert!(map.get(&pubkey, "nonce1").is_err());
+        assert!(map.get(&pubkey, "nonce2").is_ok());
+        assert!(map.get(&pubkey, "nonce3").is_ok());
+
+        // Replacing a message with the same nonce does not evict another one.
+        map.insert(&pubkey, message.clone(), "nonce2", 2, None)
+            .unwrap();
+        assert!(map.get(&pubkey, "nonce3").is_ok());
+
+        // A retry with the default limit of 1 replaces the pending message.
+        map.insert(&pubkey, message.clone(), "nonce4", 1, None)
+            .unwrap();
+        assert_eq!(map.len(), 1);
+        assert!(map.get(&pubkey, "nonce4").is_ok());
+
+        // Other addresses have their own quota.
+        let mut other = message;
+        other.address = "11111111111111111111111111111112".to_string();
+        let other_pubkey = SolPubkey::from_str(&other.address).unwrap();
+        map.insert(&other_pubkey, other, "nonce5", 1, None).unwrap();
+        assert!(map.get(&pubkey, "nonce4").is_ok());
+        assert_eq!(map.len(), 2);
+
+        map.remove(&pubkey, "nonce4");
+        map.remove(&other_pubkey, "nonce5");
+        assert!(map.address_index.is_empty());
+    }
+
+    #[test]
+    fn test_max_size() {
+        init_settings();
+        let pubkey = SolPubkey::from_str("Awes4Tr6TX8JDzEhCZY2QVNimT6iD1zWHzf1vNyGvpLM").unwrap();
+        let other_pubkey = SolPubkey::from_str("11111111111111111111111111111112").unwrap();
+        let mut message = test_message();
+        message.expiration_time = get_current_time() + 60_000_000_000;
+        let mut other = message.clone();
+        other.address = other_pubkey.to_string();
+
+        // Expired messages are pruned to make room.
+        let mut map = SiwsMessageMap::with_max_size(1);
+        map.insert(&other_pubkey, test_message(), "expired", 1, None)
+            .unwrap();
+        map.insert(&pubkey, message.clone(), "nonce1", 1, None)
+            .unwrap();
+        assert_eq!(map.len(), 1);
+
+        assert!(matches!(
+            map.insert(&other_pubkey, other.clone(), "nonce2", 1, None),
+            Err(SiwsMessageError::TooManyPendingMessages { max: 1 })
+        ));
+
+        // Replacing a message does not grow the map.
+        assert!(map.insert(&pubkey, message, "nonce1", 1, None).is_ok());
+
+        // The max_pending limit applies to maps without a max size.
+        let mut map = SiwsMessageMap::new();
+        map.insert(&pubkey, test_message(), "expired", 1, Some(1))
+            .unwrap();
+        assert!(map
+            .insert(&other_pubkey, other.clone(), "nonce2", 1, Some(1))
+            .is_ok());
+        assert!(matches!(
+            map.insert(&pubkey, other, "nonce3", 1, Some(1)),
+            Err(SiwsMessageError::TooManyPendingMessages { max: 1 })
+        ));
+    }
+
+    #[test]
+    fn test_len_and_iter() {
+        init_settings();
+        let pubkey = SolPubkey::from_str("Awes4Tr6TX8JDzEhCZY2QVNimT6iD1zWHzf1vNyGvpLM").unwrap();
+        let mut map = SiwsMessageMap::new();
+        map.insert(&pubkey, test_message(), "nonce", 1, None)
+            .unwrap();
+        assert_eq!(map.len(), 1);
+        assert!(!map.is_empty());
+        let (address, message) = map.iter().next().unwrap();
+        assert_eq!(*address, pubkey);
+        assert_eq!(message.nonce, test_message().nonce);
+    }
+
+    #[test]
+    fn test_get_by_message_hash() {
+        init_settings();
+        let pubkey = SolPubkey::from_str("Awes4Tr6TX8JDzEhCZY2QVNimT6iD1zWHzf1vNyGvpLM").unwrap();
+        let mut message = test_message();
+        message.expiration_time = get_current_time() + 60_000_000_000;
+        let message_hash = message.message_hash();
+
+        let mut map = SiwsMessageMap::new();
+        map.insert(&pubkey, message.clone(), "nonce", 1, None)
+            .unwrap();
+        assert_eq!(
+            map.get_by_message_hash(&message_hash).unwrap().nonce,
+            message.nonce
+        );
+        map.remove(&pubkey, "nonce");
+        assert!(map.get_by_message_hash(&message_hash).is_none());
+
+        // Pruning expired messages also cleans up the index.
+        let expired = test_message();
+        map.insert(&pubkey, expired.clone(), "nonce", 1, None)
+            .unwrap();
+        map.prune_expired();
+        assert!(map.get_by_message_hash(&expired.message_hash()).is_none());
+        assert!(map.message_hash_index.is_empty());
+    }
+
+    #[test]
+    fn test_statement_display_helpers() {
+        let mut message = test_message();
+        message.statement = "Sign in  to Ünicode app".to_string();
+        assert_eq!(message.statement_word_count(), 5);
+        assert_eq!(message.statement_char_count(), 23);
+        assert_eq!(message.statement_truncated(10), "Sign in...");
+        assert_eq!(message.statement_truncated(23), "Sign in  to Ünicode app");
+        assert_eq!(message.statement_truncated(22), "Sign in  to Ünicode...");
+        assert_eq!(message.statement_truncated(22).chars().count(), 22);
+        assert_eq!(message.statement_truncated(3), "Sig");
+    }
+
+    #[test]
+    fn test_with_nonce() {
+        let message = test_message().with_nonce("abcDEF123").unwrap();
+        assert_eq!(message.nonce, "abcDEF123");
+        assert!(test_message().with_nonce("abc123").is_err());
+        assert!(test_message().with_nonce("abc-DEF-123").is_err());
+
+        let mut message = test_message();
+        message.regenerate_nonce();
+        assert_ne!(message.nonce, test_message().nonce);
+        assert_eq!(message.nonce.len(), 20);
+    }
+
+    #[test]
+    fn test_not_before() {
+        let mut message = test_message();
+        assert!(!String::from(message.clone()).contains("Not Before"));
+        message.not_before = Some(1_700_000_060_000_000_000);
+        message.resources = vec!["https://example.com/resource".to_string()];
+        assert!(String::from(message.clone()).ends_with(
+            "Expiration Time: 2023-11-14T22:18:20.000Z\nNot Before: 2023-11-14T22:14:20.000Z\nResources:\n- https://example.com/resource"
+        ));
+        assert!(message.is_before_not_before(1_700_000_000_000_000_000));
+        assert!(!message.is_before_not_before(1_700_000_060_000_000_000));
+        assert!(message.is_expired());
+        assert!(!message.is_not_yet_valid());
+        assert!(!message.is_valid_now());
+    }
+
+    #[test]
+    fn test_is_valid_now() {
+        let current_time = get_current_time();
+        let mut message = test_message();
+        message.issued_at = current_time;
+        message.expiration_time = current_time + 60_000_000_000;
+        assert!(message.is_valid_now());
+
+        message.not_before = Some(current_time + 30_000_000_000);
+        assert!(message.is_not_yet_valid());
+        assert!(!message.is_expired());
+        assert!(!message.is_valid_now());
+    }
+
+    #[test]
+    fn test_request_id() {
+        let message = test_message().with_request_id("request-1").unwrap();
+        assert!(String::from(message).ends_with("\nRequest ID: request-1"));
+        assert!(test_message().with_request_id("a".repeat(4096)).is_ok());
+        assert!(matches!(
+            test_message().with_request_id("a".repeat(4097)),
+            Err(SiwsMessageError::RequestIdTooLong)
+        ));
+        for request_id in [
+            "request-1\nURI: https://evil.com",
+            "request\u{0}",
+            "\trequest",
+        ] {
+            assert!(matches!(
+                test_message().with_request_id(request_id),
+                Err(SiwsMessageError::InvalidRequestId)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_display_and_json() {
+        let message = test_message();
+        assert_eq!(message.to_string(), String::from(message.clone()));
+        assert!(message
+            .to_display_string()
+            .starts_with("example.com wants you to sign in with your Solana account:\n"));
+        let json: serde_json::Value = serde_json::from_str(&message.to_json_string()).unwrap();
+        assert_eq!(json["nonce"], "1234567890abcdef");
+    }
+
+    #[test]
+    fn test_to_json_map() {
+        let map = test_message().to_json_map();
+        assert_eq!(map["nonce"], "1234567890abcdef");
+        assert_eq!(map["issued_at"], 1_700_000_000_000_000_000u64);
+        assert_eq!(map["issued_at_rfc3339"], "2023-11-14T22:13:20Z");
+        assert_eq!(map["expiration_time_rfc3339"], "2023-11-14T22:18:20Z");
+        assert_eq!(map["address_short"], "Awes...vpLM");
+
+        let mut message = test_message();
+        message.address = "Awes".to_string();
+        assert_eq!(message.to_json_map()["address_short"], "Awes");
+        message.address = "ÄÖÜßäöüéè".to_string();
+        assert_eq!(message.to_json_map()["address_short"], "ÄÖÜß...öüéè");
+    }
+
+    #[test]
+    fn test_try_from_str_roundtrip() {
+        let message = test_message();
+        let parsed = SiwsMessage::try_from(String::from(message.clone()).as_str()).unwrap();
+        assert_eq!(parsed.to_json_string(), message.to_json_string());
+
+        let mut message = test_message().with_request_id("request-1").unwrap();
+        message.not_before = Some(1_700_000_060_000_000_000);
+        message.resources = vec![
+            "https://example.com/a".to_string(),
+            "https://example.com/b".to_string(),
+        ];
+        let parsed = SiwsMessage::try_from(String::from(message.clone()).as_str()).unwrap();
+        assert_eq!(parsed.to_json_string(), message.to_json_string());
+
+        let parsed: SiwsMessage = message.to_string().parse().unwrap();
+        assert_eq!(parsed.to_json_string(), message.to_json_string());
+    }
+
+    #[test]
+    fn test_try_from_str_out_of_order_and_unknown_fields() {
+        let text = "example.com wants you to sign in with your Solana account:\n\
+            Awes4Tr6TX8JDzEhCZY2QVNimT6iD1zWHzf1vNyGvpLM\n\
+            \n\
+            SIWS Fields:\n\
+            \n\
+            Nonce: 1234567890abcdef\n\
+            URI: http://example.com\n\
+            Unknown Field: ignored\n\
+            Chain ID: mainnet\n\
+            Version: 1\n\
+            Expiration Time: 2023-11-14T22:18:20.000Z\n\
+            Issued At: 2023-11-14T22:13:20.000Z";
+        let parsed = SiwsMessage::try_from(text).unwrap();
+        assert_eq!(parsed.to_json_string(), test_message().to_json_string());
+    }
+
+    #[test]
+    fn test_try_from_str_errors() {
+        let text = String::from(test_message());
+        let without_nonce = text.replace("Nonce: 1234567890abcdef\n", "");
+        assert!(matches!(
+            SiwsMessage::try_from(without_nonce.as_str()),
+            Err(SiwsParseError::MissingField(field)) if field == "Nonce"
+        ));
+        let bad_timestamp = text.replace("2023-11-14T22:13:20.000Z", "yesterday");
+        assert!(matches!(
+            SiwsMessage::try_from(bad_timestamp.as_str()),
+            Err(SiwsParseError::InvalidTimestamp(_))
+        ));
+        assert!(SiwsMessage::try_from("not a SIWS message").is_err());
+    }
+
+    #[test]
+    fn test_sanitize() {
+        let mut message = test_message();
+        message.domain = " example.com ".to_string();
+        message.address = "\tAwes4Tr6TX8JDzEhCZY2QVNimT6iD1zWHzf1vNyGvpLM\n".to_string();
+        message.uri = "http://example.com  ".to_string();
+        message.resources = vec![" https://example.com/resource ".to_string()];
+        let message = message.sanitize();
+        assert_eq!(message.domain, "example.com");
+        assert_eq!(
+            message.address,
+            "Awes4Tr6TX8JDzEhCZY2QVNimT6iD1zWHzf1vNyGvpLM"
+        );
+        assert_eq!(message.uri, "http://example.com");
+        assert_eq!(message.resources, vec!["https://example.com/resource"]);
+    }
+
+    #[test]
+    fn test_chain_id_clusters() {
+        let mut message = test_message();
+        for (chain_id, mainnet, testnet, devnet) in [
+            ("mainnet", true, false, false),
+            ("solana:mainnet", true, false, false),
+            ("testnet", false, true, false),
+            ("solana:testnet", false, true, false),
+            ("devnet", false, false, true),
+            ("solana:devnet", false, false, true),
+            ("localnet", false, false, false),
+        ] {
+            message.chain_id = chain_id.to_string();
+            assert_eq!(message.chain_id_is_solana_mainnet(), mainnet, "{}", chain_id);
+            assert_eq!(message.chain_id_is_solana_testnet(), testnet, "{}", chain_id);
+            assert_eq!(message.chain_id_is_solana_devnet(), devnet, "{}", chain_id);
+            assert_eq!(
+                message.chain_id_is_solana(),
+                mainnet || testnet || devnet,
+                "{}",
+                chain_id
+            );
+        }
+    }
+
+    #[test]
+    fn test_expiration_from_now() {
+        let settings =
+            crate::settings::SettingsBuilder::new("example.com", "http://example.com", "some_salt")
+                .sign_in_expires_in(60_000_000_000)
+                .build()
+                .unwrap();
+        crate::SETTINGS.set(Some(std::rc::Rc::new(settings)));
+
+        let before = get_current_time();
+        let message = test_message()
+            .with_expiration_from_now(30_000_000_000)
+            .unwrap();
+        assert!(message.expiration_time >= before + 30_000_000_000);
+        assert!(!message.is_expired());
+
+        let mut message = test_message();
+        assert!(matches!(
+            message.set_expiration_from_now(60_000_000_001),
+            Err(SiwsMessageError::ExpirationTooLong {
+                ttl_ns: 60_000_000_001,
+                max_ttl_ns: 60_000_000_000
+            })
+        ));
+        assert_eq!(message.expiration_time, test_message().expiration_time);
+    }
+
+    #[test]
+    fn test_builder() {
+        let settings =
+            crate::settings::SettingsBuilder::new("example.com", "http://example.com", "some_salt")
+                .build()
+                .unwrap();
+        crate::SETTINGS.set(Some(std::rc::Rc::new(settings)));
+        let pubkey = SolPubkey::from_str("Awes4Tr6TX8JDzEhCZY2QVNimT6iD1zWHzf1vNyGvpLM").unwrap();
+
+        let message = SiwsMessageBuilder::new()
+            .statement("Sign in to vote")
+            .nonce("abcdef123456")
+            .not_before(42)
+            .request_id("req-1")
+            .resources(vec!["https://example.com/poll/1".to_string()])
+            .build(&pubkey)
+            .unwrap();
+        assert_eq!(message.statement, "Sign in to vote");
+        assert_eq!(message.nonce, "abcdef123456");
+        assert_eq!(message.not_before, Some(42));
+        assert_eq!(message.request_id.as_deref(), Some("req-1"));
+        assert_eq!(message.resources, vec!["https://example.com/poll/1"]);
+
+        assert!(matches!(
+            SiwsMessageBuilder::new()
+                .statement("Two\nlines")
+                .build(&pubkey),
+            Err(SiwsMessageError::InvalidStatement)
+        ));
+        assert!(matches!(
+            SiwsMessageBuilder::new()
+                .resources(vec!["not a uri".to_string()])
+                .build(&pubkey),
+            Err(SiwsMessageError::InvalidResource(_))
+        ));
+    }
+}
@@ -1,7 +1,8 @@
 use {
     ed25519_dalek::{Signature, VerifyingKey},
-    serde::Serialize,
+    serde::{Deserialize, Serialize},
     std::{
+        borrow::Borrow,
         convert::{Infallible, TryFrom},
         fmt, mem,
         str::FromStr,
@@ -11,7 +12,28 @@ use {
 
 const MAX_BASE58_LEN: usize = 44;
 
-#[derive(Serialize, Clone, Copy)]
+/// Well-known Solana native and core program addresses. Signing in as one of these addresses is
+/// not possible as there is no private key associated with them.
+const NATIVE_PROGRAM_IDS: &[&str] = &[
+    "11111111111111111111111111111111",
+    "TokenkegQfeZyiNwAJbNbGKPFXCWuBvf9Ss623VQ5DA",
+    "TokenzQdBNbLqP5VEhdkAS6EPFLC1PHnBqCXEpPxuEb",
+    "ATokenGPvbdGVxr1b2hvZbsiqW5xWH25efTNsLJA8knL",
+    "BPFLoader1111111111111111111111111111111111",
+    "BPFLoader2111111111111111111111111111111111",
+    "BPFLoaderUpgradeab1e11111111111111111111111",
+    "NativeLoader1111111111111111111111111111111",
+    "Config1111111111111111111111111111111111111",
+    "Stake11111111111111111111111111111111111111",
+    "Vote111111111111111111111111111111111111111",
+    "ComputeBudget111111111111111111111111111111",
+    "AddressLookupTab1e1111111111111111111111111",
+    "Ed25519SigVerify111111111111111111111111111",
+    "KeccakSecp256k11111111111111111111111111111",
+    "MemoSq4gqABAXKb96qnH8TysNcWxMyWCqXgDLGmfcHr",
+];
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct SolPubkey(pub(crate) [u8; 32]);
 
 #[derive(Error, Debug, Serialize, Clone, PartialEq, Eq)]
@@ -29,6 +51,26 @@ impl fmt::Display for SolPubkey {
     }
 }
 
+impl fmt::Debug for SolPubkey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SolPubkey({})", self)
+    }
+}
+
+impl Ord for SolPubkey {
+    /// Public keys are ordered lexicographically by their base58 representation, matching the visual
+    /// ordering users expect when pubkeys are displayed.
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.to_string().cmp(&other.to_string())
+    }
+}
+
+impl PartialOrd for SolPubkey {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
 impl From<Infallible> for ParsePubkeyError {
     fn from(_: Infallible) -> Self {
         unreachable!("Infallible uninhabited");
@@ -48,7 +90,10 @@ impl std::str::FromStr for SolPubkey {
         if pubkey_vec.len() != mem::size_of::<SolPubkey>() {
             Err(ParsePubkeyError::WrongSize)
         } else {
-            SolPubkey::try_from(pubkey_vec.as_slice()).map_err(|_| ParsePubkeyError::Invalid)
+            // Parsed directly from the bytes, the all-zero System Program address is a valid base58 pubkey.
+            <[u8; 32]>::try_from(pubkey_vec.as_slice())
+                .map(Self::from)
+                .map_err(|_| ParsePubkeyError::Invalid)
         }
     }
 }
@@ -60,21 +105,50 @@ impl From<[u8; 32]> for SolPubkey {
     }
 }
 
+/// Creates a public key from raw bytes. Returns [`SolError::InvalidLength`] if the slice is not 32 bytes long
+/// and [`SolError::NullPublicKey`] if all bytes are zero.
 impl TryFrom<&[u8]> for SolPubkey {
-    type Error = std::array::TryFromSliceError;
+    type Error = SolError;
 
     #[inline]
     fn try_from(pubkey: &[u8]) -> Result<Self, Self::Error> {
-        <[u8; 32]>::try_from(pubkey).map(Self::from)
+        let bytes = <[u8; 32]>::try_from(pubkey).map_err(|_| SolError::InvalidLength {
+            got: pubkey.len(),
+            expected: 32,
+        })?;
+        if bytes == [0u8; 32] {
+            return Err(SolError::NullPublicKey);
+        }
+        Ok(Self::from(bytes))
     }
 }
 
 impl TryFrom<Vec<u8>> for SolPubkey {
-    type Error = Vec<u8>;
+    type Error = SolError;
 
     #[inline]
     fn try_from(pubkey: Vec<u8>) -> Result<Self, Self::Error> {
-        <[u8; 32]>::try_from(pubkey).map(Self::from)
+        SolPubkey::try_from(pubkey.as_slice())
+    }
+}
+
+impl AsRef<[u8]> for SolPubkey {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl AsRef<[u8; 32]> for SolPubkey {
+    fn as_ref(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+/// Allows looking up public keys in a `HashMap` or `HashSet` by their raw bytes. `Hash` and `Eq` match those of
+/// the bytes, `Ord` does not, see the [`Ord`] implementation. Don't use byte lookups with ordered collections.
+impl Borrow<[u8]> for SolPubkey {
+    fn borrow(&self) -> &[u8] {
+        &self.0
     }
 }
 
@@ -89,6 +163,99 @@ impl SolPubkey {
     pub fn to_bytes(self) -> [u8; 32] {
         self.0
     }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    /// Returns `true` if the public key is the address of a well-known Solana native program, such as
+    /// the System Program or the Token Program.
+    pub fn is_native_program(&self) -> bool {
+        NATIVE_PROGRAM_IDS.contains(&self.to_string().as_str())
+    }
+
+    /// Returns `true` if all bytes of the public key are zero.
+    pub fn is_null(&self) -> bool {
+        self.0 == [0u8; 32]
+    }
+
+    /// Returns `true` if the public key is a point on the ed25519 curve. Program derived addresses
+    /// (PDAs) are deliberately chosen off the curve, so no private key exists for them.
+    pub fn is_on_curve(&self) -> bool {
+        VerifyingKey::from_bytes(&self.0).is_ok()
+    }
+
+    /// Creates the public key of a signer from raw bytes. Unlike the `TryFrom<&[u8]>` implementation, which
+    /// accepts any address including program addresses, this also returns [`SolError::InvalidPubkey`] if the
+    /// bytes are not a valid compressed ed25519 point or are a point of small order, such as the identity.
+    /// No private key can produce signatures for these keys.
+    pub fn try_from_bytes(bytes: &[u8]) -> Result<SolPubkey, SolError> {
+        let pubkey = SolPubkey::try_from(bytes)?;
+        verifying_key(&pubkey)?;
+        Ok(pubkey)
+    }
+
+    /// Parses the base58 address of a signer with the checks of [`SolPubkey::try_from_bytes`]. Use `FromStr`
+    /// for addresses that may belong to programs, e.g. in the settings.
+    pub fn parse_signer(s: &str) -> Result<SolPubkey, SolError> {
+        let pubkey = SolPubkey::from_str(s).map_err(|_| SolError::InvalidPubkey)?;
+        SolPubkey::try_from_bytes(pubkey.as_bytes())
+    }
+}
+
+// Decompresses the public key to an ed25519 point, rejecting off-curve and small order points.
+fn verifying_key(pubkey: &SolPubkey) -> Result<VerifyingKey, SolError> {
+    let verifying_key = VerifyingKey::from_bytes(&pubkey.0).map_err(|_| SolError::InvalidPubkey)?;
+    if verifying_key.is_weak() {
+        return Err(SolError::InvalidPubkey);
+    }
+    Ok(verifying_key)
+}
+
+/// Sorts a list of public keys by their base58 representation. The sort is stable.
+pub fn sort_pubkeys(pubkeys: &mut [SolPubkey]) {
+    pubkeys.sort_by_cached_key(|pubkey| pubkey.to_string());
+}
+
+/// Public Solana JSON-RPC endpoints, for canisters that call Solana, for example to check on-chain state
+/// before a login. The enum only describes the endpoint, it does not perform any I/O.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SolanaRpcUrl {
+    Mainnet,
+    Testnet,
+    Devnet,
+    Localnet,
+    Custom(String),
+}
+
+impl SolanaRpcUrl {
+    /// Returns the endpoint URL.
+    pub fn as_str(&self) -> &str {
+        match self {
+            SolanaRpcUrl::Mainnet => "https://api.mainnet-beta.solana.com",
+            SolanaRpcUrl::Testnet => "https://api.testnet.solana.com",
+            SolanaRpcUrl::Devnet => "https://api.devnet.solana.com",
+            SolanaRpcUrl::Localnet => "http://127.0.0.1:8899",
+            SolanaRpcUrl::Custom(url) => url,
+        }
+    }
+
+    /// Returns the public endpoint for a SIWS chain ID, such as `devnet` or `solana:devnet`. Unknown chain
+    /// IDs map to [`SolanaRpcUrl::Mainnet`], the default chain ID of the settings.
+    pub fn from_chain_id(chain_id: &str) -> SolanaRpcUrl {
+        match chain_id.strip_prefix("solana:").unwrap_or(chain_id) {
+            "testnet" => SolanaRpcUrl::Testnet,
+            "devnet" => SolanaRpcUrl::Devnet,
+            "localnet" => SolanaRpcUrl::Localnet,
+            _ => SolanaRpcUrl::Mainnet,
+        }
+    }
+}
+
+impl fmt::Display for SolanaRpcUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
 }
 
 #[derive(Error, Debug, Serialize, Clone, PartialEq, Eq)]
@@ -132,8 +299,11 @@ impl std::str::FromStr for SolSignature {
     }
 }
 
+#[derive(Debug)]
 pub enum SolError {
     InvalidPubkey,
+    InvalidLength { got: usize, expected: usize },
+    NullPublicKey,
     InvalidSignature,
     VerificationFailure,
 }
@@ -142,12 +312,20 @@ impl fmt::Display for SolError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             SolError::InvalidPubkey => write!(f, "Invalid public key"),
+            SolError::InvalidLength { got, expected } => write!(
+                f,
+                "Invalid public key length: got {} bytes, expected {}",
+                got, expected
+            ),
+            SolError::NullPublicKey => write!(f, "Public key is all zeros"),
             SolError::InvalidSignature => write!(f, "Invalid signature"),
             SolError::VerificationFailure => write!(f, "Signature verification failed"),
         }
     }
 }
 
+impl std::error::Error for SolError {}
+
 impl From<SolError> for String {
     fn from(error: SolError) -> Self {
         error.to_string()
@@ -160,7 +338,7 @@ pub fn verify_sol_signature(
     pubkey: &SolPubkey,
 ) -> Result<(), SolError> {
     // Create a PublicKey from the Solana public key
-    let pubkey = VerifyingKey::from_bytes(&pubkey.0).map_err(|_| SolError::InvalidPubkey)?;
+    let pubkey = verifying_key(pubkey)?;
 
     // Create a Signature from the Solana signature
     let signature = Signature::from_bytes(&signature.0);
@@ -171,3 +349,157 @@ pub fn verify_sol_signature(
         .map(|_| ()) // If verification is successful, return Ok(())
         .map_err(|_| SolError::VerificationFailure) // Handle any verification failure
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_native_program() {
+        for id in NATIVE_PROGRAM_IDS {
+            let pubkey = SolPubkey::from_str(id).unwrap();
+            assert!(pubkey.is_native_program(), "{} should be a native program", id);
+        }
+    }
+
+    #[test]
+    fn test_is_not_native_program() {
+        let pubkey = SolPubkey::from_str("Awes4Tr6TX8JDzEhCZY2QVNimT6iD1zWHzf1vNyGvpLM").unwrap();
+        assert!(!pubkey.is_native_program());
+    }
+
+    #[test]
+    fn test_solana_rpc_url_from_chain_id() {
+        assert_eq!(
+            SolanaRpcUrl::from_chain_id("mainnet"),
+            SolanaRpcUrl::Mainnet
+        );
+        assert_eq!(
+            SolanaRpcUrl::from_chain_id("solana:devnet"),
+            SolanaRpcUrl::Devnet
+        );
+        assert_eq!(
+            SolanaRpcUrl::from_chain_id("testnet").as_str(),
+            "https://api.testnet.solana.com"
+        );
+        assert_eq!(
+            SolanaRpcUrl::from_chain_id("localnet").as_str(),
+            "http://127.0.0.1:8899"
+        );
+        let custom = SolanaRpcUrl::Custom("https://rpc.example.com".to_string());
+        assert_eq!(custom.as_str(), "https://rpc.example.com");
+    }
+
+    #[test]
+    fn test_is_on_curve() {
+        use solana_sdk::{pubkey::Pubkey, signature::Keypair, signer::Signer};
+
+        let keypair_pubkey = SolPubkey::from(Keypair::new().pubkey().to_bytes());
+        assert!(keypair_pubkey.is_on_curve());
+
+        let (pda, _) = Pubkey::find_program_address(&[b"ic_siws"], &Pubkey::new_unique());
+        assert!(!SolPubkey::from(pda.to_bytes()).is_on_curve());
+    }
+
+    #[test]
+    fn test_try_from_bytes() {
+        let pubkey = SolPubkey::from_str("Awes4Tr6TX8JDzEhCZY2QVNimT6iD1zWHzf1vNyGvpLM").unwrap();
+        let bytes = pubkey.to_bytes();
+        assert_eq!(SolPubkey::try_from(&bytes[..]).unwrap(), pubkey);
+        assert_eq!(SolPubkey::try_from(bytes.to_vec()).unwrap(), pubkey);
+        assert!(matches!(
+            SolPubkey::try_from(&bytes[..31]),
+            Err(SolError::InvalidLength {
+                got: 31,
+                expected: 32
+            })
+        ));
+        assert!(matches!(
+            SolPubkey::try_from(vec![0u8; 32]),
+            Err(SolError::NullPublicKey)
+        ));
+    }
+
+    #[test]
+    fn test_try_from_bytes_rejects_invalid_points() {
+        use solana_sdk::{pubkey::Pubkey, signature::Keypair, signer::Signer};
+
+        let keypair_pubkey = Keypair::new().pubkey().to_bytes();
+        assert_eq!(
+            SolPubkey::try_from_bytes(&keypair_pubkey).unwrap(),
+            SolPubkey::from(keypair_pubkey)
+        );
+        assert!(matches!(
+            SolPubkey::try_from_bytes(&keypair_pubkey[..31]),
+            Err(SolError::InvalidLength { .. })
+        ));
+        assert!(matches!(
+            SolPubkey::try_from_bytes(&[0u8; 32]),
+            Err(SolError::NullPublicKey)
+        ));
+
+        let (pda, _) = Pubkey::find_program_address(&[b"ic_siws"], &Pubkey::new_unique());
+        assert!(matches!(
+            SolPubkey::try_from_bytes(&pda.to_bytes()),
+            Err(SolError::InvalidPubkey)
+        ));
+
+        let mut identity = [0u8; 32];
+        identity[0] = 1;
+        assert!(matches!(
+            SolPubkey::try_from_bytes(&identity),
+            Err(SolError::InvalidPubkey)
+        ));
+
+        let keypair_address = SolPubkey::from(keypair_pubkey).to_string();
+        assert!(SolPubkey::parse_signer(&keypair_address).is_ok());
+        assert!(matches!(
+            SolPubkey::parse_signer("11111111111111111111111111111111"),
+            Err(SolError::NullPublicKey)
+        ));
+        assert!(matches!(
+            SolPubkey::parse_signer("not base58!"),
+            Err(SolError::InvalidPubkey)
+        ));
+    }
+
+    #[test]
+    fn test_as_ref_and_borrow() {
+        let pubkey = SolPubkey::from_str("Awes4Tr6TX8JDzEhCZY2QVNimT6iD1zWHzf1vNyGvpLM").unwrap();
+        let bytes = pubkey.to_bytes();
+        assert_eq!(AsRef::<[u8]>::as_ref(&pubkey), &bytes[..]);
+        assert_eq!(AsRef::<[u8; 32]>::as_ref(&pubkey), &bytes);
+        assert_eq!(pubkey.as_bytes(), &bytes);
+
+        let pubkeys: std::collections::HashSet<SolPubkey> = [pubkey].into_iter().collect();
+        assert!(pubkeys.contains(&bytes[..]));
+        assert!(!pubkeys.contains(&[1u8; 32][..]));
+    }
+
+    #[test]
+    fn test_sort_pubkeys() {
+        let expected = [
+            "11111111111111111111111111111111",
+            "Awes4Tr6TX8JDzEhCZY2QVNimT6iD1zWHzf1vNyGvpLM",
+            "TokenkegQfeZyiNwAJbNbGKPFXCWuBvf9Ss623VQ5DA",
+            "fz4M1r2CWEv1TeLTYaQNxN7ahuQ1hM9B92mP11HnYXU",
+        ];
+        let mut pubkeys: Vec<SolPubkey> = [expected[2], expected[3], expected[0], expected[1]]
+            .iter()
+            .map(|s| SolPubkey::from_str(s).unwrap())
+            .collect();
+        sort_pubkeys(&mut pubkeys);
+        let sorted: Vec<String> = pubkeys.iter().map(|p| p.to_string()).collect();
+        assert_eq!(sorted, expected);
+    }
+
+    #[test]
+    fn test_sort_pubkeys_is_stable() {
+        let a = SolPubkey::from_str("Awes4Tr6TX8JDzEhCZY2QVNimT6iD1zWHzf1vNyGvpLM").unwrap();
+        let b = SolPubkey::from_str("11111111111111111111111111111112").unwrap();
+        let mut pubkeys = vec![a, b, a, b];
+        sort_pubkeys(&mut pubkeys);
+        assert_eq!(pubkeys, vec![b, b, a, a]);
+        assert!(b < a);
+    }
+}
@@ -11,3 +11,57 @@ pub(crate) fn get_current_time() -> u64 {
     let start = SystemTime::now();
     start.duration_since(UNIX_EPOCH).unwrap().as_nanos() as u64
 }
+
+/// Converts a timestamp or duration in nanoseconds to milliseconds.
+pub fn ns_to_ms(ns: u64) -> u64 {
+    ns / 1_000_000
+}
+
+/// Converts a timestamp or duration in nanoseconds to seconds.
+pub fn ns_to_s(ns: u64) -> u64 {
+    ns / 1_000_000_000
+}
+
+/// Converts a timestamp or duration in milliseconds to nanoseconds, saturating at `u64::MAX`.
+pub fn ms_to_ns(ms: u64) -> u64 {
+    ms.saturating_mul(1_000_000)
+}
+
+/// Converts a timestamp or duration in seconds to nanoseconds, saturating at `u64::MAX`.
+pub fn s_to_ns(s: u64) -> u64 {
+    s.saturating_mul(1_000_000_000)
+}
+
+/// Returns the current time in milliseconds since the UNIX epoch.
+pub fn get_current_time_ms() -> u64 {
+    ns_to_ms(get_current_time())
+}
+
+/// Returns the current time in seconds since the UNIX epoch.
+pub fn get_current_time_s() -> u64 {
+    ns_to_s(get_current_time())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unit_conversions() {
+        assert_eq!(ns_to_ms(1_999_999), 1);
+        assert_eq!(ns_to_s(1_999_999_999), 1);
+        assert_eq!(ms_to_ns(2), 2_000_000);
+        assert_eq!(s_to_ns(2), 2_000_000_000);
+        assert_eq!(ms_to_ns(u64::MAX), u64::MAX);
+        assert_eq!(s_to_ns(u64::MAX), u64::MAX);
+        assert_eq!(ns_to_s(s_to_ns(300)), 300);
+    }
+
+    #[test]
+    fn test_current_time_units() {
+        let ms = get_current_time_ms();
+        let s = get_current_time_s();
+        assert!(s >= ns_to_s(ms_to_ns(ms)));
+        assert!(s <= get_current_time() / 1_000_000_000);
+    }
+}
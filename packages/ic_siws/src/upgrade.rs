@@ -0,0 +1,96 @@
+use crate::{
+    access_control::{AccessControl, AccessControlError},
+    signature_map::{SignatureMap, SignatureMapError},
+    ACCESS_CONTROL,
+};
+use std::cell::RefCell;
+
+/// Serializes the signature map held in canister state, to be saved to stable memory in the `pre_upgrade` hook.
+/// See [`SignatureMap::to_bytes`].
+///
+/// # Examples
+///
+/// ```ignore
+/// #[pre_upgrade]
+/// fn pre_upgrade() {
+///     let bytes = STATE.with(|s| pre_upgrade_signature_map(&s.signature_map));
+///     storage::stable_save((bytes,)).unwrap();
+/// }
+/// ```
+pub fn pre_upgrade_signature_map(signature_map: &RefCell<SignatureMap>) -> Vec<u8> {
+    signature_map.borrow().to_bytes()
+}
+
+/// Replaces the signature map held in canister state with the map serialized by [`pre_upgrade_signature_map`],
+/// in the `post_upgrade` hook. Certified data is not preserved across upgrades, the canister must certify the
+/// root hash of the restored map again.
+///
+/// # Examples
+///
+/// ```ignore
+/// #[post_upgrade]
+/// fn post_upgrade() {
+///     let (bytes,): (Vec<u8>,) = storage::stable_restore().unwrap();
+///     STATE.with(|s| {
+///         post_upgrade_signature_map(&s.signature_map, bytes).unwrap();
+///         update_root_hash(&s.asset_hashes.borrow(), &s.signature_map.borrow());
+///     });
+/// }
+/// ```
+pub fn post_upgrade_signature_map(
+    signature_map: &RefCell<SignatureMap>,
+    bytes: Vec<u8>,
+) -> Result<(), SignatureMapError> {
+    let restored = SignatureMap::from_bytes(&bytes)?;
+    signature_map.replace(restored);
+    Ok(())
+}
+
+/// Serializes the address blocklist, see [`crate::access_control`], to be saved to stable memory in the
+/// `pre_upgrade` hook together with the signature map.
+pub fn pre_upgrade_access_control() -> Vec<u8> {
+    ACCESS_CONTROL.with_borrow(|access_control| access_control.to_bytes())
+}
+
+/// Restores the address blocklist serialized by [`pre_upgrade_access_control`] in the `post_upgrade` hook.
+pub fn post_upgrade_access_control(bytes: Vec<u8>) -> Result<(), AccessControlError> {
+    let restored = AccessControl::from_bytes(&bytes)?;
+    ACCESS_CONTROL.set(restored);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash;
+
+    #[test]
+    fn test_upgrade_roundtrip() {
+        let signature_map = RefCell::new(SignatureMap::default());
+        signature_map
+            .borrow_mut()
+            .put(hash::hash_bytes(b"seed"), hash::hash_bytes(b"delegation"));
+        let bytes = pre_upgrade_signature_map(&signature_map);
+
+        let restored = RefCell::new(SignatureMap::default());
+        post_upgrade_signature_map(&restored, bytes).unwrap();
+        assert_eq!(
+            restored.borrow().root_hash(),
+            signature_map.borrow().root_hash()
+        );
+        assert!(post_upgrade_signature_map(&restored, vec![0xff]).is_err());
+        assert_eq!(restored.borrow().len(), 1);
+    }
+
+    #[test]
+    fn test_access_control_upgrade_roundtrip() {
+        let pubkey = crate::solana::SolPubkey::from([1u8; 32]);
+        crate::access_control::block_address(&pubkey);
+        let bytes = pre_upgrade_access_control();
+
+        ACCESS_CONTROL.set(AccessControl::new());
+        post_upgrade_access_control(bytes).unwrap();
+        assert!(crate::access_control::is_blocked(&pubkey));
+        assert!(post_upgrade_access_control(vec![0xff]).is_err());
+    }
+}
@@ -8,6 +8,7 @@ export interface Delegation {
   pubkey: PublicKey;
   targets: [] | [Array<Principal>];
   expiration: Timestamp;
+  memo: [] | [Uint8Array | number[]];
 }
 export type GetAddressResponse = { Ok: Address } | { Err: string };
 export type GetDelegationResponse = { Ok: SignedDelegation } | { Err: string };
@@ -15,6 +16,7 @@ export type GetPrincipalResponse = { Ok: Principal } | { Err: string };
 export interface LoginDetails {
   user_canister_pubkey: CanisterPublicKey;
   expiration: Timestamp;
+  session_token: [] | [string];
 }
 export type LoginResponse = { Ok: LoginDetails } | { Err: string };
 export type Nonce = string;
@@ -52,6 +54,9 @@ export interface SiwsMessage {
   address: Address;
   nonce: string;
   expiration_time: bigint;
+  not_before: [] | [bigint];
+  request_id: [] | [string];
+  resources: Array<string>;
 }
 export type SiwsSignature = string;
 export type Timestamp = bigint;
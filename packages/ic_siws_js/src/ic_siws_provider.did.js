@@ -30,6 +30,7 @@ export const idlFactory = ({ IDL }) => {
     pubkey: PublicKey,
     targets: IDL.Opt(IDL.Vec(IDL.Principal)),
     expiration: Timestamp,
+    memo: IDL.Opt(IDL.Vec(IDL.Nat8)),
   });
   const SignedDelegation = IDL.Record({
     signature: IDL.Vec(IDL.Nat8),
@@ -45,6 +46,7 @@ export const idlFactory = ({ IDL }) => {
   const LoginDetails = IDL.Record({
     user_canister_pubkey: CanisterPublicKey,
     expiration: Timestamp,
+    session_token: IDL.Opt(IDL.Text),
   });
   const LoginResponse = IDL.Variant({ Ok: LoginDetails, Err: IDL.Text });
   const SiwsMessage = IDL.Record({
@@ -57,6 +59,9 @@ export const idlFactory = ({ IDL }) => {
     address: Address,
     nonce: IDL.Text,
     expiration_time: IDL.Nat64,
+    not_before: IDL.Opt(IDL.Nat64),
+    request_id: IDL.Opt(IDL.Text),
+    resources: IDL.Vec(IDL.Text),
   });
   const PrepareLoginResponse = IDL.Variant({
     Ok: SiwsMessage,
@@ -237,6 +237,7 @@ export class SiwsManager {
         const issuedAtMilliseconds = Number(
           siwsMessage.issued_at / BigInt(1000000),
         );
+        const notBefore = siwsMessage.not_before[0];
 
         // Display the SIWS message for the user to sign.
         const result = await this.adapter.signIn({
@@ -249,6 +250,15 @@ export class SiwsManager {
           uri: siwsMessage.uri,
           version: siwsMessage.version.toString(),
           statement: siwsMessage.statement,
+          notBefore:
+            notBefore !== undefined
+              ? new Date(Number(notBefore / BigInt(1000000))).toISOString()
+              : undefined,
+          requestId: siwsMessage.request_id[0],
+          resources:
+            siwsMessage.resources.length > 0
+              ? siwsMessage.resources
+              : undefined,
         });
 
         if (!result || !result.signature) {
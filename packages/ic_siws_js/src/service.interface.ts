@@ -9,6 +9,7 @@ export interface Delegation {
   pubkey: PublicKey;
   targets: [] | [Array<Principal>];
   expiration: Timestamp;
+  memo: [] | [Uint8Array | number[]];
 }
 
 export type GetDelegationResponse = { Ok: SignedDelegation } | { Err: string };
@@ -30,6 +31,9 @@ export interface SiwsMessage {
   nonce: string;
   issued_at: bigint;
   expiration_time: bigint;
+  not_before: [] | [bigint];
+  request_id: [] | [string];
+  resources: string[];
 }
 
 export type PrepareLoginResponse = { Ok: SiwsMessage } | { Err: string };
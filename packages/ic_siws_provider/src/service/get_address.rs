@@ -26,7 +26,7 @@ pub(crate) fn get_address(principal: ByteBuf) -> Result<String, String> {
     let address = PRINCIPAL_ADDRESS.with_borrow(|m| m.get(&principal));
 
     match address {
-        Some(address) => Ok(SolPubkey::try_from(address.as_slice())
+        Some(address) => Ok(SolPubkey::try_from_bytes(address.as_slice())
             .map_err(|_| "Failed to convert Blob<32> to SolPubkey")?
             .to_string()),
         None => Err("Principal not found".to_string()),
@@ -1,9 +1,9 @@
 use candid::{CandidType, Principal};
 use ic_cdk::{init, post_upgrade};
-use ic_siws::settings::SettingsBuilder;
+use ic_siws::{settings::SettingsBuilder, signature_map::SignatureMap};
 use serde::Deserialize;
 
-use crate::SETTINGS;
+use crate::{Settings, SETTINGS, STATE};
 
 #[derive(CandidType, Debug, Clone, PartialEq, Deserialize)]
 pub enum RuntimeFeature {
@@ -64,6 +64,33 @@ pub struct SettingsInput {
 /// This means that existing users will get a new principal id when they sign in. Tip: Don't change the `salt` or `uri`
 /// settings after users have started using the service!
 fn siws_init(settings_input: SettingsInput) {
+    let (ic_siws_settings, provider_settings) = build_settings(settings_input).unwrap();
+    let signature_map_initial_capacity = ic_siws_settings.signature_map_initial_capacity;
+
+    // Initialize SIWS
+    ic_siws::init(ic_siws_settings).unwrap();
+    SETTINGS.set(provider_settings);
+
+    // Pre-allocate the signature map according to the settings
+    STATE.with(|s| {
+        *s.signature_map.borrow_mut() = SignatureMap::with_capacity(signature_map_initial_capacity);
+    });
+}
+
+/// Builds and validates the SIWS library settings from the given input. The provider canister settings are
+/// ignored, use [`build_settings`] to get both.
+impl TryFrom<SettingsInput> for ic_siws::settings::Settings {
+    type Error = String;
+
+    fn try_from(settings_input: SettingsInput) -> Result<Self, Self::Error> {
+        build_settings(settings_input).map(|(ic_siws_settings, _)| ic_siws_settings)
+    }
+}
+
+/// Builds and validates the SIWS library settings and the provider canister settings from the given input.
+pub(crate) fn build_settings(
+    settings_input: SettingsInput,
+) -> Result<(ic_siws::settings::Settings, Settings), String> {
     let mut ic_siws_settings = SettingsBuilder::new(
         &settings_input.domain,
         &settings_input.uri,
@@ -89,41 +116,39 @@ fn siws_init(settings_input: SettingsInput) {
     if let Some(targets) = settings_input.targets {
         let targets: Vec<Principal> = targets
             .into_iter()
-            .map(|t| Principal::from_text(t).unwrap())
-            .collect();
+            .map(|t| Principal::from_text(t).map_err(|e| e.to_string()))
+            .collect::<Result<_, _>>()?;
         // Make sure the canister id of this canister is in the list of targets
         let canister_id = ic_cdk::id();
         if !targets.contains(&canister_id) {
-            panic!(
+            return Err(format!(
                 "ic_siws_provider canister id {} not in the list of targets",
                 canister_id
-            );
+            ));
         }
         ic_siws_settings = ic_siws_settings.targets(targets);
     }
 
-    SETTINGS.with_borrow_mut(|provider_settings| {
-        if let Some(runtime_features) = settings_input.runtime_features {
-            for feature in runtime_features {
-                match feature {
-                    RuntimeFeature::IncludeUriInSeed => {
-                        ic_siws_settings = ic_siws_settings.runtime_features(vec![
-                            ic_siws::settings::RuntimeFeature::IncludeUriInSeed,
-                        ]);
-                    }
-                    RuntimeFeature::DisableSolToPrincipalMapping => {
-                        provider_settings.disable_sol_to_principal_mapping = true;
-                    }
-                    RuntimeFeature::DisablePrincipalToSolMapping => {
-                        provider_settings.disable_principal_to_sol_mapping = true;
-                    }
+    let mut provider_settings = Settings::default();
+    if let Some(runtime_features) = settings_input.runtime_features {
+        for feature in runtime_features {
+            match feature {
+                RuntimeFeature::IncludeUriInSeed => {
+                    ic_siws_settings = ic_siws_settings.runtime_features(vec![
+                        ic_siws::settings::RuntimeFeature::IncludeUriInSeed,
+                    ]);
+                }
+                RuntimeFeature::DisableSolToPrincipalMapping => {
+                    provider_settings.disable_sol_to_principal_mapping = true;
+                }
+                RuntimeFeature::DisablePrincipalToSolMapping => {
+                    provider_settings.disable_principal_to_sol_mapping = true;
                 }
             }
         }
+    }
 
-        // Build and initialize SIWS
-        ic_siws::init(ic_siws_settings.build().unwrap()).unwrap();
-    });
+    Ok((ic_siws_settings.build()?, provider_settings))
 }
 
 /// `init` is called when the canister is created. It initializes the SIWS library with the given settings.
@@ -5,3 +5,5 @@ pub mod init_upgrade;
 pub mod siws_get_delegation;
 pub mod siws_login;
 pub mod siws_prepare_login;
+pub mod siws_reload_settings;
+pub mod siws_signature_map_stats;
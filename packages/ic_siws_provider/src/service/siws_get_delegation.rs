@@ -1,5 +1,3 @@
-use std::str::FromStr;
-
 use ic_cdk::{api::data_certificate, query};
 use ic_certified_map::{fork, labeled_hash, AsHashTree, HashTree};
 use ic_siws::{
@@ -33,7 +31,7 @@ fn siws_get_delegation(
     let certificate =
         data_certificate().expect("siws_get_delegation must be called using a query call");
 
-    let pubkey = SolPubkey::from_str(pubkey.as_str()).map_err(|e| e.to_string())?;
+    let pubkey = SolPubkey::parse_signer(pubkey.trim()).map_err(|e| e.to_string())?;
 
     STATE.with(|s| {
         let signature_map = s.signature_map.borrow_mut();
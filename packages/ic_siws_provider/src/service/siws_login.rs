@@ -1,6 +1,5 @@
 use std::str::FromStr;
 
-use candid::Principal;
 use ic_cdk::update;
 use ic_siws::{
     login::LoginDetails,
@@ -33,13 +32,13 @@ fn siws_login(
     STATE.with(|state| {
         let signature_map = &mut *state.signature_map.borrow_mut();
 
-        let pubkey = SolPubkey::from_str(pubkey.as_str()).map_err(|e| e.to_string())?;
+        let pubkey = SolPubkey::parse_signer(pubkey.trim()).map_err(|e| e.to_string())?;
 
         // Create an EthSignature from the string. This validates the signature.
         let signature = SolSignature::from_str(signature.as_str()).map_err(|e| e.to_string())?;
 
         // Attempt to log in with the provided signature, address, and session key.
-        let login_response = ic_siws::login::login(
+        let (login_response, _) = ic_siws::login::login(
             &signature,
             &pubkey,
             session_key,
@@ -53,10 +52,9 @@ fn siws_login(
         update_root_hash(&state.asset_hashes.borrow(), signature_map);
 
         // Convert the user canister public key to a principal.
-        let principal: Blob<29> =
-            Principal::self_authenticating(&login_response.user_canister_pubkey).as_slice()[..29]
-                .try_into()
-                .map_err(|_| format!("Invalid principal: {:?}", login_response))?;
+        let principal: Blob<29> = login_response.derive_principal().as_slice()[..29]
+            .try_into()
+            .map_err(|_| format!("Invalid principal: {:?}", login_response))?;
 
         // Store the mapping of principal to Solana address and vice versa if the settings allow it.
         manage_principal_address_mappings(&principal, &pubkey);
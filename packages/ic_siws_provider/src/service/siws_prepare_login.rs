@@ -9,11 +9,12 @@ use ic_siws::{siws::SiwsMessage, solana::SolPubkey};
 ///
 /// # Returns
 /// * `Ok(SiwsMessage)` containing the SIWS challenge message.
-/// * `Err(String)` if the provided public key string is invalid.
+/// * `Err(String)` if the provided public key string is invalid or the public key is not allowed to sign in.
 #[update]
 fn siws_prepare_login(pubkey: String) -> Result<SiwsMessage, String> {
-    // Attempt to create a Pubkey from the string. This validates the PK.
-    let pubkey = SolPubkey::from_str(pubkey.as_str()).map_err(|e| e.to_string())?;
+    // Attempt to create a Pubkey from the string. prepare_login rejects addresses that can't sign, such as
+    // program derived or small order keys, with a specific error.
+    let pubkey = SolPubkey::from_str(pubkey.trim()).map_err(|e| e.to_string())?;
 
-    Ok(ic_siws::login::prepare_login(&pubkey))
+    ic_siws::login::prepare_login(&pubkey).map_err(|e| e.to_string())
 }
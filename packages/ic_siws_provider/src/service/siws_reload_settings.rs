@@ -0,0 +1,27 @@
+use ic_cdk::update;
+
+use crate::SETTINGS;
+
+use super::init_upgrade::{build_settings, SettingsInput};
+
+/// Replaces the settings of the canister without requiring an upgrade. Can only be called by a controller
+/// of the canister.
+///
+/// SIWS messages created before the reload keep the expiration time of the old settings. Only messages
+/// created after the reload use the new settings.
+///
+/// ## 🛑 Important: Changing the `salt` or `uri` setting affects how user seeds are generated.
+/// This means that existing users will get a new principal id when they sign in. Tip: Don't change the `salt` or `uri`
+/// settings after users have started using the service!
+#[update]
+fn siws_reload_settings(settings_input: SettingsInput) -> Result<(), String> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return Err("Only controllers can reload settings".to_string());
+    }
+
+    let (ic_siws_settings, provider_settings) = build_settings(settings_input)?;
+    ic_siws::settings::reload_settings(ic_siws_settings)?;
+    SETTINGS.set(provider_settings);
+
+    Ok(())
+}
@@ -0,0 +1,14 @@
+use ic_cdk::query;
+use ic_siws::signature_map::{SignatureMapHealth, SignatureMapStats};
+
+use crate::STATE;
+
+/// Returns statistics about the signature map and its health, allowing operators to monitor the number of
+/// delegations awaiting retrieval and how many have been pruned.
+#[query]
+fn siws_signature_map_stats() -> (SignatureMapStats, SignatureMapHealth) {
+    STATE.with(|s| {
+        let signature_map = s.signature_map.borrow();
+        (signature_map.stats(), signature_map.health_check())
+    })
+}